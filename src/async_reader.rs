@@ -0,0 +1,107 @@
+/*!
+
+Processing of CSV transaction records read from a Tokio `AsyncRead`, e.g. the body of a request to
+an async web service, without blocking a runtime thread on IO. Only reading is async: each record is
+processed as it is read, with the same logic as `process_records`.
+
+*/
+use crate::{
+    check_headers, positional_headers, Engine, InputFormat, Ledger, ParseFailure,
+    ProcessingOptions, ProcessingResult, Record,
+};
+use anyhow::{ensure, Result};
+use csv::{ByteRecord, Position, StringRecord};
+use csv_async::{AsyncReaderBuilder, Trim};
+use encoding_rs::UTF_8;
+use tokio::io::AsyncRead;
+
+/// The byte order mark that some programs, e.g. Excel, write at the start of UTF-8 files.
+const BOM: &str = "\u{feff}";
+
+/// Like `process_records`, but reads the CSV transaction records from an async `reader`. The input
+/// must be CSV encoded as UTF-8, and `options.diagnose_ordering` is not supported, because the
+/// input would have to be read twice.
+pub async fn process_records_async(
+    reader: impl AsyncRead + Unpin + Send,
+    options: &ProcessingOptions,
+) -> Result<ProcessingResult> {
+    ensure!(
+        options.input_format == InputFormat::Csv,
+        "Only CSV input can be processed asynchronously"
+    );
+    ensure!(
+        options.encoding == UTF_8,
+        "Only UTF-8 input can be processed asynchronously, not {}",
+        options.encoding.name()
+    );
+    ensure!(
+        !options.diagnose_ordering,
+        "Ordering can not be diagnosed when processing asynchronously"
+    );
+    options.check_decimal_separator()?;
+    let mut csv_reader = AsyncReaderBuilder::new()
+        .delimiter(options.delimiter)
+        .has_headers(!options.no_headers)
+        .trim(Trim::All)
+        .create_reader(reader);
+    let headers = if options.no_headers {
+        positional_headers()
+    } else {
+        // Unlike the `csv` crate, `csv_async` does not remove a byte order mark.
+        let headers: Vec<String> = csv_reader
+            .headers()
+            .await?
+            .iter()
+            .enumerate()
+            .map(|(i, header)| match i {
+                0 => header.trim_start_matches(BOM).trim().to_string(),
+                _ => header.to_string(),
+            })
+            .collect();
+        check_headers(
+            &StringRecord::from(headers.clone()),
+            options.deny_unknown_columns,
+        )?;
+        ByteRecord::from(headers)
+    };
+    let amount_index = options.amount_index(&headers);
+
+    let mut ledger = Ledger::new(Engine::new(*options));
+    let mut row = csv_async::ByteRecord::new();
+    while !options.stop_reading(ledger.report.rows) {
+        let result = match csv_reader.read_byte_record(&mut row).await {
+            Ok(false) => break,
+            Ok(true) => {
+                let mut row = converted(&row);
+                options.normalize_amount(&mut row, amount_index);
+                row.deserialize::<Record>(Some(&headers))
+                    .map(|record| (record, row.position().cloned()))
+                    .map_err(ParseFailure::from)
+            }
+            Err(e) => {
+                let position = e.position().map(position);
+                Err(ParseFailure::new(e, position))
+            }
+        };
+        ledger.process_row(result, options)?;
+    }
+    ledger.into_processed().map(ProcessingResult::from)
+}
+
+/// Converts a `row` read by `csv_async` to the `csv` crate's type, which is what the records of
+/// synchronous input are parsed from.
+fn converted(row: &csv_async::ByteRecord) -> ByteRecord {
+    let mut converted = ByteRecord::from(row.iter().collect::<Vec<_>>());
+    converted.set_position(row.position().map(position));
+    converted
+}
+
+/// Converts a `position` in the input from `csv_async`'s type to the `csv` crate's type.
+fn position(position: &csv_async::Position) -> Position {
+    let mut converted = Position::new();
+    converted
+        .set_byte(position.byte())
+        .set_line(position.line())
+        .set_record(position.record());
+    converted
+}
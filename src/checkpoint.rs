@@ -0,0 +1,158 @@
+/*!
+
+Checkpoints of the state of processing, from which processing of the same input can be resumed, e.g.
+after a crash, without processing the rows before the checkpoint again.
+
+*/
+use crate::{Activity, Client, DisputeState, ErrorKind, Ledger, Record, TransactionStore};
+use anyhow::{ensure, Context, Result};
+use chrono::{DateTime, Utc};
+use csv::Position;
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+/// The state of processing at the start of a row of the input, after every row before it has been
+/// processed. It is saved as JSON.
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct Checkpoint {
+    /// The number of rows before the checkpoint.
+    rows: usize,
+    /// The byte offset in the input of the row at the checkpoint.
+    byte: u64,
+    /// The number of rows before the checkpoint that were skipped, keyed by the reason.
+    skipped: BTreeMap<ErrorKind, usize>,
+    /// The number of rows before the checkpoint that were ignored.
+    #[serde(default)]
+    ignored: usize,
+    /// The number of records before the checkpoint that were for locked accounts, if they are
+    /// alerted on.
+    #[serde(default)]
+    locked_activity: usize,
+    clients: Vec<Account>,
+    /// The deposits and withdrawals that may be disputed after the checkpoint.
+    records: Vec<Record>,
+    disputes: BTreeMap<u32, DisputeState>,
+    failed_withdrawals: BTreeMap<u32, usize>,
+    latest_timestamp: Option<DateTime<Utc>>,
+    activity: BTreeMap<u32, Activity>,
+    deposits: BTreeMap<u32, Decimal>,
+}
+
+/// A client account, with its funds saved exactly, rather than rounded as they are in the output.
+#[derive(Debug, Serialize, Deserialize)]
+struct Account {
+    client: u32,
+    available: Decimal,
+    held: Decimal,
+    total: Decimal,
+    locked: bool,
+}
+
+impl Checkpoint {
+    /// Saves the state of the `ledger` before the row at `position`.
+    pub(crate) fn new<S: TransactionStore>(
+        ledger: &Ledger<'_, S>,
+        position: &Position,
+    ) -> Result<Self> {
+        let records = ledger
+            .engine
+            .records
+            .records()
+            .context("The transaction store can not be saved in a checkpoint")?;
+        let clients = ledger
+            .engine
+            .clients
+            .values()
+            .map(|client| Account {
+                client: client.id,
+                available: client.available,
+                held: client.held,
+                total: client.total,
+                locked: client.locked,
+            })
+            .collect();
+        Ok(Self {
+            rows: ledger.report.rows,
+            byte: position.byte(),
+            skipped: ledger.report.skipped.clone(),
+            ignored: ledger.report.ignored,
+            locked_activity: ledger.report.locked_activity,
+            clients,
+            records,
+            disputes: ledger.engine.disputes.clone(),
+            failed_withdrawals: ledger.engine.failed_withdrawals.clone(),
+            latest_timestamp: ledger.engine.latest_timestamp,
+            activity: ledger.activity.clone(),
+            deposits: ledger.deposits.clone(),
+        })
+    }
+
+    /// Reads the checkpoint at `path`, or returns `None` if there is no file there.
+    pub(crate) fn read(path: &Path) -> Result<Option<Self>> {
+        if !path.exists() {
+            return Ok(None);
+        }
+        let f = File::open(path).context(format!("Unable to open file '{}'", path.display()))?;
+        let checkpoint = serde_json::from_reader(BufReader::new(f))
+            .context(format!("Unable to read checkpoint '{}'", path.display()))?;
+        Ok(Some(checkpoint))
+    }
+
+    /// Writes the checkpoint to `path`, replacing any checkpoint that is already there. It is
+    /// written to a temporary file first, which then replaces it, so that if writing is interrupted
+    /// the previous checkpoint is left intact.
+    pub(crate) fn write(&self, path: &Path) -> Result<()> {
+        let mut temporary = path.as_os_str().to_owned();
+        temporary.push(".tmp");
+        let temporary = PathBuf::from(temporary);
+        let f = File::create(&temporary)
+            .context(format!("Unable to create file '{}'", temporary.display()))?;
+        let mut writer = BufWriter::new(f);
+        serde_json::to_writer(&mut writer, self)?;
+        writer.flush()?;
+        writer.get_ref().sync_all()?;
+        std::fs::rename(&temporary, path)
+            .context(format!("Unable to write checkpoint '{}'", path.display()))
+    }
+
+    /// Restores the saved state to the `ledger`, replacing any accounts that it has. The rows
+    /// before the checkpoint are counted as read, and the byte offset of the row at the checkpoint
+    /// is returned, so that it can be found in the input.
+    pub(crate) fn restore<S: TransactionStore>(self, ledger: &mut Ledger<'_, S>) -> Result<u64> {
+        ensure!(
+            ledger.report.rows == 0,
+            "A checkpoint can only be resumed before any rows are read"
+        );
+        for record in self.records {
+            ledger.engine.records.insert(record)?;
+        }
+        ledger.engine.clients = self
+            .clients
+            .into_iter()
+            .map(|account| {
+                let client = Client {
+                    id: account.client,
+                    available: account.available,
+                    held: account.held,
+                    total: account.total,
+                    locked: account.locked,
+                };
+                (client.id, client)
+            })
+            .collect();
+        ledger.engine.disputes = self.disputes;
+        ledger.engine.failed_withdrawals = self.failed_withdrawals;
+        ledger.engine.latest_timestamp = self.latest_timestamp;
+        ledger.activity = self.activity;
+        ledger.deposits = self.deposits;
+        ledger.report.rows = self.rows;
+        ledger.report.skipped = self.skipped;
+        ledger.report.ignored = self.ignored;
+        ledger.report.locked_activity = self.locked_activity;
+        Ok(self.byte)
+    }
+}
@@ -0,0 +1,133 @@
+/*!
+
+Generation of random, but reproducible, transaction records, e.g. to benchmark `moneybags` or to
+test it with more input than could be written by hand.
+
+*/
+use crate::{RecordType, REQUIRED_COLUMNS};
+use anyhow::Result;
+use csv::Writer;
+use rust_decimal::prelude::ToPrimitive;
+use rust_decimal::Decimal;
+use std::io::Write;
+
+/// The denominator of the probabilities used by `Generator`, which are in millionths.
+const MILLION: u64 = 1_000_000;
+
+/// The largest amount of a deposit or withdrawal, in ten-thousandths, i.e. 1000.
+const MAX_AMOUNT: u64 = 10_000_000;
+
+/// Writes a CSV of `transactions` records for clients `1` to `clients`, determined entirely by the
+/// `seed`. Each record is a deposit or a withdrawal, except that with a probability of
+/// `dispute_rate` it is instead a dispute of an earlier deposit of the same client, if there is an
+/// undisputed one, and with the same probability it is instead a resolve or chargeback of a
+/// dispute, if there is an open one. One in four of those are chargebacks, which lock the account,
+/// so no more records are generated for that client, except that the last unlocked account is never
+/// charged back.
+pub(crate) fn generate(
+    writer: impl Write,
+    clients: u32,
+    transactions: u32,
+    seed: u64,
+    dispute_rate: Decimal,
+) -> Result<()> {
+    let mut generator = Generator {
+        rng: SplitMix64(seed),
+        unlocked: (1..=clients).collect(),
+        dispute_rate: (dispute_rate * Decimal::from(MILLION))
+            .to_u64()
+            .unwrap_or_default(),
+        tx: 0,
+        deposits: Vec::new(),
+        disputes: Vec::new(),
+    };
+    let mut csv_writer = Writer::from_writer(writer);
+    csv_writer.write_record(REQUIRED_COLUMNS)?;
+    for _ in 0..transactions {
+        let (record_type, client, tx, amount) = generator.next_record();
+        let amount = amount.map(|amount| amount.to_string()).unwrap_or_default();
+        csv_writer.write_record([
+            record_type.to_string(),
+            client.to_string(),
+            tx.to_string(),
+            amount,
+        ])?;
+    }
+    csv_writer.flush()?;
+    Ok(())
+}
+
+/// The state of the records generated so far.
+struct Generator {
+    rng: SplitMix64,
+    /// The clients whose accounts have not been locked by a chargeback.
+    unlocked: Vec<u32>,
+    /// The probability of a dispute, and of a resolve or chargeback, in millionths.
+    dispute_rate: u64,
+    /// The tx of the last deposit or withdrawal, which is at most the number of records, so it can
+    /// not overflow.
+    tx: u32,
+    /// The client and tx of every deposit that has not been disputed.
+    deposits: Vec<(u32, u32)>,
+    /// The client and tx of every deposit that is disputed, and not yet resolved or charged back.
+    disputes: Vec<(u32, u32)>,
+}
+
+impl Generator {
+    /// The type, client, tx and amount, if it has one, of the next record.
+    fn next_record(&mut self) -> (RecordType, u32, u32, Option<Decimal>) {
+        if !self.disputes.is_empty() && self.chance(self.dispute_rate) {
+            let i = self.rng.below(self.disputes.len() as u64) as usize;
+            let (client, tx) = self.disputes.swap_remove(i);
+            if self.rng.below(4) > 0 || self.unlocked.len() == 1 {
+                return (RecordType::Resolve, client, tx, None);
+            }
+            self.unlocked.retain(|&id| id != client);
+            self.deposits.retain(|&(id, _)| id != client);
+            self.disputes.retain(|&(id, _)| id != client);
+            return (RecordType::Chargeback, client, tx, None);
+        }
+        if !self.deposits.is_empty() && self.chance(self.dispute_rate) {
+            let i = self.rng.below(self.deposits.len() as u64) as usize;
+            let (client, tx) = self.deposits.swap_remove(i);
+            self.disputes.push((client, tx));
+            return (RecordType::Dispute, client, tx, None);
+        }
+        self.tx += 1;
+        let client = self.unlocked[self.rng.below(self.unlocked.len() as u64) as usize];
+        let amount = Decimal::new(1 + self.rng.below(MAX_AMOUNT) as i64, 4);
+        // Deposits are more common, so that most withdrawals have funds to withdraw.
+        if self.rng.below(10) < 7 {
+            self.deposits.push((client, self.tx));
+            (RecordType::Deposit, client, self.tx, Some(amount))
+        } else {
+            (RecordType::Withdrawal, client, self.tx, Some(amount))
+        }
+    }
+
+    /// Whether an event with a `probability` in millionths happens.
+    fn chance(&mut self, probability: u64) -> bool {
+        self.rng.below(MILLION) < probability
+    }
+}
+
+/// The SplitMix64 pseudorandom number generator, which is small, fast, and good enough for test
+/// data. It is implemented here, rather than taken from a crate, so that the records generated from
+/// a seed never change with the version of a dependency.
+struct SplitMix64(u64);
+
+impl SplitMix64 {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// A number less than `n`, which must not be zero. The slight bias of the modulo does not
+    /// matter for test data.
+    fn below(&mut self, n: u64) -> u64 {
+        self.next() % n
+    }
+}
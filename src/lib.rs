@@ -4,243 +4,5087 @@ The implementation of the `moneybags` program. This library exists to facilitate
 testing. It is not meant for publication.
 
 */
+#[cfg(feature = "async")]
+mod async_reader;
+mod checkpoint;
+mod generate;
+mod store;
+
+#[cfg(feature = "async")]
+pub use async_reader::process_records_async;
+pub use store::{DiskStore, TransactionStore};
+
+use checkpoint::Checkpoint;
+
 use anyhow::{ensure, Context, Result};
-use clap::Parser;
-use csv::WriterBuilder;
-use rust_decimal::Decimal;
-use serde::{Deserialize, Deserializer, Serialize};
+use chrono::{DateTime, Utc};
+use clap::{ArgEnum, Parser};
+use csv::{ByteRecord, Position, ReaderBuilder, StringRecord, Trim, WriterBuilder};
+use encoding_rs::{Encoding, UTF_8};
+use encoding_rs_io::{DecodeReaderBytes, DecodeReaderBytesBuilder};
+use flate2::read::GzDecoder;
+use log::{info, warn};
+use rust_decimal::{Decimal, RoundingStrategy};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use serde_plain::{derive_display_from_serialize, derive_fromstr_from_deserialize};
-use std::collections::BTreeMap;
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
+use std::fmt::{Display, Formatter};
 use std::fs::File;
-use std::io::{BufReader, Read, Write};
-use std::path::PathBuf;
+use std::io::{stdin, BufRead, BufReader, BufWriter, Read, Write};
+use std::num::{NonZeroU32, NonZeroUsize};
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::sync_channel;
+use std::time::{Duration, Instant};
+
+/// The precision of amounts, in places past the decimal, specified by the input and output formats.
+/// A different precision can be given with `--currency-scale`.
+const DECIMAL_PLACES: u32 = 4;
+
+/// Processes the transactions found in <CSV_FILE>..., or stdin if no file is given, and outputs a
+/// CSV to stdout summarizing the end state of the accounts found therein.
+#[derive(Parser, Clone, Debug, Default, Eq, PartialEq, Ord, PartialOrd)]
+#[clap(name = "moneybags")]
+#[clap(bin_name = "moneybags")]
+#[clap(after_help = "EXIT STATUS:
+    0    All rows were processed.
+    1    An error stopped processing, or --validate found an invalid row.
+    2    Processing completed, but some rows were skipped.
+    130  Processing was interrupted, e.g. with Ctrl-C, and the output is of the rows read before.")]
+pub struct Moneybags {
+    /// The paths to CSV files containing transaction records, which are processed in order as if
+    /// they were a single file. Only the header of the first file is used, the first line of each
+    /// of the others is skipped. If omitted, the path in the `MONEYBAGS_INPUT` environment variable
+    /// is used, if it is set, otherwise transaction records are read from stdin.
+    #[clap(env = "MONEYBAGS_INPUT")]
+    pub csv_file: Vec<PathBuf>,
+
+    /// The input is gzip-compressed. This is assumed for a <CSV_FILE> with a `.gz` extension.
+    #[clap(long)]
+    pub gzip: bool,
+
+    /// Write the output to this file instead of stdout. The file is created if it does not
+    /// exist and truncated if it does.
+    #[clap(long, short)]
+    pub output: Option<PathBuf>,
+
+    /// Stop at the first row that cannot be parsed or processed, and exit with an error, instead of
+    /// skipping it.
+    #[clap(long)]
+    pub strict: bool,
+
+    /// Stop at the first row that cannot be parsed, e.g. because the input is truncated or
+    /// corrupt, and exit with an error, while still skipping rows that cannot be processed.
+    #[clap(long)]
+    pub fail_fast_on_parse: bool,
+
+    /// The format of the output.
+    #[clap(long, arg_enum, default_value_t)]
+    pub format: OutputFormat,
+
+    /// Only output the account of the client with this ID. Can be given more than once. All
+    /// transactions are still processed.
+    #[clap(long = "client", value_name = "ID")]
+    pub clients: Vec<u32>,
+
+    /// Do not output accounts that have no funds and are not locked, such as those of clients
+    /// whose every transaction was skipped.
+    #[clap(long)]
+    pub omit_empty: bool,
+
+    /// Only output accounts that are locked, e.g. for a review of frozen accounts.
+    #[clap(long)]
+    pub locked_only: bool,
+
+    /// The column by which the accounts in the output are sorted, in ascending order unless
+    /// `--sort-desc` is given. Accounts with the same value are in ascending order of client ID.
+    #[clap(long, arg_enum, default_value_t)]
+    pub sort_by: SortKey,
+
+    /// Sort the accounts in the output in descending order of the `--sort-by` column.
+    #[clap(long)]
+    pub sort_desc: bool,
+
+    /// The format of the input.
+    #[clap(long, arg_enum, default_value_t)]
+    pub input_format: InputFormat,
+
+    /// The character that separates fields in the input CSV.
+    #[clap(long, default_value_t)]
+    pub delimiter: Delimiter,
+
+    /// The input CSV has no header, so its first row is a record. The columns must then be in the
+    /// order `type,client,tx,amount`, optionally followed by `target_client`, `reference_tx` and
+    /// `timestamp`, in that order.
+    #[clap(long)]
+    pub no_headers: bool,
+
+    /// Exit with an error if the header of the input CSV has a column other than those of records,
+    /// e.g. as a sign that whatever produced the input has changed. Without this such columns are
+    /// ignored.
+    #[clap(long)]
+    pub deny_unknown_columns: bool,
+
+    /// The character that separates fields in the output CSV, which is independent of the input
+    /// `--delimiter`.
+    #[clap(long, default_value_t)]
+    pub output_delimiter: Delimiter,
+
+    /// The character that separates the whole and fractional parts of amounts in the input CSV,
+    /// either `.` or `,`. It must differ from the `--delimiter`. Output amounts always use `.`.
+    #[clap(long, default_value_t)]
+    pub decimal_separator: DecimalSeparator,
+
+    /// The character encoding of the input, e.g. `latin1` for Windows-1252. A byte order mark at
+    /// the start of the input is removed, and a UTF-16 one takes precedence over this.
+    #[clap(long, default_value_t)]
+    pub encoding: InputEncoding,
+
+    /// The number of places past the decimal of amounts, e.g. 0 for JPY or 2 for USD. Input
+    /// amounts with more places are skipped and output amounts have exactly this many places.
+    #[clap(long, default_value_t)]
+    pub currency_scale: CurrencyScale,
+
+    /// How output amounts with more places than the currency scale are rounded.
+    #[clap(long, arg_enum, default_value_t)]
+    pub rounding: RoundingMode,
+
+    /// Allow withdrawals to take a client's available funds this far below zero.
+    #[clap(long, default_value_t, parse(try_from_str = parse_overdraft_limit))]
+    pub overdraft_limit: Decimal,
+
+    /// What to do with a withdrawal of more than the available funds, after any overdraft limit.
+    #[clap(long, arg_enum, default_value_t)]
+    pub overdraw_policy: OverdrawPolicy,
+
+    /// Skip deposits, withdrawals and transfers of more than this amount, as a control against
+    /// amounts that are almost certainly errors, e.g. a deposit of ten million in retail data.
+    #[clap(long, value_name = "DECIMAL", parse(try_from_str = parse_max_amount))]
+    pub max_amount: Option<Decimal>,
+
+    /// What to do with records for a client whose account is locked.
+    #[clap(long, arg_enum, default_value_t)]
+    pub locked_policy: LockedPolicy,
+
+    /// Process unlock records, which unlock a client's account. Without this they are skipped.
+    #[clap(long)]
+    pub allow_unlock: bool,
+
+    /// Leave an account unlocked after a chargeback, for a regime in which chargebacks are routine.
+    /// The chargeback still changes the funds as usual.
+    #[clap(long)]
+    pub no_freeze_on_chargeback: bool,
+
+    /// Skip disputes of deposits whose funds are no longer available, e.g. because they have been
+    /// withdrawn, rather than taking the available funds below zero.
+    #[clap(long)]
+    pub dispute_requires_funds: bool,
+
+    /// Skip deposits and withdrawals with an amount of zero, which have no effect and may be a sign
+    /// of a bug in whatever produced the input. Without this they are processed, and can be
+    /// disputed, to no effect.
+    #[clap(long)]
+    pub reject_zero_amount: bool,
+
+    /// Lock a client's account, for review, once this many of its withdrawals have failed for lack
+    /// of funds. Its later records are then handled by the `--locked-policy`.
+    #[clap(long, value_name = "N")]
+    pub freeze_after_failed_withdrawals: Option<NonZeroUsize>,
+
+    /// Log a warning for each dispute of more than the client has deposited so far, e.g. of a
+    /// withdrawal of funds that were transferred to it, as a possible sign of fraud. The dispute is
+    /// still processed.
+    #[clap(long)]
+    pub flag_anomalies: bool,
+
+    /// Log an alert for each record for a client whose account is locked, e.g. a deposit after a
+    /// chargeback, whether or not it is then processed, so that activity after a freeze can be
+    /// reviewed. The number of them is logged once processing completes.
+    #[clap(long)]
+    pub alert_on_locked_activity: bool,
+
+    /// After each record is applied, check that the available and held funds of each client it
+    /// changed add up to their total funds, and exit with an error if they do not. This is a
+    /// self-test, which should never fail.
+    #[clap(long)]
+    pub check_invariants: bool,
+
+    /// Read the whole input before processing it, so that a dispute, resolve or chargeback of a
+    /// transaction that could not be found can say whether the transaction comes later in the
+    /// input, was skipped, or is not in the input at all. The input is held in memory.
+    #[clap(long, conflicts_with = "threads")]
+    pub diagnose_ordering: bool,
+
+    /// Ignore rows whose tx is less than this. A dispute, resolve or chargeback is filtered by the
+    /// tx it references, so it is always kept or ignored along with the transaction it references.
+    #[clap(long)]
+    pub since_tx: Option<u32>,
+
+    /// Ignore rows whose tx is greater than this. See `--since-tx`.
+    #[clap(long)]
+    pub until_tx: Option<u32>,
+
+    /// Ignore rows whose timestamp is before this, e.g. `2024-01-01T00:00:00Z`. Rows without a
+    /// timestamp are not ignored.
+    #[clap(long, value_name = "TIMESTAMP")]
+    pub from: Option<DateTime<Utc>>,
+
+    /// Ignore rows whose timestamp is this or later, so that consecutive windows given by `--from`
+    /// and `--to` do not overlap. Rows without a timestamp are not ignored.
+    #[clap(long, value_name = "TIMESTAMP")]
+    pub to: Option<DateTime<Utc>>,
+
+    /// Skip a row whose timestamp is before that of an earlier row, or stop with an error in strict
+    /// mode. Rows without a timestamp are not checked.
+    #[clap(long, conflicts_with = "threads")]
+    pub enforce_order: bool,
+
+    /// Skip a row that is identical to one of the last this many rows, in its type, client, tx and
+    /// amount, as a replay of it, e.g. by a message queue that delivers a message twice.
+    #[clap(long, value_name = "N", conflicts_with = "threads")]
+    pub dedupe_window: Option<NonZeroUsize>,
+
+    /// Stop processing, with a warning, once this many rows have been read, and output the accounts
+    /// as they are at that point.
+    #[clap(long, value_name = "N")]
+    pub max_rows: Option<usize>,
+
+    /// Skip, with a warning, the records of any client beyond the first this many, including those
+    /// from `--state`, so that the number of accounts held in memory is bounded. The records of
+    /// those first clients are still processed.
+    #[clap(long, value_name = "N", conflicts_with = "threads")]
+    pub max_clients: Option<usize>,
+
+    /// Write a line to stderr each time another `--progress-interval` rows have been read, with
+    /// the number of rows read and, except with `--threads`, of clients seen so far.
+    #[clap(long)]
+    pub progress: bool,
+
+    /// The number of rows between each line of `--progress`, one million by default.
+    #[clap(long, value_name = "N")]
+    pub progress_interval: Option<NonZeroUsize>,
+
+    /// Keep deposit and withdrawal records, which may later be disputed, in a file at this path
+    /// rather than in memory. This keeps memory use roughly constant regardless of the size of the
+    /// input, but is slower. The file is removed when processing completes.
+    #[clap(long)]
+    pub tx_store: Option<PathBuf>,
+
+    /// Keep deposit and withdrawal records in a hash map with room for this many of them, rather
+    /// than in an ordered map. This uses more memory, but lookups by disputes are faster.
+    #[clap(long, value_name = "N", conflicts_with_all = &["tx-store", "threads"])]
+    pub tx_store_capacity: Option<usize>,
+
+    /// Process transactions on this many worker threads, with each client's transactions handled by
+    /// a single thread.
+    #[clap(long, conflicts_with = "tx-store")]
+    pub threads: Option<NonZeroUsize>,
+
+    /// Start from the client accounts in this file, in the same CSV format as the output, e.g. the
+    /// output of a previous run, rather than from no accounts. Disputes can not reference the
+    /// transactions of previous runs, which are not known.
+    #[clap(long, value_name = "PATH")]
+    pub state: Option<PathBuf>,
+
+    /// Write an audit log to this file, as a CSV with a row for every transaction record showing
+    /// the state of the client account after it was applied, or whether it was skipped.
+    #[clap(long, conflicts_with = "threads")]
+    pub audit: Option<PathBuf>,
+
+    /// Write every row that is skipped, or that stops processing, to this file, as a CSV with its
+    /// line, tx, client, the kind of error and its message. They are still logged to stderr.
+    #[clap(long, value_name = "PATH", conflicts_with_all = &["threads", "validate"])]
+    pub errors: Option<PathBuf>,
+
+    /// Write every movement of held funds to this file, in order, as a CSV with the tx of the
+    /// disputed transaction, the client, the action, which is `hold` for a dispute, `release` for a
+    /// resolve or `chargeback`, the amount moved and the client's held funds after it.
+    #[clap(
+        long,
+        value_name = "PATH",
+        conflicts_with_all = &["threads", "validate", "no-dispute-mode"]
+    )]
+    pub hold_ledger: Option<PathBuf>,
+
+    /// Write the balances of each account after every transaction applied to it to this file, in
+    /// order, as a CSV with a sequence index, which counts the applied transactions from one, the
+    /// client and its available, held and total funds, e.g. to plot them over time.
+    #[clap(long, value_name = "PATH", conflicts_with_all = &["threads", "validate"])]
+    pub history: Option<PathBuf>,
+
+    /// Write a checkpoint of the state of processing to this file every `--checkpoint-interval`
+    /// rows, from which processing of the same input can be resumed with `--resume` if it is
+    /// interrupted. It holds every account and every deposit and withdrawal, so it can be large.
+    #[clap(
+        long,
+        value_name = "PATH",
+        conflicts_with_all = &[
+            "threads", "tx-store", "validate", "audit", "errors", "hold-ledger",
+            "history", "dedupe-window", "no-dispute-mode",
+        ]
+    )]
+    pub checkpoint: Option<PathBuf>,
+
+    /// The number of rows between each `--checkpoint`, one hundred thousand by default.
+    #[clap(long, value_name = "N", requires = "checkpoint")]
+    pub checkpoint_interval: Option<NonZeroUsize>,
+
+    /// Resume processing from the `--checkpoint`, if there is one, rather than from the start of
+    /// the input. The rows before it are read, to find it, but not processed again.
+    #[clap(long, requires = "checkpoint", conflicts_with = "state")]
+    pub resume: bool,
+
+    /// Fail, without writing any output, if the SHA-256 digest of the input, in hex, is not this.
+    /// The input must be a single file, or stdin.
+    #[clap(long)]
+    pub verify_checksum: Option<String>,
+
+    /// Write the SHA-256 digest of the input, in hex, to stderr. The input must be a single file, or
+    /// stdin.
+    #[clap(long)]
+    pub print_checksum: bool,
+
+    /// Check that every row can be parsed and that every dispute, resolve and chargeback references
+    /// an earlier transaction of the same client, without computing balances. A list of the invalid
+    /// rows and a pass or fail line are written instead of the account summary.
+    #[clap(long, conflicts_with_all = &["tx-store", "threads", "audit", "stats", "state"])]
+    pub validate: bool,
+
+    /// Write the number of rows read, processed and skipped, how long processing took, and the
+    /// number of rows processed per second, to stderr. The time spent writing the output is not
+    /// included.
+    #[clap(long)]
+    pub stats: bool,
+
+    /// Write the sums of the available, held and total funds of the accounts in the output, and the
+    /// number of them that are locked, to stderr.
+    #[clap(long, conflicts_with = "validate")]
+    pub totals: bool,
+
+    /// Add a `tx_count` column to the output, with the number of transaction records that were
+    /// applied to each account, and a `last_tx` column, with the highest tx among them.
+    #[clap(long, conflicts_with = "validate")]
+    pub extended: bool,
+
+    /// Write the amounts of each account with as many places past the decimal as the most precise
+    /// amount of the deposits, withdrawals and transfers applied to it, e.g. `100` rather than
+    /// `100.0000` for a client that only ever dealt in whole units, up to the currency scale.
+    #[clap(long, conflicts_with_all = &["validate", "no-dispute-mode"])]
+    pub scale_to_input: bool,
+
+    /// Write a single JSON object with the number of accounts, the number of them that are locked,
+    /// the sums of their funds, and the number of rows read and skipped, instead of the accounts,
+    /// e.g. as a health check.
+    #[clap(
+        long,
+        conflicts_with_all = &["validate", "no-dispute-mode", "diff", "round-trip-test"]
+    )]
+    pub summary_only: bool,
+
+    /// Check that the output reads back as the accounts that were written, with their funds in
+    /// balance, before writing it, to catch any loss in formatting the output. This holds the whole
+    /// output in memory.
+    #[clap(long, hide = true)]
+    pub round_trip_test: bool,
+
+    /// Write the state of an account to the output every time a record is applied to it, as the
+    /// input is processed, rather than the state of every account once it has all been processed.
+    /// The last row for each client is its final state. This relies on there being no dispute,
+    /// resolve or chargeback records, which could change an account that has already been written,
+    /// so any such record is an error. Only CSV output can be written this way, and the input can not
+    /// be verified with `--verify-checksum`, since it is only hashed once all of it has been read.
+    #[clap(
+        long,
+        conflicts_with_all = &[
+            "threads", "validate", "clients", "omit-empty", "locked-only", "sort-by", "sort-desc",
+            "extended",
+            "round-trip-test",
+            "verify-checksum",
+        ]
+    )]
+    pub no_dispute_mode: bool,
+
+    /// Compare the accounts with those in this file, in the same CSV format as the output, e.g. the
+    /// output of another version, instead of writing them. Each field that differs is written, and
+    /// it is an error if any do.
+    #[clap(
+        long,
+        value_name = "PATH",
+        conflicts_with_all = &["validate", "no-dispute-mode", "round-trip-test"]
+    )]
+    pub diff: Option<PathBuf>,
+
+    #[clap(subcommand)]
+    pub command: Option<MoneybagsCommand>,
+
+    /// Stops processing when it is set, e.g. by the binary's Ctrl-C handler. This is not a command
+    /// line argument.
+    #[clap(skip)]
+    pub interrupt: Option<Interrupt>,
+}
+
+/// Something to do other than processing transaction records.
+#[derive(clap::Subcommand, Clone, Debug, Eq, PartialEq, Ord, PartialOrd)]
+pub enum MoneybagsCommand {
+    /// Describe how records of a type change the accounts they are applied to, given the other
+    /// options, e.g. `moneybags --overdraft-limit 10 explain withdrawal`. No input is read.
+    Explain {
+        /// The type of record to describe, e.g. `chargeback`.
+        record_type: RecordType,
+    },
+    /// Write a CSV of random deposits, withdrawals, disputes, resolves and chargebacks to the output,
+    /// e.g. to benchmark processing. The same options always generate the same records. No input
+    /// is read.
+    Generate {
+        /// The number of clients, whose IDs are from one to this.
+        #[clap(long, value_name = "N", default_value = "10")]
+        clients: NonZeroU32,
+        /// The number of records, at most 4294967295, so that each deposit and withdrawal can have
+        /// a tx of its own.
+        #[clap(long, value_name = "N", default_value = "100")]
+        transactions: u32,
+        /// The seed of the random number generator.
+        #[clap(long, value_name = "N", default_value = "0")]
+        seed: u64,
+        /// The probability, from 0 to 1, that a record is a dispute of an earlier deposit, and
+        /// likewise that it is a resolve or chargeback of an open dispute.
+        #[clap(
+            long,
+            value_name = "DECIMAL",
+            default_value = "0.01",
+            parse(try_from_str = parse_dispute_rate)
+        )]
+        dispute_rate: Decimal,
+    },
+}
+
+impl Moneybags {
+    /// Writes a csv-formatted summary of the accounts found in `self.csv_file`, or `stdin` if there
+    /// is no `csv_file`, and returns a report of the rows that were skipped. By taking a `Write`
+    /// instead of writing to `stdout`, we make the program easier to test.
+    pub fn run(&self, mut writer: impl Write) -> Result<ProcessingReport> {
+        match self.command {
+            Some(MoneybagsCommand::Explain { record_type }) => {
+                writeln!(writer, "{}", self.options().explain(record_type))?;
+                return Ok(ProcessingReport::default());
+            }
+            Some(MoneybagsCommand::Generate {
+                clients,
+                transactions,
+                seed,
+                dispute_rate,
+            }) => {
+                generate::generate(writer, clients.get(), transactions, seed, dispute_rate)?;
+                return Ok(ProcessingReport::default());
+            }
+            None => {}
+        }
+        if self.csv_file.is_empty() {
+            return self.run_reader(self.decompress(None, stdin().lock()), writer);
+        }
+        // The files are not hashed as they are, because the headers of all but the first are
+        // skipped, so the checksum of several would not match one computed by any other tool.
+        ensure!(
+            self.csv_file.len() == 1 || (!self.print_checksum && self.verify_checksum.is_none()),
+            "The checksum of the input can only be printed or verified for a single file"
+        );
+        let mut reader: Box<dyn Read> = Box::new(std::io::empty());
+        for (i, csv_file) in self.csv_file.iter().enumerate() {
+            let f = File::open(csv_file)
+                .context(format!("Unable to open file '{}'", csv_file.display()))?;
+            let mut file_reader = self.decompress(Some(csv_file), f);
+            if i > 0 {
+                // Only the header of the first CSV file is kept.
+                if self.input_format == InputFormat::Csv && !self.no_headers {
+                    file_reader
+                        .read_until(b'\n', &mut Vec::new())
+                        .context(format!("Unable to read file '{}'", csv_file.display()))?;
+                }
+                // In case the previous file does not end with a newline. Blank lines are ignored.
+                reader = Box::new(reader.chain(&b"\n"[..]));
+            }
+            reader = Box::new(reader.chain(file_reader));
+        }
+        self.run_reader(reader, writer)
+    }
+
+    /// Buffers the input read from `csv_file`, or from stdin if it is `None`, decompressing it if
+    /// `--gzip` was given or if `csv_file` has a `.gz` extension.
+    fn decompress<'a>(
+        &self,
+        csv_file: Option<&Path>,
+        reader: impl Read + 'a,
+    ) -> Box<dyn BufRead + 'a> {
+        let gzip = self.gzip
+            || csv_file
+                .and_then(|csv_file| csv_file.extension())
+                .is_some_and(|extension| extension == "gz");
+        if gzip {
+            Box::new(BufReader::new(GzDecoder::new(reader)))
+        } else {
+            Box::new(BufReader::new(reader))
+        }
+    }
+
+    /// The `ProcessingOptions` specified by the command line arguments.
+    pub fn options(&self) -> ProcessingOptions {
+        ProcessingOptions {
+            strict: self.strict,
+            fail_fast_on_parse: self.fail_fast_on_parse,
+            input_format: self.input_format,
+            delimiter: self.delimiter.0,
+            no_headers: self.no_headers,
+            deny_unknown_columns: self.deny_unknown_columns,
+            decimal_separator: self.decimal_separator.0,
+            encoding: self.encoding.0,
+            scale: self.currency_scale.0,
+            rounding: self.rounding,
+            overdraft_limit: self.overdraft_limit,
+            max_amount: self.max_amount,
+            overdraw_policy: self.overdraw_policy,
+            locked_policy: self.locked_policy,
+            allow_unlock: self.allow_unlock,
+            no_freeze_on_chargeback: self.no_freeze_on_chargeback,
+            dispute_requires_funds: self.dispute_requires_funds,
+            reject_zero_amount: self.reject_zero_amount,
+            freeze_after_failed_withdrawals: self.freeze_after_failed_withdrawals,
+            flag_anomalies: self.flag_anomalies,
+            alert_on_locked_activity: self.alert_on_locked_activity,
+            check_invariants: self.check_invariants,
+            diagnose_ordering: self.diagnose_ordering,
+            since_tx: self.since_tx,
+            until_tx: self.until_tx,
+            from: self.from,
+            to: self.to,
+            enforce_order: self.enforce_order,
+            dedupe_window: self.dedupe_window,
+            max_rows: self.max_rows,
+            interrupt: self.interrupt,
+            max_clients: self.max_clients,
+            progress_interval: self
+                .progress
+                .then(|| self.progress_interval.unwrap_or(DEFAULT_PROGRESS_INTERVAL)),
+        }
+    }
+
+    /// Writes a csv-formatted summary of the accounts found in `reader`, ignoring `self.csv_file`,
+    /// and returns a report of the rows that were skipped.
+    pub fn run_reader(&self, reader: impl Read, writer: impl Write) -> Result<ProcessingReport> {
+        let options = self.options();
+        let mut audit = match &self.audit {
+            Some(path) => Some(BufWriter::new(
+                File::create(path)
+                    .context(format!("Unable to create file '{}'", path.display()))?,
+            )),
+            None => None,
+        };
+        let audit = audit.as_mut().map(|audit| audit as &mut dyn Write);
+        let mut errors = match &self.errors {
+            Some(path) => Some(BufWriter::new(
+                File::create(path)
+                    .context(format!("Unable to create file '{}'", path.display()))?,
+            )),
+            None => None,
+        };
+        let errors = errors.as_mut().map(|errors| errors as &mut dyn Write);
+        let mut holds = match &self.hold_ledger {
+            Some(path) => Some(BufWriter::new(
+                File::create(path)
+                    .context(format!("Unable to create file '{}'", path.display()))?,
+            )),
+            None => None,
+        };
+        let holds = holds.as_mut().map(|holds| holds as &mut dyn Write);
+        let mut history = match &self.history {
+            Some(path) => Some(BufWriter::new(
+                File::create(path)
+                    .context(format!("Unable to create file '{}'", path.display()))?,
+            )),
+            None => None,
+        };
+        let history = history.as_mut().map(|history| history as &mut dyn Write);
+        let mut reader = HashingReader::new(reader);
+        if self.validate {
+            let validation = validate_records(&mut reader, &options)?;
+            self.finish_reading(reader)?;
+            let mut writer = writer;
+            write!(writer, "{}", validation)?;
+            writer.flush()?;
+            ensure!(
+                validation.is_valid(),
+                "Validation failed with {} invalid rows",
+                validation.errors.len()
+            );
+            return Ok(ProcessingReport {
+                rows: validation.rows,
+                ..Default::default()
+            });
+        }
+        let state = match &self.state {
+            Some(path) => read_clients(path, b',')?,
+            None => BTreeMap::new(),
+        };
+        let checkpoint = self.checkpoint.as_deref().map(|path| {
+            let interval = self
+                .checkpoint_interval
+                .unwrap_or(DEFAULT_CHECKPOINT_INTERVAL);
+            (path, interval)
+        });
+        let resume = match (&self.checkpoint, self.resume) {
+            (Some(path), true) => Checkpoint::read(path)?,
+            _ => None,
+        };
+        let mut writer = writer;
+        let start = Instant::now();
+        // The stream borrows the writer, so it is kept within this block.
+        let processed = {
+            let stream = match self.no_dispute_mode {
+                true => {
+                    ensure!(
+                        self.format == OutputFormat::Csv,
+                        "Only CSV output can be written in no-dispute mode"
+                    );
+                    let mut stream = WriterBuilder::new()
+                        .has_headers(false)
+                        .delimiter(self.output_delimiter.0)
+                        .from_writer(&mut writer as &mut dyn Write);
+                    stream.write_record(OUTPUT_COLUMNS)?;
+                    Some(stream)
+                }
+                false => None,
+            };
+            match (&self.tx_store, self.tx_store_capacity, self.threads) {
+                (Some(path), _, _) => {
+                    let ledger = Ledger::new(Engine::with_store(options, DiskStore::create(path)?));
+                    process_reader(
+                        &mut reader,
+                        &options,
+                        ledger
+                            .with(state, audit)
+                            .streaming(stream)
+                            .logging_errors(errors)
+                            .logging_holds(holds)
+                            .logging_history(history)
+                            .checkpointing(checkpoint)
+                            .resuming(resume)?,
+                    )?
+                }
+                (None, Some(capacity), _) => {
+                    let ledger = Ledger::new(Engine::with_store(
+                        options,
+                        HashMap::with_capacity(capacity),
+                    ));
+                    process_reader(
+                        &mut reader,
+                        &options,
+                        ledger
+                            .with(state, audit)
+                            .streaming(stream)
+                            .logging_errors(errors)
+                            .logging_holds(holds)
+                            .logging_history(history)
+                            .checkpointing(checkpoint)
+                            .resuming(resume)?,
+                    )?
+                }
+                (None, None, Some(threads)) => {
+                    ensure!(
+                        stream.is_none(),
+                        "No-dispute mode can not be used on multiple threads"
+                    );
+                    process_threaded(&mut reader, &options, threads, state)?
+                }
+                (None, None, None) => {
+                    let ledger = Ledger::new(Engine::new(options));
+                    process_reader(
+                        &mut reader,
+                        &options,
+                        ledger
+                            .with(state, audit)
+                            .streaming(stream)
+                            .logging_errors(errors)
+                            .logging_holds(holds)
+                            .logging_history(history)
+                            .checkpointing(checkpoint)
+                            .resuming(resume)?,
+                    )?
+                }
+            }
+        };
+        let Processed {
+            clients,
+            report,
+            activity,
+            ..
+        } = processed;
+        let elapsed = start.elapsed();
+        self.finish_reading(reader)?;
+        if report.total_skipped() > 0 {
+            warn!("{}", report);
+        }
+        if report.locked_activity > 0 {
+            warn!(
+                "Alert: {} records were for locked accounts",
+                report.locked_activity
+            );
+        }
+        if self.stats {
+            eprintln!("{}", ProcessingStats::new(&report, elapsed));
+        }
+        // Only references to the clients in the map that they were processed in are collected, to
+        // be sorted, so that there is only ever one copy of them.
+        let mut written = clients
+            .values()
+            .filter(|client| self.clients.is_empty() || self.clients.contains(&client.id))
+            .filter(|client| !(self.omit_empty && client.is_empty()))
+            .filter(|client| !self.locked_only || client.locked)
+            .collect::<Vec<_>>();
+        // The map is already ordered by client ID.
+        if self.sort_by != SortKey::Client || self.sort_desc {
+            written.sort_by(|a, b| self.sort_by.compare(a, b, self.sort_desc));
+        }
+        // The totals are summed first, so that nothing is written if they overflow.
+        let totals = match self.totals {
+            true => Some(Totals::new(written.iter().copied())?),
+            false => None,
+        };
+        if self.no_dispute_mode {
+            // The accounts have already been written, as they changed.
+        } else if let Some(path) = &self.diff {
+            let expected = read_clients(path, self.output_delimiter.0)?;
+            let actual = written.iter().map(|&&client| (client.id, client)).collect();
+            let diff = diff_clients(&actual, &expected, options.scale, options.rounding);
+            write!(writer, "{}", diff)?;
+            writer.flush()?;
+            ensure!(
+                diff.is_same(),
+                "The accounts differ from '{}' for {} clients",
+                path.display(),
+                diff.differing_clients()
+            );
+        } else if self.summary_only {
+            let totals = Totals::new(written.iter().copied())?;
+            let summary = Summary::new(&report, totals, &options);
+            serde_json::to_writer_pretty(&mut writer, &summary)?;
+            writeln!(writer)?;
+            writer.flush()?;
+        } else if self.round_trip_test {
+            let mut output = Vec::new();
+            self.write_output(&mut output, &written, &activity, &options)?;
+            self.check_round_trip(&output, &written)?;
+            writer.write_all(&output)?;
+            writer.flush()?;
+        } else {
+            self.write_output(writer, &written, &activity, &options)?;
+        }
+        if let Some(totals) = totals {
+            eprintln!("{}", totals.line(options.scale, options.rounding));
+        }
+        Ok(report)
+    }
+
+    /// Writes the `clients`, along with their `activity` if the output is extended.
+    fn write_output(
+        &self,
+        writer: impl Write,
+        clients: &[&Client],
+        activity: &BTreeMap<u32, Activity>,
+        options: &ProcessingOptions,
+    ) -> Result<()> {
+        let summaries = clients.iter().map(|client| {
+            let scale = match self.scale_to_input {
+                true => activity
+                    .get(&client.id)
+                    .and_then(|activity| activity.input_scale)
+                    .map_or(options.scale, |scale| scale.min(options.scale)),
+                false => options.scale,
+            };
+            ClientSummary::new(client, scale, options.rounding)
+        });
+        if self.extended {
+            let summaries = summaries.map(|summary| {
+                let activity = activity.get(&summary.client).copied().unwrap_or_default();
+                ExtendedClientSummary::new(summary, activity)
+            });
+            let columns = [&OUTPUT_COLUMNS[..], &EXTENDED_COLUMNS[..]].concat();
+            self.write_summaries(writer, &columns, summaries)
+        } else {
+            self.write_summaries(writer, &OUTPUT_COLUMNS, summaries)
+        }
+    }
+
+    /// Reads the `output` back as clients, with the same `Deserialize` as `--state`, and checks that
+    /// they are the `expected` clients, in the same order, with their funds in balance.
+    fn check_round_trip(&self, output: &[u8], expected: &[&Client]) -> Result<()> {
+        let clients: Vec<Client> = match self.format {
+            OutputFormat::Csv => ReaderBuilder::new()
+                .delimiter(self.output_delimiter.0)
+                .from_reader(output)
+                .deserialize()
+                .collect::<Result<_, _>>()
+                .context("Unable to read back the output")?,
+            OutputFormat::Json => {
+                serde_json::from_slice(output).context("Unable to read back the output")?
+            }
+        };
+        ensure!(
+            clients.len() == expected.len(),
+            "The output reads back as {} clients, but {} were written",
+            clients.len(),
+            expected.len()
+        );
+        for (client, &expected) in clients.iter().zip(expected) {
+            ensure!(
+                client == expected,
+                "Client {} reads back from the output as {:?}, but was written from {:?}",
+                expected.id,
+                client,
+                expected
+            );
+            client.check_balance()?;
+        }
+        Ok(())
+    }
+
+    /// Writes the account `summaries` in the output format. A CSV header with the `columns` is
+    /// written even if there are no summaries.
+    fn write_summaries(
+        &self,
+        writer: impl Write,
+        columns: &[&str],
+        summaries: impl Iterator<Item = impl Serialize>,
+    ) -> Result<()> {
+        match self.format {
+            OutputFormat::Csv => {
+                let mut csv_writer = WriterBuilder::new()
+                    .has_headers(false)
+                    .delimiter(self.output_delimiter.0)
+                    .from_writer(writer);
+                csv_writer.write_record(columns)?;
+                for summary in summaries {
+                    csv_writer.serialize(summary)?;
+                }
+                csv_writer.flush()?;
+            }
+            OutputFormat::Json => {
+                let mut writer = writer;
+                let mut serializer = serde_json::Serializer::pretty(&mut writer);
+                serializer.collect_seq(summaries)?;
+                writeln!(writer)?;
+                writer.flush()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads whatever remains of the input, then prints and verifies its checksum as requested.
+    fn finish_reading(&self, mut reader: HashingReader<impl Read>) -> Result<()> {
+        if !self.print_checksum && self.verify_checksum.is_none() {
+            return Ok(());
+        }
+        // Processing usually reads to the end of the input, but not with `--max-rows`, so make
+        // sure that all of it is hashed.
+        std::io::copy(&mut reader, &mut std::io::sink())?;
+        let checksum = reader.checksum();
+        if self.print_checksum {
+            eprintln!("{}", checksum);
+        }
+        if let Some(expected) = &self.verify_checksum {
+            ensure!(
+                checksum.eq_ignore_ascii_case(expected.trim()),
+                "Input checksum {} does not match the expected checksum {}",
+                checksum,
+                expected
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Reads client accounts, in the same CSV format as the output with fields separated by
+/// `delimiter`, from the file at `path`, e.g. those to start processing from with `--state`. Each
+/// client can only appear once, and its funds must be in balance.
+fn read_clients(path: &Path, delimiter: u8) -> Result<BTreeMap<u32, Client>> {
+    let f = File::open(path).context(format!("Unable to open file '{}'", path.display()))?;
+    let mut csv_reader = ReaderBuilder::new()
+        .delimiter(delimiter)
+        .trim(Trim::All)
+        .from_reader(f);
+    let mut clients = BTreeMap::new();
+    for client in csv_reader.deserialize() {
+        let client: Client =
+            client.context(format!("Unable to read accounts from '{}'", path.display()))?;
+        client.check_balance()?;
+        ensure!(
+            clients.insert(client.id, client).is_none(),
+            "Client {} appears more than once in '{}'",
+            client.id,
+            path.display()
+        );
+    }
+    Ok(clients)
+}
+
+/// Parses an overdraft limit, which can not be negative.
+fn parse_overdraft_limit(s: &str) -> Result<Decimal> {
+    let limit = Decimal::from_str(s)?;
+    ensure!(
+        limit >= Decimal::ZERO,
+        "the overdraft limit can not be negative"
+    );
+    Ok(limit)
+}
+
+/// Parses a dispute rate, which is a probability from 0 to 1.
+fn parse_dispute_rate(s: &str) -> Result<Decimal> {
+    let rate = Decimal::from_str(s)?;
+    ensure!(
+        (Decimal::ZERO..=Decimal::ONE).contains(&rate),
+        "the dispute rate must be from 0 to 1"
+    );
+    Ok(rate)
+}
+
+/// Parses a maximum amount, which can not be negative.
+fn parse_max_amount(s: &str) -> Result<Decimal> {
+    let max = Decimal::from_str(s)?;
+    ensure!(
+        max >= Decimal::ZERO,
+        "the maximum amount can not be negative"
+    );
+    Ok(max)
+}
+
+/// A reader that computes the SHA-256 digest of the bytes read through it, so that the input can be
+/// verified without reading it twice.
+struct HashingReader<R> {
+    inner: R,
+    hasher: Sha256,
+}
+
+impl<R: Read> HashingReader<R> {
+    fn new(inner: R) -> Self {
+        Self {
+            inner,
+            hasher: Sha256::new(),
+        }
+    }
+
+    /// The hex-encoded digest of everything that has been read so far.
+    fn checksum(&self) -> String {
+        format!("{:x}", self.hasher.clone().finalize())
+    }
+}
+
+impl<R: Read> Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.hasher.update(&buf[..n]);
+        Ok(n)
+    }
+}
+
+/// The formats in which the account summary can be written.
+#[derive(
+    ArgEnum,
+    Debug,
+    Default,
+    Clone,
+    Copy,
+    Eq,
+    Ord,
+    PartialEq,
+    PartialOrd,
+    Hash,
+    Serialize,
+    Deserialize,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum OutputFormat {
+    /// A CSV file with a header row.
+    #[default]
+    Csv,
+    /// A JSON array of objects. Amounts are strings, to avoid any loss of precision.
+    Json,
+}
+
+derive_fromstr_from_deserialize!(OutputFormat);
+derive_display_from_serialize!(OutputFormat);
+
+/// The columns by which the accounts in the output can be sorted.
+#[derive(
+    ArgEnum,
+    Debug,
+    Default,
+    Clone,
+    Copy,
+    Eq,
+    Ord,
+    PartialEq,
+    PartialOrd,
+    Hash,
+    Serialize,
+    Deserialize,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum SortKey {
+    #[default]
+    Client,
+    Available,
+    Held,
+    Total,
+    /// Accounts that are not locked come first.
+    Locked,
+}
+
+derive_fromstr_from_deserialize!(SortKey);
+derive_display_from_serialize!(SortKey);
+
+impl SortKey {
+    /// Compares two accounts by this column, in descending order if `desc`, and then by client ID in
+    /// ascending order.
+    fn compare(self, a: &Client, b: &Client, desc: bool) -> std::cmp::Ordering {
+        let ordering = match self {
+            SortKey::Client => a.id.cmp(&b.id),
+            SortKey::Available => a.available.cmp(&b.available),
+            SortKey::Held => a.held.cmp(&b.held),
+            SortKey::Total => a.total.cmp(&b.total),
+            SortKey::Locked => a.locked.cmp(&b.locked),
+        };
+        let ordering = if desc { ordering.reverse() } else { ordering };
+        ordering.then(a.id.cmp(&b.id))
+    }
+}
+
+/// The formats in which transaction records can be read.
+#[derive(
+    ArgEnum,
+    Debug,
+    Default,
+    Clone,
+    Copy,
+    Eq,
+    Ord,
+    PartialEq,
+    PartialOrd,
+    Hash,
+    Serialize,
+    Deserialize,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum InputFormat {
+    /// A CSV file with a header row.
+    #[default]
+    Csv,
+    /// JSON Lines, i.e. a JSON object on each line with the same fields as the CSV columns, like
+    /// `{"type": "deposit", "client": 1, "tx": 1, "amount": "1.0"}`. Amounts are strings, to avoid
+    /// any loss of precision. Blank lines are ignored.
+    Jsonl,
+}
+
+derive_fromstr_from_deserialize!(InputFormat);
+derive_display_from_serialize!(InputFormat);
+
+/// What to do with a record for a client whose account is locked.
+// https://github.com/webern/moneybags/issues/4
+#[derive(
+    ArgEnum,
+    Debug,
+    Default,
+    Clone,
+    Copy,
+    Eq,
+    Ord,
+    PartialEq,
+    PartialOrd,
+    Hash,
+    Serialize,
+    Deserialize,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum LockedPolicy {
+    /// Report the record and skip it, or stop with an error in strict mode.
+    #[default]
+    Reject,
+    /// Skip the record without reporting it or counting it as skipped.
+    Ignore,
+    /// Process the record as if the account were not locked. The account remains locked.
+    Process,
+}
+
+derive_fromstr_from_deserialize!(LockedPolicy);
+derive_display_from_serialize!(LockedPolicy);
+
+/// What to do with a withdrawal that would take a client's available funds below the overdraft
+/// limit.
+#[derive(
+    ArgEnum,
+    Debug,
+    Default,
+    Clone,
+    Copy,
+    Eq,
+    Ord,
+    PartialEq,
+    PartialOrd,
+    Hash,
+    Serialize,
+    Deserialize,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum OverdrawPolicy {
+    /// Report the withdrawal as having insufficient funds and skip it, or stop with an error in
+    /// strict mode.
+    #[default]
+    Reject,
+    /// Withdraw only as much as is available, taking the available funds to zero, or to the
+    /// overdraft limit if there is one. The amount of the withdrawal is reduced to the amount
+    /// actually withdrawn, which is what is audited and what a later dispute refers to.
+    Drain,
+}
+
+derive_fromstr_from_deserialize!(OverdrawPolicy);
+derive_display_from_serialize!(OverdrawPolicy);
+
+/// A CSV field delimiter, which must be a single ASCII character.
+#[derive(Debug, Clone, Copy, Eq, Ord, PartialEq, PartialOrd, Hash)]
+pub struct Delimiter(pub u8);
+
+impl Default for Delimiter {
+    fn default() -> Self {
+        Self(b',')
+    }
+}
+
+impl FromStr for Delimiter {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.as_bytes() {
+            [byte] if byte.is_ascii() => Ok(Self(*byte)),
+            _ => Err(format!(
+                "The delimiter must be a single ASCII character, got '{}'",
+                s
+            )),
+        }
+    }
+}
+
+impl Display for Delimiter {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0 as char)
+    }
+}
+
+/// The character that separates the whole and fractional parts of an amount, either `.` or `,`.
+#[derive(Debug, Clone, Copy, Eq, Ord, PartialEq, PartialOrd, Hash)]
+pub struct DecimalSeparator(pub u8);
+
+impl Default for DecimalSeparator {
+    fn default() -> Self {
+        Self(b'.')
+    }
+}
+
+impl FromStr for DecimalSeparator {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "." => Ok(Self(b'.')),
+            "," => Ok(Self(b',')),
+            _ => Err(format!(
+                "The decimal separator must be '.' or ',', got '{}'",
+                s
+            )),
+        }
+    }
+}
+
+impl Display for DecimalSeparator {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0 as char)
+    }
+}
+
+/// The character encoding of the input, given by any of its WHATWG labels, e.g. `utf-8` or
+/// `latin1`, which is Windows-1252.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+pub struct InputEncoding(pub &'static Encoding);
+
+impl Default for InputEncoding {
+    fn default() -> Self {
+        Self(UTF_8)
+    }
+}
+
+impl FromStr for InputEncoding {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        Encoding::for_label(s.trim().as_bytes())
+            .map(Self)
+            .ok_or_else(|| format!("Unknown encoding '{}'", s))
+    }
+}
+
+impl Display for InputEncoding {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.0.name())
+    }
+}
+
+/// Encodings are ordered by name, so that `Moneybags` can be.
+impl Ord for InputEncoding {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.0.name().cmp(other.0.name())
+    }
+}
+
+impl PartialOrd for InputEncoding {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// The number of places past the decimal of amounts, which can be at most 28.
+#[derive(Debug, Clone, Copy, Eq, Ord, PartialEq, PartialOrd, Hash)]
+pub struct CurrencyScale(pub u32);
+
+impl Default for CurrencyScale {
+    fn default() -> Self {
+        Self(DECIMAL_PLACES)
+    }
+}
+
+impl FromStr for CurrencyScale {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.parse::<u32>() {
+            Ok(scale) if scale <= MAX_SCALE => Ok(Self(scale)),
+            _ => Err(format!(
+                "The currency scale must be a number from 0 to {}, got '{}'",
+                MAX_SCALE, s
+            )),
+        }
+    }
+}
+
+impl Display for CurrencyScale {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// The default number of rows between each line of `--progress`.
+const DEFAULT_PROGRESS_INTERVAL: NonZeroUsize = match NonZeroUsize::new(1_000_000) {
+    Some(interval) => interval,
+    None => panic!("the progress interval can not be zero"),
+};
+
+/// The default number of rows between each `--checkpoint`.
+const DEFAULT_CHECKPOINT_INTERVAL: NonZeroUsize = match NonZeroUsize::new(100_000) {
+    Some(interval) => interval,
+    None => panic!("the checkpoint interval can not be zero"),
+};
+
+/// The largest number of places past the decimal that a `Decimal` can have.
+const MAX_SCALE: u32 = 28;
+
+/// How amounts are rounded to the currency scale, named after the `rust_decimal` strategy each
+/// uses. Amounts with more places than the currency scale are skipped, so this only matters for
+/// amounts derived from them.
+#[derive(
+    ArgEnum,
+    Debug,
+    Default,
+    Clone,
+    Copy,
+    Eq,
+    Ord,
+    PartialEq,
+    PartialOrd,
+    Hash,
+    Serialize,
+    Deserialize,
+)]
+#[serde(rename_all = "kebab-case")]
+pub enum RoundingMode {
+    /// Banker's rounding: a midpoint is rounded to the nearest even digit, e.g. 1.00005 to 1.0000.
+    #[default]
+    MidpointNearestEven,
+    /// A midpoint is rounded away from zero, e.g. 1.00005 to 1.0001 and -1.00005 to -1.0001.
+    MidpointAwayFromZero,
+    /// A midpoint is rounded toward zero, e.g. 1.00005 to 1.0000 and -1.00005 to -1.0000.
+    MidpointTowardZero,
+    /// Always round toward zero, i.e. truncate.
+    ToZero,
+    /// Always round away from zero.
+    AwayFromZero,
+    /// Always round down.
+    ToNegativeInfinity,
+    /// Always round up.
+    ToPositiveInfinity,
+}
+
+impl RoundingMode {
+    /// Rounds `value` to `scale` places past the decimal.
+    pub fn round(self, value: Decimal, scale: u32) -> Decimal {
+        let strategy = match self {
+            RoundingMode::MidpointNearestEven => RoundingStrategy::MidpointNearestEven,
+            RoundingMode::MidpointAwayFromZero => RoundingStrategy::MidpointAwayFromZero,
+            RoundingMode::MidpointTowardZero => RoundingStrategy::MidpointTowardZero,
+            RoundingMode::ToZero => RoundingStrategy::ToZero,
+            RoundingMode::AwayFromZero => RoundingStrategy::AwayFromZero,
+            RoundingMode::ToNegativeInfinity => RoundingStrategy::ToNegativeInfinity,
+            RoundingMode::ToPositiveInfinity => RoundingStrategy::ToPositiveInfinity,
+        };
+        value.round_dp_with_strategy(scale, strategy)
+    }
+}
+
+derive_fromstr_from_deserialize!(RoundingMode);
+derive_display_from_serialize!(RoundingMode);
+
+/// Options that control how transaction records are parsed and processed.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct ProcessingOptions {
+    /// Return an error for the first row that cannot be parsed or processed instead of skipping it.
+    pub strict: bool,
+    /// Return an error for the first row that cannot be parsed, while still skipping rows that
+    /// cannot be processed. This is implied by `strict`.
+    pub fail_fast_on_parse: bool,
+    /// The format of the input. Only CSV input can be validated or processed on multiple threads.
+    pub input_format: InputFormat,
+    /// The byte that separates fields in the input CSV.
+    pub delimiter: u8,
+    /// The input CSV has no header, so its columns are positional, in the order of
+    /// `type,client,tx,amount,target_client,reference_tx,timestamp`, where the optional columns
+    /// may be omitted from the end.
+    pub no_headers: bool,
+    /// Return an error if the header of the input CSV has a column that is not part of the schema,
+    /// rather than ignoring the column.
+    pub deny_unknown_columns: bool,
+    /// The byte that separates the whole and fractional parts of amounts in the input CSV, either
+    /// `.` or `,`, which must differ from `delimiter`. JSON lines input always uses `.`.
+    pub decimal_separator: u8,
+    /// The character encoding of the input, which is decoded to UTF-8 as it is read unless it is
+    /// already UTF-8. A byte order mark at the start of the input is removed.
+    pub encoding: &'static Encoding,
+    /// The number of places past the decimal of amounts. Records with amounts that have more places
+    /// are skipped.
+    pub scale: u32,
+    /// How amounts in the output and audit log are rounded to `scale` places.
+    pub rounding: RoundingMode,
+    /// How far below zero a withdrawal may take a client's available funds. Zero means that
+    /// withdrawals can not exceed the available funds.
+    pub overdraft_limit: Decimal,
+    /// What to do with a withdrawal that would take available funds below the overdraft limit.
+    pub overdraw_policy: OverdrawPolicy,
+    /// If given, deposits, withdrawals and transfers of more than this amount are skipped.
+    pub max_amount: Option<Decimal>,
+    /// What to do with records for a client whose account is locked.
+    pub locked_policy: LockedPolicy,
+    /// Process unlock records rather than skipping them.
+    pub allow_unlock: bool,
+    /// Leave an account unlocked after a chargeback, rather than locking it.
+    pub no_freeze_on_chargeback: bool,
+    /// Skip a dispute of a deposit if the client's available funds are less than the disputed
+    /// amount, instead of holding funds that the client no longer has.
+    pub dispute_requires_funds: bool,
+    /// Skip deposits and withdrawals with an amount of zero, so that they are not stored to be
+    /// disputed.
+    pub reject_zero_amount: bool,
+    /// If given, a client's account is locked once this many of its withdrawals have been skipped
+    /// for insufficient funds.
+    pub freeze_after_failed_withdrawals: Option<NonZeroUsize>,
+    /// Log a warning for each dispute of more than the sum of the client's deposits so far. This
+    /// does not change how records are processed.
+    pub flag_anomalies: bool,
+    /// Log a warning for each record for a client whose account is locked, and count them in the
+    /// report, whether or not they are processed.
+    pub alert_on_locked_activity: bool,
+    /// Check the balance of each client changed by a record after it is applied, returning an error
+    /// if it is out of balance.
+    pub check_invariants: bool,
+    /// Read the whole input into memory, and find the line of every deposit and withdrawal, before
+    /// processing it, so that errors for transactions that could not be found say why. This is not
+    /// supported by `process_records_threaded`.
+    pub diagnose_ordering: bool,
+    /// If given, records with a tx less than this are ignored.
+    pub since_tx: Option<u32>,
+    /// If given, records with a tx greater than this are ignored.
+    pub until_tx: Option<u32>,
+    /// If given, records with a timestamp before this are ignored.
+    pub from: Option<DateTime<Utc>>,
+    /// If given, records with a timestamp at or after this are ignored.
+    pub to: Option<DateTime<Utc>>,
+    /// Skip a record with a timestamp before that of an earlier record. This is not supported by
+    /// `process_records_threaded`.
+    pub enforce_order: bool,
+    /// If given, a record that is identical to one of this many before it, in its type, client, tx
+    /// and amount, is skipped. This is not supported by `process_records_threaded`.
+    pub dedupe_window: Option<NonZeroUsize>,
+    /// If given, processing stops once this many rows have been read, whether or not they were
+    /// processed successfully, and the result is of those rows alone.
+    pub max_rows: Option<usize>,
+    /// If given, processing stops once it is set, before the next row is read, and the result is of
+    /// the rows read until then.
+    pub interrupt: Option<Interrupt>,
+    /// If given, records that would create an account once there are this many are skipped. This
+    /// is not supported by `process_records_threaded`.
+    pub max_clients: Option<usize>,
+    /// If given, progress is logged each time this many more rows have been read.
+    pub progress_interval: Option<NonZeroUsize>,
+}
+
+impl Default for ProcessingOptions {
+    fn default() -> Self {
+        Self {
+            strict: false,
+            fail_fast_on_parse: false,
+            input_format: InputFormat::Csv,
+            delimiter: b',',
+            no_headers: false,
+            deny_unknown_columns: false,
+            decimal_separator: b'.',
+            encoding: UTF_8,
+            scale: DECIMAL_PLACES,
+            rounding: RoundingMode::MidpointNearestEven,
+            overdraft_limit: Decimal::ZERO,
+            max_amount: None,
+            overdraw_policy: OverdrawPolicy::Reject,
+            locked_policy: LockedPolicy::Reject,
+            allow_unlock: false,
+            no_freeze_on_chargeback: false,
+            dispute_requires_funds: false,
+            reject_zero_amount: false,
+            freeze_after_failed_withdrawals: None,
+            flag_anomalies: false,
+            alert_on_locked_activity: false,
+            check_invariants: false,
+            diagnose_ordering: false,
+            since_tx: None,
+            until_tx: None,
+            from: None,
+            to: None,
+            enforce_order: false,
+            dedupe_window: None,
+            max_rows: None,
+            interrupt: None,
+            max_clients: None,
+            progress_interval: None,
+        }
+    }
+}
+
+/// Builds `ProcessingOptions` from the defaults, e.g.
+/// `ProcessingOptions::default().with_strict(true).with_scale(2)`. Each method sets the field of the
+/// same name, which can also be set directly.
+impl ProcessingOptions {
+    /// Sets whether the first row that cannot be parsed or processed is an error.
+    pub fn with_strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
+
+    /// Sets whether the first row that cannot be parsed is an error.
+    pub fn with_fail_fast_on_parse(mut self, fail_fast_on_parse: bool) -> Self {
+        self.fail_fast_on_parse = fail_fast_on_parse;
+        self
+    }
+
+    /// Sets the format of the input.
+    pub fn with_input_format(mut self, input_format: InputFormat) -> Self {
+        self.input_format = input_format;
+        self
+    }
+
+    /// Sets the byte that separates fields in the input CSV.
+    pub fn with_delimiter(mut self, delimiter: u8) -> Self {
+        self.delimiter = delimiter;
+        self
+    }
+
+    /// Reads input CSV without a header, with positional columns, if `no_headers` is `true`.
+    pub fn with_no_headers(mut self, no_headers: bool) -> Self {
+        self.no_headers = no_headers;
+        self
+    }
+
+    /// Sets whether a column that is not part of the schema is an error in the header of input CSV.
+    pub fn with_deny_unknown_columns(mut self, deny_unknown_columns: bool) -> Self {
+        self.deny_unknown_columns = deny_unknown_columns;
+        self
+    }
+
+    /// Sets the byte that separates the whole and fractional parts of amounts in the input CSV.
+    pub fn with_decimal_separator(mut self, decimal_separator: u8) -> Self {
+        self.decimal_separator = decimal_separator;
+        self
+    }
+
+    /// Sets the character encoding of the input.
+    pub fn with_encoding(mut self, encoding: &'static Encoding) -> Self {
+        self.encoding = encoding;
+        self
+    }
+
+    /// Sets the number of places past the decimal of amounts.
+    pub fn with_scale(mut self, scale: u32) -> Self {
+        self.scale = scale;
+        self
+    }
+
+    /// Sets how amounts in the output and audit log are rounded.
+    pub fn with_rounding(mut self, rounding: RoundingMode) -> Self {
+        self.rounding = rounding;
+        self
+    }
+
+    /// Sets how far below zero a withdrawal may take a client's available funds.
+    pub fn with_overdraft_limit(mut self, overdraft_limit: Decimal) -> Self {
+        self.overdraft_limit = overdraft_limit;
+        self
+    }
+
+    /// Skips deposits, withdrawals and transfers of more than `max_amount`.
+    pub fn with_max_amount(mut self, max_amount: Decimal) -> Self {
+        self.max_amount = Some(max_amount);
+        self
+    }
+
+    /// Sets what to do with a withdrawal of more than the available funds.
+    pub fn with_overdraw_policy(mut self, overdraw_policy: OverdrawPolicy) -> Self {
+        self.overdraw_policy = overdraw_policy;
+        self
+    }
+
+    /// Sets what to do with records for a client whose account is locked.
+    pub fn with_locked_policy(mut self, locked_policy: LockedPolicy) -> Self {
+        self.locked_policy = locked_policy;
+        self
+    }
+
+    /// Sets whether unlock records are processed.
+    pub fn with_allow_unlock(mut self, allow_unlock: bool) -> Self {
+        self.allow_unlock = allow_unlock;
+        self
+    }
+
+    /// Leaves accounts unlocked after a chargeback if `no_freeze_on_chargeback` is `true`.
+    pub fn with_no_freeze_on_chargeback(mut self, no_freeze_on_chargeback: bool) -> Self {
+        self.no_freeze_on_chargeback = no_freeze_on_chargeback;
+        self
+    }
+
+    /// Sets whether a dispute of a deposit requires the disputed funds to be available.
+    pub fn with_dispute_requires_funds(mut self, dispute_requires_funds: bool) -> Self {
+        self.dispute_requires_funds = dispute_requires_funds;
+        self
+    }
+
+    /// Skips deposits and withdrawals with an amount of zero if `reject_zero_amount` is `true`.
+    pub fn with_reject_zero_amount(mut self, reject_zero_amount: bool) -> Self {
+        self.reject_zero_amount = reject_zero_amount;
+        self
+    }
+
+    /// Locks a client's account once `freeze_after_failed_withdrawals` of its withdrawals have
+    /// failed.
+    pub fn with_freeze_after_failed_withdrawals(
+        mut self,
+        freeze_after_failed_withdrawals: NonZeroUsize,
+    ) -> Self {
+        self.freeze_after_failed_withdrawals = Some(freeze_after_failed_withdrawals);
+        self
+    }
+
+    /// Sets whether disputes of more than the client has deposited are logged.
+    pub fn with_flag_anomalies(mut self, flag_anomalies: bool) -> Self {
+        self.flag_anomalies = flag_anomalies;
+        self
+    }
+
+    /// Sets whether records for clients whose accounts are locked are logged and counted.
+    pub fn with_alert_on_locked_activity(mut self, alert_on_locked_activity: bool) -> Self {
+        self.alert_on_locked_activity = alert_on_locked_activity;
+        self
+    }
+
+    /// Sets whether client balances are checked after each record.
+    pub fn with_check_invariants(mut self, check_invariants: bool) -> Self {
+        self.check_invariants = check_invariants;
+        self
+    }
+
+    /// Sets whether errors for transactions that could not be found say why.
+    pub fn with_diagnose_ordering(mut self, diagnose_ordering: bool) -> Self {
+        self.diagnose_ordering = diagnose_ordering;
+        self
+    }
+
+    /// Ignores records with a tx less than `since_tx`.
+    pub fn with_since_tx(mut self, since_tx: u32) -> Self {
+        self.since_tx = Some(since_tx);
+        self
+    }
+
+    /// Ignores records with a tx greater than `until_tx`.
+    pub fn with_until_tx(mut self, until_tx: u32) -> Self {
+        self.until_tx = Some(until_tx);
+        self
+    }
+
+    /// Ignores records with a timestamp before `from`.
+    pub fn with_from(mut self, from: DateTime<Utc>) -> Self {
+        self.from = Some(from);
+        self
+    }
+
+    /// Ignores records with a timestamp at or after `to`.
+    pub fn with_to(mut self, to: DateTime<Utc>) -> Self {
+        self.to = Some(to);
+        self
+    }
+
+    /// Skips records with a timestamp before that of an earlier record if `enforce_order` is `true`.
+    pub fn with_enforce_order(mut self, enforce_order: bool) -> Self {
+        self.enforce_order = enforce_order;
+        self
+    }
+
+    /// Skips records that are identical to one of the `dedupe_window` records before them.
+    pub fn with_dedupe_window(mut self, dedupe_window: NonZeroUsize) -> Self {
+        self.dedupe_window = Some(dedupe_window);
+        self
+    }
+
+    /// Stops processing once `max_rows` rows have been read.
+    pub fn with_max_rows(mut self, max_rows: usize) -> Self {
+        self.max_rows = Some(max_rows);
+        self
+    }
+
+    /// Stops processing once `flag` is set.
+    pub fn with_interrupt(mut self, flag: &'static AtomicBool) -> Self {
+        self.interrupt = Some(Interrupt::new(flag));
+        self
+    }
+
+    /// Skips records that would create an account once there are `max_clients`.
+    pub fn with_max_clients(mut self, max_clients: usize) -> Self {
+        self.max_clients = Some(max_clients);
+        self
+    }
+
+    /// Logs progress each time another `progress_interval` rows have been read.
+    pub fn with_progress_interval(mut self, progress_interval: NonZeroUsize) -> Self {
+        self.progress_interval = Some(progress_interval);
+        self
+    }
+}
+
+impl ProcessingOptions {
+    /// Describes how records of `record_type` change the accounts they are applied to with these
+    /// options, including how locked accounts and withdrawals of more than the available funds are
+    /// handled, as a paragraph for each case.
+    pub fn explain(&self, record_type: RecordType) -> String {
+        let skipped = if self.strict {
+            "stops processing with an error"
+        } else {
+            "is reported and skipped"
+        };
+        let overdrawn = if self.overdraft_limit.is_zero() {
+            "of more than the available funds".to_string()
+        } else {
+            format!(
+                "that would take the available funds below the overdraft limit of -{}",
+                self.overdraft_limit
+            )
+        };
+        let mut paragraphs = Vec::new();
+        match record_type {
+            RecordType::Deposit => {
+                paragraphs.push(
+                    "A deposit of an amount increases the available and total funds by the amount. \
+                     The deposit can later be disputed."
+                        .to_string(),
+                );
+            }
+            RecordType::Withdrawal => {
+                paragraphs.push(
+                    "A withdrawal of an amount decreases the available and total funds by the \
+                     amount. The withdrawal can later be disputed."
+                        .to_string(),
+                );
+                paragraphs.push(match self.overdraw_policy {
+                    OverdrawPolicy::Reject => {
+                        format!(
+                            "A withdrawal {} {} for insufficient funds.",
+                            overdrawn, skipped
+                        )
+                    }
+                    OverdrawPolicy::Drain => format!(
+                        "A withdrawal {} withdraws only as much as it can, and its amount becomes \
+                         what was withdrawn, which is what a later dispute holds.",
+                        overdrawn
+                    ),
+                });
+                if let Some(limit) = self.freeze_after_failed_withdrawals {
+                    paragraphs.push(format!(
+                        "The account is locked once {} of its withdrawals have been skipped for \
+                         insufficient funds.",
+                        limit
+                    ));
+                }
+            }
+            RecordType::Dispute => {
+                paragraphs.push(
+                    "A dispute of a deposit holds the disputed amount, which is the whole deposit \
+                     unless the dispute has an amount other than zero: the available funds \
+                     decrease by the amount and the held funds increase by it, while the total \
+                     funds are unchanged."
+                        .to_string(),
+                );
+                paragraphs.push(if self.dispute_requires_funds {
+                    format!(
+                        "A dispute of a deposit of more than the available funds {}.",
+                        skipped
+                    )
+                } else {
+                    "The available funds can go below zero if the deposit has already been spent."
+                        .to_string()
+                });
+                paragraphs.push(
+                    "A dispute of a withdrawal returns the disputed amount as held funds: the held \
+                     and total funds increase by the amount, while the available funds are \
+                     unchanged."
+                        .to_string(),
+                );
+                paragraphs.push(format!(
+                    "A dispute of a transaction that is not a deposit or withdrawal of the same \
+                     client, or that has already been disputed, {}.",
+                    skipped
+                ));
+                if self.flag_anomalies {
+                    paragraphs.push(
+                        "A warning is logged for a dispute of more than the client has deposited."
+                            .to_string(),
+                    );
+                }
+            }
+            RecordType::Resolve => {
+                paragraphs.push(
+                    "A resolve of a disputed deposit releases the held amount: the held funds \
+                     decrease by the amount and the available funds increase by it, while the \
+                     total funds are unchanged."
+                        .to_string(),
+                );
+                paragraphs.push(
+                    "A resolve of a disputed withdrawal lets the withdrawal stand: the held and \
+                     total funds decrease by the amount, while the available funds are unchanged."
+                        .to_string(),
+                );
+                paragraphs.push(format!(
+                    "A resolve of a transaction of the same client that is not currently disputed \
+                     {}.",
+                    skipped
+                ));
+            }
+            RecordType::Chargeback => {
+                paragraphs.push(
+                    "A chargeback of a disputed deposit reverses it: the held and total funds \
+                     decrease by the amount, while the available funds are unchanged."
+                        .to_string(),
+                );
+                paragraphs.push(
+                    "A chargeback of a disputed withdrawal reverses it: the held funds decrease by \
+                     the amount and the available funds increase by it, while the total funds are \
+                     unchanged."
+                        .to_string(),
+                );
+                paragraphs.push(if self.no_freeze_on_chargeback {
+                    "Either way, the account is not locked, i.e. frozen, by the chargeback, so its \
+                     later records are processed as usual."
+                        .to_string()
+                } else {
+                    format!(
+                        "Either way, the account is then locked, i.e. frozen, {}.",
+                        if self.allow_unlock {
+                            "until an unlock record unlocks it"
+                        } else {
+                            "for good, because unlock records are not allowed"
+                        }
+                    )
+                });
+                paragraphs.push(format!(
+                    "A chargeback of a transaction of the same client that is not currently \
+                     disputed {}.",
+                    skipped
+                ));
+            }
+            RecordType::Transfer => {
+                paragraphs.push(
+                    "A transfer of an amount decreases the available and total funds by the \
+                     amount, and increases those of the target client by it. Both accounts change, \
+                     or neither does. A transfer can not be disputed."
+                        .to_string(),
+                );
+                paragraphs.push(format!(
+                    "A transfer {} {} for insufficient funds.",
+                    overdrawn, skipped
+                ));
+            }
+            RecordType::Unlock => {
+                paragraphs.push(if self.allow_unlock {
+                    "An unlock unlocks the account, e.g. after a chargeback has locked it. Its \
+                     amount is ignored."
+                        .to_string()
+                } else {
+                    format!(
+                        "An unlock {}, because unlock records are not allowed.",
+                        skipped
+                    )
+                });
+            }
+        }
+        if record_type != RecordType::Unlock {
+            paragraphs.push(match self.locked_policy {
+                LockedPolicy::Reject => {
+                    format!("A {} for a locked account {}.", record_type, skipped)
+                }
+                LockedPolicy::Ignore => format!(
+                    "A {} for a locked account is skipped without being reported.",
+                    record_type
+                ),
+                LockedPolicy::Process => format!(
+                    "A {} for a locked account is processed as for any other, and the account \
+                     remains locked.",
+                    record_type
+                ),
+            });
+            if self.alert_on_locked_activity {
+                paragraphs.push(format!(
+                    "Either way, an alert is logged for a {} for a locked account.",
+                    record_type
+                ));
+            }
+        }
+        if matches!(record_type, RecordType::Deposit | RecordType::Withdrawal) {
+            paragraphs.push(if self.reject_zero_amount {
+                format!("A {} of zero {}.", record_type, skipped)
+            } else {
+                format!(
+                    "A {} of zero is processed, to no effect, and can be disputed, also to no \
+                     effect.",
+                    record_type
+                )
+            });
+        }
+        if matches!(
+            record_type,
+            RecordType::Deposit | RecordType::Withdrawal | RecordType::Transfer
+        ) {
+            paragraphs.push(format!(
+                "A {} with a negative amount, an amount with more than {} places, or a tx that has \
+                 already been used {}.",
+                record_type, self.scale, skipped
+            ));
+            if let Some(max) = self.max_amount {
+                paragraphs.push(format!(
+                    "A {} of more than {}, the maximum amount, {}.",
+                    record_type, max, skipped
+                ));
+            }
+        }
+        if let Some(window) = self.dedupe_window {
+            paragraphs.push(format!(
+                "A {} with the same client, tx and amount as one of the {} records before it {}, \
+                 as a replay.",
+                record_type, window, skipped
+            ));
+        }
+        paragraphs.join("\n\n")
+    }
+
+    /// Creates a CSV reader for `reader`, decoded with `decode`. Leading and trailing whitespace is
+    /// trimmed from every field, including the headers, so that values like ` 1.0` can be parsed.
+    /// It is an error for the decimal separator to be the same as the delimiter.
+    fn csv_reader<R: Read>(&self, reader: R) -> Result<csv::Reader<DecodeReaderBytes<R, Vec<u8>>>> {
+        self.check_decimal_separator()?;
+        Ok(ReaderBuilder::new()
+            .delimiter(self.delimiter)
+            .has_headers(!self.no_headers)
+            .trim(Trim::All)
+            .from_reader(self.decode(reader)))
+    }
+
+    /// The columns of the CSV input read by `csv_reader`, which are read from its header and checked
+    /// with `check_headers`, unless the input has no header, in which case they are the
+    /// `REQUIRED_COLUMNS` followed by the `OPTIONAL_COLUMNS`, in that order.
+    fn csv_headers<R: Read>(&self, csv_reader: &mut csv::Reader<R>) -> Result<ByteRecord> {
+        if self.no_headers {
+            return Ok(positional_headers());
+        }
+        check_headers(csv_reader.headers()?, self.deny_unknown_columns)?;
+        Ok(csv_reader.byte_headers()?.clone())
+    }
+
+    /// Checks that the decimal separator is not the same as the delimiter, which would make amounts
+    /// with a fractional part ambiguous.
+    fn check_decimal_separator(&self) -> Result<()> {
+        ensure!(
+            self.delimiter != self.decimal_separator,
+            "The decimal separator '{}' can not also be the delimiter",
+            self.decimal_separator as char
+        );
+        Ok(())
+    }
+
+    /// The index of the amount column in the CSV `headers` if amounts use a decimal separator other
+    /// than `.`, and so need to be normalized with `normalize_amount` before they are parsed.
+    fn amount_index(&self, headers: &ByteRecord) -> Option<usize> {
+        if self.decimal_separator == b'.' {
+            return None;
+        }
+        headers.iter().position(|header| header == b"amount")
+    }
+
+    /// Swaps the decimal separator with `.` in the amount at `amount_index` of `row`, so that an
+    /// amount like `1,5` is parsed as `1.5`, while one that contains a `.` fails to parse.
+    fn normalize_amount(&self, row: &mut ByteRecord, amount_index: Option<usize>) {
+        let index = match amount_index {
+            Some(index) if index < row.len() => index,
+            _ => return,
+        };
+        let mut normalized = ByteRecord::with_capacity(row.as_slice().len(), row.len());
+        for (i, field) in row.iter().enumerate() {
+            if i == index {
+                let amount: Vec<u8> = field
+                    .iter()
+                    .map(|&byte| match byte {
+                        b'.' => self.decimal_separator,
+                        byte if byte == self.decimal_separator => b'.',
+                        byte => byte,
+                    })
+                    .collect();
+                normalized.push_field(&amount);
+            } else {
+                normalized.push_field(field);
+            }
+        }
+        normalized.set_position(row.position().cloned());
+        *row = normalized;
+    }
+
+    /// Decodes `reader` from the input encoding to UTF-8, removing any byte order mark, which
+    /// would otherwise become part of the first header. UTF-8 input, including any invalid UTF-8,
+    /// is passed through as it is.
+    fn decode<R: Read>(&self, reader: R) -> DecodeReaderBytes<R, Vec<u8>> {
+        DecodeReaderBytesBuilder::new()
+            .encoding(Some(self.encoding))
+            .bom_override(true)
+            .strip_bom(true)
+            .utf8_passthru(true)
+            .build(reader)
+    }
+
+    /// Whether `record` is to be processed rather than ignored, because the tx it references is
+    /// within the inclusive range given by `since_tx` and `until_tx`, and it either has no timestamp
+    /// or one within the window given by `from` and `to`.
+    fn includes(&self, record: &Record) -> bool {
+        let tx = record.referenced_tx();
+        self.since_tx.is_none_or(|since| tx >= since)
+            && self.until_tx.is_none_or(|until| tx <= until)
+            && record.timestamp.is_none_or(|timestamp| {
+                self.from.is_none_or(|from| timestamp >= from)
+                    && self.to.is_none_or(|to| timestamp < to)
+            })
+    }
+
+    /// Whether `rows` have been read and no more should be, because they are the maximum or because
+    /// processing was interrupted, in which case a warning is logged.
+    fn stop_reading(&self, rows: usize) -> bool {
+        if self.max_rows.is_some_and(|max_rows| rows >= max_rows) {
+            warn!(
+                "Warning: stopped after reading {} rows, the maximum, so the output is partial",
+                rows
+            );
+            return true;
+        }
+        if self.interrupt.is_some_and(|interrupt| interrupt.is_set()) {
+            warn!(
+                "Warning: interrupted after reading {} rows, so the output is partial",
+                rows
+            );
+            return true;
+        }
+        false
+    }
+
+    /// Logs progress if `rows`, the number read so far, is a multiple of the progress interval.
+    /// `clients` is the number of clients seen so far, if it is known.
+    fn report_progress(&self, rows: usize, clients: Option<usize>) {
+        if self
+            .progress_interval
+            .is_some_and(|interval| rows.is_multiple_of(interval.get()))
+        {
+            match clients {
+                Some(clients) => info!("progress: {} rows, {} clients", rows, clients),
+                None => info!("progress: {} rows", rows),
+            }
+        }
+    }
+}
+
+/// Represents the type of record found in input CSV data.
+#[derive(
+    Debug, Default, Clone, Copy, Eq, Ord, PartialEq, PartialOrd, Hash, Serialize, Deserialize,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum RecordType {
+    /// A deposit is a credit to the client’s asset account, meaning it should increase the
+    /// available and total funds of the client account.
+    #[default]
+    Deposit,
+
+    /// A withdraw is a debit to the client’s asset account, meaning it should decrease the
+    /// available and total funds of the client account.
+    Withdrawal,
+
+    /// A dispute represents a client’s claim that a transaction was erroneous and should be
+    /// reversed. The transaction shouldn’t be reversed yet but the associated funds should be held.
+    /// This means that the clients available funds should decrease by the amount disputed, their
+    /// held funds should increase by the amount disputed, while their total funds should remain the
+    /// same.
+    ///
+    /// When the disputed transaction is a withdrawal, the withdrawn funds are instead returned to
+    /// the account as held funds, i.e. held and total funds increase by the amount disputed. A
+    /// resolve then withdraws them again while a chargeback makes them available.
+    Dispute,
+
+    /// A resolve represents a resolution to a dispute, releasing the associated held funds. Funds
+    /// that were previously disputed are no longer disputed. This means that the clients held funds
+    /// should decrease by the amount no longer disputed, their available funds should increase by
+    /// the amount no longer disputed, and their total funds should remain the same.
+    Resolve,
+
+    /// A chargeback is the final state of a dispute and represents the client reversing a
+    /// transaction. Funds that were held have now been withdrawn. This means that the clients held
+    /// funds and total funds should decrease by the amount previously disputed. If a chargeback
+    /// occurs the client’s account should be immediately frozen.
+    Chargeback,
+
+    /// A transfer moves funds from the client's account to the account of the target client in a
+    /// single operation, decreasing the available and total funds of the client and increasing
+    /// those of the target client. If either account cannot take part, neither is changed.
+    /// Transfers cannot be disputed.
+    Transfer,
+
+    /// An unlock is an administrative record that unlocks the client's account, e.g. after a
+    /// chargeback has been reversed. It is only processed when unlocking is allowed. Its tx is not
+    /// referenced by any other record and its amount is ignored.
+    Unlock,
+}
+
+derive_fromstr_from_deserialize!(RecordType);
+derive_display_from_serialize!(RecordType);
+
+/// Represents an input row in CSV transaction data.
+#[derive(
+    Debug, Default, Clone, Copy, Eq, Ord, PartialEq, PartialOrd, Hash, Serialize, Deserialize,
+)]
+#[serde(rename_all = "snake_case")]
+pub struct Record {
+    #[serde(rename = "type")]
+    /// The type of record.
+    record_type: RecordType,
+
+    /// The client ID will be unique per client though are not guaranteed to be ordered.
+    client: u32,
+
+    /// Transaction IDs (tx) are globally unique and not guaranteed to be ordered.
+    tx: u32,
+
+    /// The amount of the transaction, in fixed-precision decimal type. This type will not
+    /// accumulate errors like a floating point type would.
+    #[serde(default)]
+    #[serde(deserialize_with = "parse_decimal")]
+    amount: Decimal,
+
+    /// The client receiving the funds of a transfer. Other types of record do not have a target
+    /// client, and the column may be omitted entirely from input that has no transfers.
+    #[serde(default)]
+    target_client: Option<u32>,
+
+    /// The transaction referenced by a dispute, resolve or chargeback, which then has its own
+    /// unique `tx`. When it is absent, as it is in older input, `tx` is the referenced transaction.
+    /// Other types of record ignore it, and the column may be omitted entirely.
+    #[serde(default)]
+    reference_tx: Option<u32>,
+
+    /// When the transaction happened. The column may be omitted entirely, or left empty, in which
+    /// case the record is neither filtered by time nor checked for order.
+    #[serde(default)]
+    timestamp: Option<DateTime<Utc>>,
+}
+
+impl Record {
+    /// Creates a record. For dispute, resolve and chargeback records, `tx` is the transaction being
+    /// referenced. The `amount` of a dispute is the part of that transaction being disputed, or zero
+    /// to dispute all of it, and the `amount` of a resolve or chargeback is ignored.
+    pub fn new(record_type: RecordType, client: u32, tx: u32, amount: Decimal) -> Self {
+        Self {
+            record_type,
+            client,
+            tx,
+            amount,
+            target_client: None,
+            reference_tx: None,
+            timestamp: None,
+        }
+    }
+
+    /// Creates a transfer record, moving `amount` from `client` to `target_client`.
+    pub fn transfer(client: u32, target_client: u32, tx: u32, amount: Decimal) -> Self {
+        Self {
+            target_client: Some(target_client),
+            ..Self::new(RecordType::Transfer, client, tx, amount)
+        }
+    }
+
+    /// Creates a dispute, resolve or chargeback record with its own `tx` that references the
+    /// transaction `reference_tx`. Otherwise the same as `new`.
+    pub fn referencing(
+        record_type: RecordType,
+        client: u32,
+        tx: u32,
+        reference_tx: u32,
+        amount: Decimal,
+    ) -> Self {
+        Self {
+            reference_tx: Some(reference_tx),
+            ..Self::new(record_type, client, tx, amount)
+        }
+    }
+
+    /// Returns the record with the given `timestamp`.
+    pub fn with_timestamp(mut self, timestamp: DateTime<Utc>) -> Self {
+        self.timestamp = Some(timestamp);
+        self
+    }
+
+    /// The tx of the transaction that a dispute, resolve or chargeback references, which is
+    /// `reference_tx` if it was given, otherwise `tx`. For other types of record this is `tx`.
+    fn referenced_tx(&self) -> u32 {
+        match self.record_type {
+            RecordType::Dispute | RecordType::Resolve | RecordType::Chargeback => {
+                self.reference_tx.unwrap_or(self.tx)
+            }
+            _ => self.tx,
+        }
+    }
+}
+
+/// Represents the state of a transaction that has been disputed.
+#[derive(Debug, Clone, Copy, Eq, Ord, PartialEq, PartialOrd, Hash, Serialize, Deserialize)]
+enum DisputeState {
+    /// The transaction is disputed and `amount` of its funds, which may be less than the amount of
+    /// the transaction, are held.
+    Disputed { amount: Decimal },
+    /// The dispute was resolved and the held funds released.
+    Resolved,
+    /// The dispute ended in a chargeback.
+    ChargedBack,
+}
+
+/// A custom deserializer for the fixed decimal type. Amounts with too many places past the decimal
+/// are rejected when they are processed, because that depends on the `ProcessingOptions`.
+fn parse_decimal<'de, D>(d: D) -> Result<Decimal, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let value: String = match Option::deserialize(d)? {
+        Some(value) => value,
+        // We do not need to distinguish between nulls and zeros.
+        None => return Ok(Default::default()),
+    };
+    Decimal::from_str(&value).map_err(|e| serde::de::Error::custom(format!("{}", e)))
+}
+
+/// A custom serializer for the fixed decimal type, which formats values with `format_decimal` to
+/// `DECIMAL_PLACES` places past the decimal.
+fn serialize_decimal<S>(value: &Decimal, s: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    s.serialize_str(&format_decimal(
+        *value,
+        DECIMAL_PLACES,
+        RoundingMode::MidpointNearestEven,
+    ))
+}
+
+/// Values are rounded (using banker's rounding unless another `rounding` is given) and padded so
+/// that they always have exactly `scale` places past the decimal. This gives us a single canonical
+/// representation regardless of the precision accumulated during processing.
+fn format_decimal(value: Decimal, scale: u32, rounding: RoundingMode) -> String {
+    let mut rounded = rounding.round(value, scale);
+    rounded.rescale(scale);
+    rounded.to_string()
+}
+
+/// Whether `amount` has more than `scale` places past the decimal. Trailing zeros do not add
+/// precision, so with a scale of four `1.50000` is acceptable but `1.00001` is not.
+fn exceeds_scale(amount: Decimal, scale: u32) -> bool {
+    amount.normalize().scale() > scale
+}
+
+/// Represents the status of a client/account. The fields can be read with the methods of the same
+/// name.
+///
+/// ```
+/// use moneybags::{process_records, ProcessingOptions};
+/// use rust_decimal::Decimal;
+///
+/// let input = "type,client,tx,amount\ndeposit,1,1,2.5\ndispute,1,1,\n";
+/// let result = process_records(input.as_bytes(), &ProcessingOptions::default()).unwrap();
+/// let client = &result.clients[0];
+/// assert_eq!(client.id(), 1);
+/// assert_eq!(client.available(), Decimal::ZERO);
+/// assert_eq!(client.held(), Decimal::new(25, 1));
+/// assert_eq!(client.total(), Decimal::new(25, 1));
+/// assert!(!client.locked());
+/// ```
+#[derive(
+    Debug, Default, Clone, Copy, Eq, Ord, PartialEq, PartialOrd, Hash, Serialize, Deserialize,
+)]
+#[serde(rename_all = "snake_case")]
+pub struct Client {
+    #[serde(rename = "client")]
+    id: u32,
+    #[serde(serialize_with = "serialize_decimal")]
+    available: Decimal,
+    #[serde(serialize_with = "serialize_decimal")]
+    held: Decimal,
+    #[serde(serialize_with = "serialize_decimal")]
+    total: Decimal,
+    locked: bool,
+}
+
+/// The columns of the output, which are the fields of `ClientSummary`.
+const OUTPUT_COLUMNS: [&str; 5] = ["client", "available", "held", "total", "locked"];
+
+/// The columns added to `OUTPUT_COLUMNS` by `--extended`, which are the extra fields of
+/// `ExtendedClientSummary`.
+const EXTENDED_COLUMNS: [&str; 2] = ["tx_count", "last_tx"];
+
+/// A client account as it is written to the output, with amounts formatted to the currency scale.
+#[derive(Debug, Clone, Serialize)]
+struct ClientSummary {
+    client: u32,
+    available: String,
+    held: String,
+    total: String,
+    locked: bool,
+}
+
+impl ClientSummary {
+    fn new(client: &Client, scale: u32, rounding: RoundingMode) -> Self {
+        Self {
+            client: client.id,
+            available: format_decimal(client.available, scale, rounding),
+            held: format_decimal(client.held, scale, rounding),
+            total: format_decimal(client.total, scale, rounding),
+            locked: client.locked,
+        }
+    }
+}
+
+/// A `ClientSummary` with the `Activity` of the client, for `--extended` output.
+#[derive(Debug, Clone, Serialize)]
+struct ExtendedClientSummary {
+    client: u32,
+    available: String,
+    held: String,
+    total: String,
+    locked: bool,
+    tx_count: usize,
+    /// Empty if no transaction records were applied.
+    last_tx: Option<u32>,
+}
+
+impl ExtendedClientSummary {
+    fn new(summary: ClientSummary, activity: Activity) -> Self {
+        Self {
+            client: summary.client,
+            available: summary.available,
+            held: summary.held,
+            total: summary.total,
+            locked: summary.locked,
+            tx_count: activity.tx_count,
+            last_tx: activity.last_tx,
+        }
+    }
+}
+
+/// The transaction records that were applied to a client account.
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+struct Activity {
+    /// The number of records applied, including disputes, resolves and chargebacks.
+    tx_count: usize,
+    /// The highest tx of the records applied.
+    last_tx: Option<u32>,
+    /// The most places past the decimal of the amounts of the deposits, withdrawals, transfers and
+    /// disputes applied, as they were given in the input, if any were applied.
+    #[serde(default)]
+    input_scale: Option<u32>,
+}
+
+impl Activity {
+    fn record(&mut self, record: &Record) {
+        self.tx_count += 1;
+        self.last_tx = self.last_tx.max(Some(record.tx));
+        if matches!(
+            record.record_type,
+            RecordType::Deposit
+                | RecordType::Withdrawal
+                | RecordType::Transfer
+                | RecordType::Dispute
+        ) {
+            self.input_scale = self.input_scale.max(Some(record.amount.scale()));
+        }
+    }
+}
+
+impl Client {
+    pub fn new(id: u32) -> Self {
+        Self {
+            id,
+            ..Default::default()
+        }
+    }
+
+    /// The client ID.
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    /// The funds that are available to withdraw.
+    pub fn available(&self) -> Decimal {
+        self.available
+    }
+
+    /// The funds that are held by disputes.
+    pub fn held(&self) -> Decimal {
+        self.held
+    }
+
+    /// The available and held funds together.
+    pub fn total(&self) -> Decimal {
+        self.total
+    }
+
+    /// Whether the account has been locked by a chargeback.
+    pub fn locked(&self) -> bool {
+        self.locked
+    }
+
+    /// Whether the account has no available, held or total funds and is not locked, which is the
+    /// state of a new account.
+    pub fn is_empty(&self) -> bool {
+        self.available.is_zero() && self.held.is_zero() && self.total.is_zero() && !self.locked
+    }
+
+    /// Checks that the available and held funds add up to the total funds, which is always the case
+    /// unless there is a bug in processing.
+    pub fn check_balance(&self) -> Result<(), ProcessError> {
+        if self.available + self.held != self.total {
+            return Err(ProcessError::Imbalance {
+                client: self.id,
+                available: self.available,
+                held: self.held,
+                total: self.total,
+            });
+        }
+        Ok(())
+    }
+
+    // Every change to the funds of an account is made by one of the methods below, each of which
+    // changes exactly two of the three fields, so that `available + held == total` always holds.
+    // Amounts have a finite range, so each change fails, without changing anything, if it would
+    // take any of the fields beyond that range.
+
+    /// Adds `amount` to the available funds for `tx`, e.g. a deposit.
+    fn credit(&mut self, amount: Decimal, tx: u32) -> Result<(), ProcessError> {
+        self.set_funds(
+            tx,
+            self.available.checked_add(amount),
+            Some(self.held),
+            self.total.checked_add(amount),
+        )
+    }
+
+    /// Removes `amount` from the available funds for `tx`, e.g. a withdrawal.
+    fn debit(&mut self, amount: Decimal, tx: u32) -> Result<(), ProcessError> {
+        self.set_funds(
+            tx,
+            self.available.checked_sub(amount),
+            Some(self.held),
+            self.total.checked_sub(amount),
+        )
+    }
+
+    /// Holds `amount` for a dispute of `tx`, a transaction of the `disputed` type. A disputed
+    /// deposit holds the deposited funds, which may no longer be available. A disputed withdrawal
+    /// is a claim that the funds should not have left the account, so the withdrawn amount is
+    /// provisionally returned to the account as held funds. See
+    /// <https://github.com/webern/moneybags/issues/5>.
+    fn apply_hold(
+        &mut self,
+        amount: Decimal,
+        tx: u32,
+        disputed: RecordType,
+    ) -> Result<(), ProcessError> {
+        if disputed == RecordType::Withdrawal {
+            self.set_funds(
+                tx,
+                Some(self.available),
+                self.held.checked_add(amount),
+                self.total.checked_add(amount),
+            )
+        } else {
+            self.set_funds(
+                tx,
+                self.available.checked_sub(amount),
+                self.held.checked_add(amount),
+                Some(self.total),
+            )
+        }
+    }
+
+    /// Releases `amount` held by `apply_hold` when the dispute of `tx` is resolved. A resolved
+    /// withdrawal stands, so the provisionally returned funds leave again.
+    fn release_hold(
+        &mut self,
+        amount: Decimal,
+        tx: u32,
+        disputed: RecordType,
+    ) -> Result<(), ProcessError> {
+        if disputed == RecordType::Withdrawal {
+            self.set_funds(
+                tx,
+                Some(self.available),
+                self.held.checked_sub(amount),
+                self.total.checked_sub(amount),
+            )
+        } else {
+            self.set_funds(
+                tx,
+                self.available.checked_add(amount),
+                self.held.checked_sub(amount),
+                Some(self.total),
+            )
+        }
+    }
+
+    /// Removes `amount` held by `apply_hold` when the dispute of `tx` ends in a chargeback, and
+    /// locks the account if `lock` is `true`. A charged back withdrawal is reversed, so the held
+    /// funds become available again.
+    fn finalize_chargeback(
+        &mut self,
+        amount: Decimal,
+        tx: u32,
+        disputed: RecordType,
+        lock: bool,
+    ) -> Result<(), ProcessError> {
+        if disputed == RecordType::Withdrawal {
+            self.set_funds(
+                tx,
+                self.available.checked_add(amount),
+                self.held.checked_sub(amount),
+                Some(self.total),
+            )?;
+        } else {
+            self.set_funds(
+                tx,
+                Some(self.available),
+                self.held.checked_sub(amount),
+                self.total.checked_sub(amount),
+            )?;
+        }
+        self.locked |= lock;
+        Ok(())
+    }
+
+    /// Sets the funds, unless any of them is `None` because it overflowed, in which case none of
+    /// them are changed and `tx` is the cause of the `Overflow`.
+    fn set_funds(
+        &mut self,
+        tx: u32,
+        available: Option<Decimal>,
+        held: Option<Decimal>,
+        total: Option<Decimal>,
+    ) -> Result<(), ProcessError> {
+        match (available, held, total) {
+            (Some(available), Some(held), Some(total)) => {
+                self.available = available;
+                self.held = held;
+                self.total = total;
+                Ok(())
+            }
+            _ => Err(ProcessError::Overflow {
+                client: self.id,
+                tx,
+            }),
+        }
+    }
+}
+
+/// Represents the reason that a row was skipped.
+#[derive(Debug, Clone, Copy, Eq, Ord, PartialEq, PartialOrd, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorKind {
+    /// The row could not be parsed.
+    Parse,
+    /// A withdrawal was larger than the available funds.
+    InsufficientFunds,
+    /// A resolve or chargeback was larger than the held funds.
+    InsufficientHeldFunds,
+    /// A dispute, resolve or chargeback referenced a transaction that could not be found.
+    UnknownTx,
+    /// A dispute, resolve or chargeback referenced a transaction belonging to a different client.
+    ClientMismatch,
+    /// The client account is locked.
+    Locked,
+    /// A dispute referenced a transaction that has already been disputed.
+    AlreadyDisputed,
+    /// A resolve or chargeback referenced a transaction that is not currently disputed.
+    NotDisputed,
+    /// A deposit or withdrawal had a negative amount.
+    NegativeAmount,
+    /// A deposit or withdrawal had an amount of zero, when that is not allowed.
+    ZeroAmount,
+    /// A deposit, withdrawal or transfer had an amount of more than the maximum.
+    AmountTooLarge,
+    /// A deposit or withdrawal had the same tx as an earlier deposit or withdrawal.
+    DuplicateTx,
+    /// A transfer did not have a target client.
+    MissingTargetClient,
+    /// A transfer was found while processing on multiple threads, where it can not be atomic.
+    UnsupportedTransfer,
+    /// An unlock was found when unlocking is not allowed.
+    UnlockNotAllowed,
+    /// A dispute was for more than the amount of the disputed transaction.
+    DisputeTooLarge,
+    /// An amount had more places past the decimal than the currency scale.
+    ExcessPrecision,
+    /// A dispute of a deposit was for more than the available funds, when that is not allowed.
+    FundsUnavailable,
+    /// A resolve or chargeback had an amount, in strict mode. Otherwise the amount is ignored.
+    UnexpectedAmount,
+    /// A record would have created an account when there were already the maximum number.
+    TooManyClients,
+    /// A dispute, resolve or chargeback was found in no-dispute mode, where it is always an error.
+    UnexpectedDispute,
+    /// A record would have taken the funds of an account beyond the range of amounts.
+    Overflow,
+    /// A record had a timestamp before that of an earlier record, when that is not allowed.
+    OutOfOrder,
+    /// A record was identical to a recent one, within the dedupe window.
+    Replayed,
+}
+
+impl Display for ErrorKind {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            ErrorKind::Parse => "parse",
+            ErrorKind::InsufficientFunds => "insufficient funds",
+            ErrorKind::InsufficientHeldFunds => "insufficient held funds",
+            ErrorKind::UnknownTx => "unknown tx",
+            ErrorKind::ClientMismatch => "client mismatch",
+            ErrorKind::Locked => "locked",
+            ErrorKind::AlreadyDisputed => "already disputed",
+            ErrorKind::NotDisputed => "not disputed",
+            ErrorKind::NegativeAmount => "negative amount",
+            ErrorKind::ZeroAmount => "zero amount",
+            ErrorKind::AmountTooLarge => "amount too large",
+            ErrorKind::DuplicateTx => "duplicate tx",
+            ErrorKind::MissingTargetClient => "missing target client",
+            ErrorKind::UnsupportedTransfer => "unsupported transfer",
+            ErrorKind::UnlockNotAllowed => "unlock not allowed",
+            ErrorKind::DisputeTooLarge => "dispute too large",
+            ErrorKind::ExcessPrecision => "excess precision",
+            ErrorKind::FundsUnavailable => "funds unavailable",
+            ErrorKind::UnexpectedAmount => "unexpected amount",
+            ErrorKind::TooManyClients => "too many clients",
+            ErrorKind::UnexpectedDispute => "unexpected dispute",
+            ErrorKind::Overflow => "overflow",
+            ErrorKind::OutOfOrder => "out of order",
+            ErrorKind::Replayed => "replayed",
+        };
+        f.write_str(s)
+    }
+}
+
+/// An error that occurred while processing a record. Apart from `Imbalance` and `Store`, these are
+/// the fault of the record, which is skipped unless processing is strict, and each has an
+/// `ErrorKind` so that it can be counted in a `ProcessingReport`.
+#[derive(Debug, thiserror::Error)]
+pub enum ProcessError {
+    #[error("Withdrawal failed. Available funds insufficient.")]
+    InsufficientFunds { client: u32, tx: u32 },
+    #[error("{} failed. Held funds insufficient.", capitalized(.record_type))]
+    InsufficientHeldFunds {
+        record_type: RecordType,
+        client: u32,
+        tx: u32,
+    },
+    #[error("{} record tx {tx} could not be found", referenced(.record_type))]
+    UnknownTx { record_type: RecordType, tx: u32 },
+    /// When ordering is diagnosed, the referenced transaction is on a `line` after the record.
+    #[error(
+        "{} record tx {tx} could not be found because it is on line {line}, after the {record_type}",
+        referenced(.record_type)
+    )]
+    LaterTx {
+        record_type: RecordType,
+        tx: u32,
+        line: u64,
+    },
+    /// When ordering is diagnosed, the referenced transaction on an earlier `line` was skipped.
+    #[error(
+        "{} record tx {tx} could not be found because it was skipped on line {line}",
+        referenced(.record_type)
+    )]
+    SkippedTx {
+        record_type: RecordType,
+        tx: u32,
+        line: u64,
+    },
+    /// When ordering is diagnosed, the referenced transaction is not in the input.
+    #[error(
+        "{} record tx {tx} could not be found anywhere in the input",
+        referenced(.record_type)
+    )]
+    MissingTx { record_type: RecordType, tx: u32 },
+    /// The referenced transaction belongs to the `expected` client, not the `found` client named by
+    /// the record.
+    #[error(
+        "{} record tx {tx} belongs to client {expected} but the {record_type} is for client {found}",
+        referenced(.record_type)
+    )]
+    ClientMismatch {
+        record_type: RecordType,
+        tx: u32,
+        expected: u32,
+        found: u32,
+    },
+    #[error("Client account is locked")]
+    Locked { client: u32 },
+    #[error("Disputed record tx {tx} has already been disputed")]
+    AlreadyDisputed { tx: u32 },
+    #[error("{} record tx {tx} is not disputed", referenced(.record_type))]
+    NotDisputed { record_type: RecordType, tx: u32 },
+    #[error("{} amount {amount} is negative", capitalized(.record_type))]
+    NegativeAmount {
+        record_type: RecordType,
+        tx: u32,
+        amount: Decimal,
+    },
+    #[error("{} tx {tx} has an amount of zero", capitalized(.record_type))]
+    ZeroAmount { record_type: RecordType, tx: u32 },
+    #[error(
+        "{} tx {tx} amount {amount} is more than the maximum of {max}",
+        capitalized(.record_type)
+    )]
+    AmountTooLarge {
+        record_type: RecordType,
+        tx: u32,
+        amount: Decimal,
+        max: Decimal,
+    },
+    #[error("{} tx {tx} is a duplicate", capitalized(.record_type))]
+    DuplicateTx { record_type: RecordType, tx: u32 },
+    #[error("Transfer tx {tx} has no target client")]
+    MissingTargetClient { tx: u32 },
+    #[error("Transfer tx {tx} can not be processed on multiple threads")]
+    UnsupportedTransfer { tx: u32 },
+    #[error("Unlocking client {client} is not allowed")]
+    UnlockNotAllowed { client: u32 },
+    #[error(
+        "Dispute amount {amount} is larger than the amount {disputed} of disputed record tx {tx}"
+    )]
+    DisputeTooLarge {
+        tx: u32,
+        amount: Decimal,
+        disputed: Decimal,
+    },
+    #[error(
+        "{} amount {amount} has more than {scale} places past the decimal",
+        capitalized(.record_type)
+    )]
+    ExcessPrecision {
+        record_type: RecordType,
+        tx: u32,
+        amount: Decimal,
+        scale: u32,
+    },
+    #[error("Disputed funds of tx {tx} are no longer available to client {client}")]
+    FundsUnavailable { client: u32, tx: u32 },
+    #[error(
+        "{} of tx {tx} has an amount {amount}, but it settles the whole dispute",
+        capitalized(.record_type)
+    )]
+    UnexpectedAmount {
+        record_type: RecordType,
+        tx: u32,
+        amount: Decimal,
+    },
+    #[error("Client {client} would be more than the maximum of {max} clients")]
+    TooManyClients { client: u32, max: usize },
+    #[error(
+        "{} of tx {tx} is not allowed in no-dispute mode",
+        capitalized(.record_type)
+    )]
+    UnexpectedDispute { record_type: RecordType, tx: u32 },
+    #[error("Tx {tx} would take the funds of client {client} beyond the range of amounts")]
+    Overflow { client: u32, tx: u32 },
+    #[error(
+        "Tx {tx} has the timestamp {timestamp}, which is before {latest}, that of an earlier row"
+    )]
+    OutOfOrder {
+        tx: u32,
+        timestamp: DateTime<Utc>,
+        latest: DateTime<Utc>,
+    },
+    #[error(
+        "{} tx {tx} is a replay of one of the last {window} records",
+        capitalized(.record_type)
+    )]
+    Replayed {
+        record_type: RecordType,
+        tx: u32,
+        window: usize,
+    },
+    /// The client's funds do not add up, which is a bug rather than the fault of the record.
+    #[error(
+        "Client {client} is out of balance: available {available} + held {held} != total {total}"
+    )]
+    Imbalance {
+        client: u32,
+        available: Decimal,
+        held: Decimal,
+        total: Decimal,
+    },
+    /// The transaction store failed, which is not the fault of the record.
+    #[error(transparent)]
+    Store(#[from] anyhow::Error),
+}
+
+impl ProcessError {
+    /// The reason the record is skipped, or `None` if the error is not the fault of the record.
+    pub fn kind(&self) -> Option<ErrorKind> {
+        Some(match self {
+            ProcessError::InsufficientFunds { .. } => ErrorKind::InsufficientFunds,
+            ProcessError::InsufficientHeldFunds { .. } => ErrorKind::InsufficientHeldFunds,
+            ProcessError::UnknownTx { .. }
+            | ProcessError::LaterTx { .. }
+            | ProcessError::SkippedTx { .. }
+            | ProcessError::MissingTx { .. } => ErrorKind::UnknownTx,
+            ProcessError::ClientMismatch { .. } => ErrorKind::ClientMismatch,
+            ProcessError::Locked { .. } => ErrorKind::Locked,
+            ProcessError::AlreadyDisputed { .. } => ErrorKind::AlreadyDisputed,
+            ProcessError::NotDisputed { .. } => ErrorKind::NotDisputed,
+            ProcessError::NegativeAmount { .. } => ErrorKind::NegativeAmount,
+            ProcessError::ZeroAmount { .. } => ErrorKind::ZeroAmount,
+            ProcessError::AmountTooLarge { .. } => ErrorKind::AmountTooLarge,
+            ProcessError::DuplicateTx { .. } => ErrorKind::DuplicateTx,
+            ProcessError::MissingTargetClient { .. } => ErrorKind::MissingTargetClient,
+            ProcessError::UnsupportedTransfer { .. } => ErrorKind::UnsupportedTransfer,
+            ProcessError::UnlockNotAllowed { .. } => ErrorKind::UnlockNotAllowed,
+            ProcessError::DisputeTooLarge { .. } => ErrorKind::DisputeTooLarge,
+            ProcessError::ExcessPrecision { .. } => ErrorKind::ExcessPrecision,
+            ProcessError::FundsUnavailable { .. } => ErrorKind::FundsUnavailable,
+            ProcessError::UnexpectedAmount { .. } => ErrorKind::UnexpectedAmount,
+            ProcessError::TooManyClients { .. } => ErrorKind::TooManyClients,
+            ProcessError::UnexpectedDispute { .. } => ErrorKind::UnexpectedDispute,
+            ProcessError::Overflow { .. } => ErrorKind::Overflow,
+            ProcessError::OutOfOrder { .. } => ErrorKind::OutOfOrder,
+            ProcessError::Replayed { .. } => ErrorKind::Replayed,
+            ProcessError::Imbalance { .. } | ProcessError::Store(_) => return None,
+        })
+    }
+}
+
+/// How a record that references another record is described in error messages.
+fn referenced(record_type: &RecordType) -> &'static str {
+    match record_type {
+        RecordType::Dispute => "Disputed",
+        RecordType::Resolve => "Resolved",
+        RecordType::Chargeback => "Chargeback",
+        RecordType::Deposit => "Deposit",
+        RecordType::Withdrawal => "Withdrawal",
+        RecordType::Transfer => "Transfer",
+        RecordType::Unlock => "Unlock",
+    }
+}
+
+/// The record type at the start of an error message.
+fn capitalized(record_type: &RecordType) -> &'static str {
+    match record_type {
+        RecordType::Deposit => "Deposit",
+        RecordType::Withdrawal => "Withdrawal",
+        RecordType::Dispute => "Dispute",
+        RecordType::Resolve => "Resolve",
+        RecordType::Chargeback => "Chargeback",
+        RecordType::Transfer => "Transfer",
+        RecordType::Unlock => "Unlock",
+    }
+}
+
+/// A flag that, once set, e.g. by a signal handler, stops processing before the next row is read.
+/// Interrupts are compared by the flag that they refer to, not by whether it is set.
+#[derive(Debug, Clone, Copy)]
+pub struct Interrupt(&'static AtomicBool);
+
+impl Interrupt {
+    pub fn new(flag: &'static AtomicBool) -> Self {
+        Self(flag)
+    }
+
+    /// Whether the flag has been set.
+    pub fn is_set(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+
+    fn address(&self) -> *const AtomicBool {
+        self.0
+    }
+}
+
+impl PartialEq for Interrupt {
+    fn eq(&self, other: &Self) -> bool {
+        self.address() == other.address()
+    }
+}
+
+impl Eq for Interrupt {}
+
+impl PartialOrd for Interrupt {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Interrupt {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.address().cmp(&other.address())
+    }
+}
+
+/// A summary of the rows that were read and skipped while processing the input.
+#[derive(Debug, Default, Clone, Eq, PartialEq)]
+pub struct ProcessingReport {
+    /// The number of rows read from the input, whether or not they were processed successfully.
+    pub rows: usize,
+    /// The number of rows that were skipped, keyed by the reason they were skipped.
+    pub skipped: BTreeMap<ErrorKind, usize>,
+    /// The number of rows that were ignored without being processed or skipped, because they were
+    /// outside of the tx or timestamp range given, or for a locked account when the locked policy
+    /// is `Ignore`.
+    pub ignored: usize,
+    /// The number of records for a client whose account was locked, whether or not they were
+    /// skipped, if `alert_on_locked_activity` is `true`.
+    pub locked_activity: usize,
+}
+
+impl ProcessingReport {
+    /// The total number of rows that were skipped, for any reason.
+    pub fn total_skipped(&self) -> usize {
+        self.skipped.values().sum()
+    }
+
+    /// The number of rows that were processed successfully, i.e. neither skipped nor ignored.
+    pub fn processed(&self) -> usize {
+        self.rows - self.total_skipped() - self.ignored
+    }
+
+    fn skip(&mut self, kind: ErrorKind) {
+        *self.skipped.entry(kind).or_default() += 1;
+    }
+
+    /// Adds the counts from `other` to this report.
+    fn merge(&mut self, other: ProcessingReport) {
+        self.rows += other.rows;
+        self.ignored += other.ignored;
+        self.locked_activity += other.locked_activity;
+        for (kind, count) in other.skipped {
+            *self.skipped.entry(kind).or_default() += count;
+        }
+    }
+}
+
+/// Produces a one-line summary like `skipped 12 rows (3 parse, 4 insufficient funds, 5 locked)`.
+impl Display for ProcessingReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "skipped {} rows", self.total_skipped())?;
+        if self.skipped.is_empty() {
+            return Ok(());
+        }
+        let counts = self
+            .skipped
+            .iter()
+            .map(|(kind, count)| format!("{} {}", count, kind))
+            .collect::<Vec<_>>();
+        write!(f, " ({})", counts.join(", "))
+    }
+}
+
+/// The counts from a `ProcessingReport` along with how long processing took.
+struct ProcessingStats<'a> {
+    report: &'a ProcessingReport,
+    elapsed: Duration,
+}
+
+impl<'a> ProcessingStats<'a> {
+    fn new(report: &'a ProcessingReport, elapsed: Duration) -> Self {
+        Self { report, elapsed }
+    }
+
+    /// The number of rows read per second, or zero if no time was measured.
+    fn rows_per_sec(&self) -> f64 {
+        let secs = self.elapsed.as_secs_f64();
+        if secs > 0.0 {
+            self.report.rows as f64 / secs
+        } else {
+            0.0
+        }
+    }
+}
+
+/// Produces a line of space-separated `key=value` pairs like
+/// `stats: rows=5 processed=4 skipped=1 ignored=0 elapsed_secs=0.000120 rows_per_sec=41666.7`.
+impl Display for ProcessingStats<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "stats: rows={} processed={} skipped={} ignored={} elapsed_secs={:.6} rows_per_sec={:.1}",
+            self.report.rows,
+            self.report.processed(),
+            self.report.total_skipped(),
+            self.report.ignored,
+            self.elapsed.as_secs_f64(),
+            self.rows_per_sec()
+        )
+    }
+}
+
+/// The outcome of processing transaction records.
+#[derive(Debug, Default, Clone, Eq, PartialEq)]
+pub struct ProcessingResult {
+    /// The resultant state of each client account, ordered by client ID.
+    pub clients: Vec<Client>,
+    /// A summary of the rows that were processed and skipped.
+    pub report: ProcessingReport,
+    /// The transactions that are still disputed, i.e. neither resolved nor charged back, ordered by
+    /// tx.
+    pub open_disputes: Vec<OpenDispute>,
+}
+
+/// A transaction that was disputed and whose dispute has not been settled, so its funds are held.
+#[derive(Debug, Default, Clone, Copy, Eq, Ord, PartialEq, PartialOrd, Hash)]
+pub struct OpenDispute {
+    /// The tx of the disputed transaction.
+    pub tx: u32,
+    /// The client whose transaction it is.
+    pub client: u32,
+    /// The amount held by the dispute, which may be less than the amount of the transaction.
+    pub amount: Decimal,
+}
+
+/// The outcome of processing transaction records, with the clients still in the map in which they
+/// were processed, keyed and therefore ordered by client ID, so that they can be written out
+/// without being collected into a `ProcessingResult`.
+struct Processed {
+    clients: BTreeMap<u32, Client>,
+    report: ProcessingReport,
+    open_disputes: Vec<OpenDispute>,
+    /// The records applied to each client account, keyed by client ID. Accounts to which no record
+    /// was applied, e.g. those from `--state`, are missing.
+    activity: BTreeMap<u32, Activity>,
+}
+
+impl ProcessingResult {
+    /// The resultant state of the client with the given `id`, if it has an account.
+    ///
+    /// ```
+    /// use moneybags::{process_records, ProcessingOptions};
+    /// use rust_decimal::Decimal;
+    ///
+    /// let input = "type,client,tx,amount\ndeposit,1,1,2.5\ndeposit,7,2,1.0\n";
+    /// let result = process_records(input.as_bytes(), &ProcessingOptions::default()).unwrap();
+    /// let client = result.client(7).unwrap();
+    /// assert_eq!(client.available(), Decimal::new(1, 0));
+    /// assert!(result.client(2).is_none());
+    /// ```
+    pub fn client(&self, id: u32) -> Option<&Client> {
+        // The clients are ordered by ID, so they can be searched.
+        self.clients
+            .binary_search_by_key(&id, Client::id)
+            .ok()
+            .map(|i| &self.clients[i])
+    }
+
+    /// The sums of the funds of every client account, which is an error if they are beyond the
+    /// range of amounts.
+    pub fn totals(&self) -> Result<Totals> {
+        Totals::new(&self.clients)
+    }
+}
+
+/// The sums of the funds of a set of client accounts. In a closed system, the sum of the `total`
+/// funds of every account is the sum of the deposits less the withdrawals and the chargebacks, so
+/// this can be used to reconcile the accounts with the transactions.
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq)]
+pub struct Totals {
+    /// The number of accounts.
+    pub clients: usize,
+    pub available: Decimal,
+    pub held: Decimal,
+    pub total: Decimal,
+    /// The number of accounts that are locked.
+    pub locked: usize,
+}
+
+impl Totals {
+    /// Sums the funds of the `clients`, which is an error if any of the sums is beyond the range of
+    /// amounts, even though the funds of each account are not.
+    pub fn new<'a>(clients: impl IntoIterator<Item = &'a Client>) -> Result<Self> {
+        let overflow = "The sum of the funds of the accounts is beyond the range of amounts";
+        let mut totals = Self::default();
+        for client in clients {
+            totals.clients += 1;
+            totals.available = totals
+                .available
+                .checked_add(client.available)
+                .context(overflow)?;
+            totals.held = totals.held.checked_add(client.held).context(overflow)?;
+            totals.total = totals.total.checked_add(client.total).context(overflow)?;
+            totals.locked += usize::from(client.locked);
+        }
+        Ok(totals)
+    }
+
+    /// A line of space-separated `key=value` pairs like
+    /// `totals: clients=2 available=3.5000 held=0.0000 total=3.5000 locked=0`, with the funds
+    /// formatted like the output.
+    fn line(&self, scale: u32, rounding: RoundingMode) -> String {
+        format!(
+            "totals: clients={} available={} held={} total={} locked={}",
+            self.clients,
+            format_decimal(self.available, scale, rounding),
+            format_decimal(self.held, scale, rounding),
+            format_decimal(self.total, scale, rounding),
+            self.locked
+        )
+    }
+}
+
+/// The aggregates written by `--summary-only`, with the funds formatted like the output.
+#[derive(Debug, Clone, Serialize)]
+struct Summary {
+    clients: usize,
+    locked: usize,
+    available: String,
+    held: String,
+    total: String,
+    rows: usize,
+    skipped: usize,
+}
+
+impl Summary {
+    fn new(report: &ProcessingReport, totals: Totals, options: &ProcessingOptions) -> Self {
+        Self {
+            clients: totals.clients,
+            locked: totals.locked,
+            available: format_decimal(totals.available, options.scale, options.rounding),
+            held: format_decimal(totals.held, options.scale, options.rounding),
+            total: format_decimal(totals.total, options.scale, options.rounding),
+            rows: report.rows,
+            skipped: report.total_skipped(),
+        }
+    }
+}
+
+/// A way in which a client account differs from the account of the same client in a reference.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Difference {
+    /// The client has an account in the reference, but not otherwise.
+    Missing { client: u32 },
+    /// The client has an account, but not in the reference.
+    Unexpected { client: u32 },
+    /// The `field` of the client's account, formatted as it is in the output, is `actual` rather
+    /// than the `expected` value in the reference.
+    Field {
+        client: u32,
+        field: &'static str,
+        actual: String,
+        expected: String,
+    },
+}
+
+impl Difference {
+    /// The client whose account differs.
+    pub fn client(&self) -> u32 {
+        match self {
+            Self::Missing { client } | Self::Unexpected { client } | Self::Field { client, .. } => {
+                *client
+            }
+        }
+    }
+}
+
+/// Produces a line like `client 2: available is 1.5000, expected 2.0000`.
+impl Display for Difference {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Missing { client } => write!(f, "client {}: missing", client),
+            Self::Unexpected { client } => write!(f, "client {}: not in the reference", client),
+            Self::Field {
+                client,
+                field,
+                actual,
+                expected,
+            } => write!(
+                f,
+                "client {}: {} is {}, expected {}",
+                client, field, actual, expected
+            ),
+        }
+    }
+}
+
+/// The outcome of comparing client accounts with those of a reference with `diff_clients`.
+#[derive(Debug, Default, Clone, Eq, PartialEq)]
+pub struct DiffReport {
+    /// The number of clients compared, i.e. those with an account in either.
+    pub clients: usize,
+    /// The differences, in order of client ID.
+    pub differences: Vec<Difference>,
+}
+
+impl DiffReport {
+    /// Whether every account is the same as in the reference.
+    pub fn is_same(&self) -> bool {
+        self.differences.is_empty()
+    }
+
+    /// The number of clients whose accounts differ.
+    pub fn differing_clients(&self) -> usize {
+        let mut clients = self
+            .differences
+            .iter()
+            .map(Difference::client)
+            .collect::<Vec<_>>();
+        clients.dedup();
+        clients.len()
+    }
+}
+
+/// Produces a line for each difference, followed by either `PASS: 2 clients are the same` or
+/// `FAIL: 1 of 2 clients differ`.
+impl Display for DiffReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        for difference in &self.differences {
+            writeln!(f, "{}", difference)?;
+        }
+        if self.is_same() {
+            writeln!(f, "PASS: {} clients are the same", self.clients)
+        } else {
+            writeln!(
+                f,
+                "FAIL: {} of {} clients differ",
+                self.differing_clients(),
+                self.clients
+            )
+        }
+    }
+}
+
+/// Compares the `actual` client accounts with the `expected` accounts of a reference, e.g. the
+/// output of another version. Funds are compared as they are written to the output, rounded to
+/// `scale` places, so that the accounts can be compared with those read from an output.
+pub fn diff_clients(
+    actual: &BTreeMap<u32, Client>,
+    expected: &BTreeMap<u32, Client>,
+    scale: u32,
+    rounding: RoundingMode,
+) -> DiffReport {
+    let ids = actual
+        .keys()
+        .chain(expected.keys())
+        .copied()
+        .collect::<BTreeSet<_>>();
+    let mut differences = Vec::new();
+    for &client in &ids {
+        let (actual, expected) = match (actual.get(&client), expected.get(&client)) {
+            (Some(actual), Some(expected)) => (
+                ClientSummary::new(actual, scale, rounding),
+                ClientSummary::new(expected, scale, rounding),
+            ),
+            (None, _) => {
+                differences.push(Difference::Missing { client });
+                continue;
+            }
+            (_, None) => {
+                differences.push(Difference::Unexpected { client });
+                continue;
+            }
+        };
+        let fields = [
+            ("available", actual.available, expected.available),
+            ("held", actual.held, expected.held),
+            ("total", actual.total, expected.total),
+            (
+                "locked",
+                actual.locked.to_string(),
+                expected.locked.to_string(),
+            ),
+        ];
+        for (field, actual, expected) in fields {
+            if actual != expected {
+                differences.push(Difference::Field {
+                    client,
+                    field,
+                    actual,
+                    expected,
+                });
+            }
+        }
+    }
+    DiffReport {
+        clients: ids.len(),
+        differences,
+    }
+}
+
+impl From<Processed> for ProcessingResult {
+    fn from(processed: Processed) -> Self {
+        // Callers rely on the clients being in ascending order of ID, which a `BTreeMap` gives us.
+        // If it is ever replaced, e.g. by a `HashMap`, the clients must be sorted here instead.
+        Self {
+            clients: processed.clients.into_values().collect(),
+            report: processed.report,
+            open_disputes: processed.open_disputes,
+        }
+    }
+}
+
+/// Processes the CSV transaction records found in `reader` and returns the resultant state of each
+/// client account, ordered by client ID. Rows that cannot be parsed or processed are logged as
+/// warnings with the `log` crate, counted in the `ProcessingReport`, and skipped, unless
+/// `options.strict` is `true`, in which case the first such row is returned as an error. This does
+/// not touch the filesystem, so any `Read` will do, e.g. an in-memory buffer or a network socket.
+pub fn process_records(reader: impl Read, options: &ProcessingOptions) -> Result<ProcessingResult> {
+    process_records_with_store(reader, options, BTreeMap::new())
+}
+
+/// Like `process_records`, but keeps deposit and withdrawal records, which may later be referenced
+/// by disputes, in the given `TransactionStore`. An error from the store stops processing, even
+/// when `options.strict` is `false`.
+pub fn process_records_with_store(
+    reader: impl Read,
+    options: &ProcessingOptions,
+    records: impl TransactionStore,
+) -> Result<ProcessingResult> {
+    let ledger = Ledger::new(Engine::with_store(*options, records));
+    process_reader(reader, options, ledger).map(ProcessingResult::from)
+}
+
+/// Processes the transaction records found in `reader`, in the input format given by `options`,
+/// with the `ledger`.
+fn process_reader<S: TransactionStore>(
+    mut reader: impl Read,
+    options: &ProcessingOptions,
+    mut ledger: Ledger<'_, S>,
+) -> Result<Processed> {
+    if !options.diagnose_ordering {
+        let results = parse_input(reader, options)?;
+        return process_results(results, options, ledger);
+    }
+    // The input is parsed twice, first to find the transactions, so it has to be kept.
+    let mut input = Vec::new();
+    reader
+        .read_to_end(&mut input)
+        .context("Unable to read input")?;
+    ledger.transaction_lines = Some(transaction_lines(parse_input(&input[..], options)?));
+    let results = parse_input(&input[..], options)?;
+    process_results(results, options, ledger)
+}
+
+/// The records parsed from `reader`, in the input format given by `options`, along with their
+/// positions in the input, or the errors that occurred while parsing them.
+type ParseResults<'a> =
+    Box<dyn Iterator<Item = Result<(Record, Option<Position>), ParseFailure>> + 'a>;
+
+/// Parses the transaction records found in `reader`, in the input format given by `options`. A CSV
+/// header is checked before any record is parsed.
+fn parse_input<'a>(
+    reader: impl Read + 'a,
+    options: &ProcessingOptions,
+) -> Result<ParseResults<'a>> {
+    if options.input_format == InputFormat::Jsonl {
+        return Ok(Box::new(jsonl_records(options.decode(reader))));
+    }
+    let mut csv_reader = options.csv_reader(reader)?;
+    let headers = options.csv_headers(&mut csv_reader)?;
+    let amount_index = options.amount_index(&headers);
+    let options = *options;
+    Ok(Box::new(csv_reader.into_byte_records().map(move |row| {
+        let mut row = row?;
+        options.normalize_amount(&mut row, amount_index);
+        let record = row.deserialize(Some(&headers))?;
+        Ok((record, row.position().cloned()))
+    })))
+}
+
+/// The line of every deposit and withdrawal in `results`, keyed by tx. Only the first line is kept
+/// for a duplicate tx. Rows that can not be parsed are left to be reported when they are processed.
+fn transaction_lines(results: ParseResults<'_>) -> BTreeMap<u32, u64> {
+    let mut lines = BTreeMap::new();
+    for (record, position) in results.flatten() {
+        if matches!(
+            record.record_type,
+            RecordType::Deposit | RecordType::Withdrawal
+        ) {
+            let line = position.map_or(0, |position| position.line());
+            lines.entry(record.tx).or_insert(line);
+        }
+    }
+    lines
+}
+
+/// The columns that CSV input must have, in any order.
+const REQUIRED_COLUMNS: [&str; 4] = ["type", "client", "tx", "amount"];
+
+/// The columns that CSV input may have in addition to `REQUIRED_COLUMNS`.
+const OPTIONAL_COLUMNS: [&str; 3] = ["target_client", "reference_tx", "timestamp"];
+
+/// The columns of CSV input without a header, which must be in this order. Trailing optional columns
+/// may be omitted.
+fn positional_headers() -> ByteRecord {
+    REQUIRED_COLUMNS.iter().chain(&OPTIONAL_COLUMNS).collect()
+}
+
+/// Checks that the CSV `headers` have each of the `REQUIRED_COLUMNS`, and none of them or of the
+/// `OPTIONAL_COLUMNS` more than once, so that a mistake in the header is reported once rather than
+/// as a parse error on every row. Other columns are ignored, like the fields serde does not know,
+/// unless `deny_unknown_columns` is `true`, in which case they are an error too. Empty input has no
+/// header, which is an error, but a header without any rows is not.
+fn check_headers(headers: &StringRecord, deny_unknown_columns: bool) -> Result<()> {
+    let expected = format!(
+        "expected the columns {} in any order, optionally with {}",
+        REQUIRED_COLUMNS.join(", "),
+        OPTIONAL_COLUMNS.join(", ")
+    );
+    ensure!(
+        !headers.is_empty(),
+        "The input is empty, without a CSV header, {}",
+        expected
+    );
+    for (i, column) in headers.iter().enumerate() {
+        let known = REQUIRED_COLUMNS.contains(&column) || OPTIONAL_COLUMNS.contains(&column);
+        ensure!(
+            known || !deny_unknown_columns,
+            "Unexpected column '{}' in the CSV header, {}",
+            column,
+            expected
+        );
+        ensure!(
+            !known || !headers.iter().take(i).any(|previous| previous == column),
+            "Duplicate column '{}' in the CSV header, {}",
+            column,
+            expected
+        );
+    }
+    for column in REQUIRED_COLUMNS {
+        ensure!(
+            headers.iter().any(|header| header == column),
+            "Missing column '{}' in the CSV header, {}",
+            column,
+            expected
+        );
+    }
+    Ok(())
+}
+
+/// Processes `records` that have already been parsed, e.g. records constructed in memory, with the
+/// same logic as `process_records`. Records that cannot be processed are logged as warnings,
+/// counted in the `ProcessingReport`, and skipped, unless `options.strict` is `true`, in which case
+/// the first such record is returned as an error.
+pub fn process(
+    records: impl IntoIterator<Item = Record>,
+    options: &ProcessingOptions,
+) -> Result<ProcessingResult> {
+    let results = records.into_iter().map(|record| Ok((record, None)));
+    process_results(results, options, Ledger::new(Engine::new(*options)))
+        .map(ProcessingResult::from)
+}
+
+/// Combines the clients from the results of separate runs, e.g. of shards of the input split by
+/// client, into one list ordered by client ID. Each client must be in only one of the `results`,
+/// because their accounts can not be combined without the transactions that produced them, so a
+/// client that appears more than once is an error.
+pub fn merge(results: impl IntoIterator<Item = Vec<Client>>) -> Result<Vec<Client>> {
+    let mut merged = BTreeMap::new();
+    for (shard, clients) in results.into_iter().enumerate() {
+        for client in clients {
+            ensure!(
+                merged.insert(client.id, client).is_none(),
+                "Client {} appears in more than one result, including result {} of them",
+                client.id,
+                shard + 1
+            );
+        }
+    }
+    Ok(merged.into_values().collect())
+}
+
+/// Reads a JSON object from each line of `reader`, skipping blank lines. Reading stops after the
+/// first IO error.
+fn jsonl_records(
+    reader: impl Read,
+) -> impl Iterator<Item = Result<(Record, Option<Position>), ParseFailure>> {
+    let mut reader = BufReader::new(reader);
+    let mut next = Position::new();
+    let mut line = Vec::new();
+    let mut done = false;
+    std::iter::from_fn(move || {
+        while !done {
+            let position = next.clone();
+            line.clear();
+            match reader.read_until(b'\n', &mut line) {
+                Ok(0) => done = true,
+                Ok(n) => {
+                    next.set_byte(next.byte() + n as u64);
+                    next.set_line(next.line() + 1);
+                }
+                Err(e) => {
+                    done = true;
+                    return Some(Err(ParseFailure::new(e, Some(position))));
+                }
+            }
+            if line.iter().all(u8::is_ascii_whitespace) {
+                continue;
+            }
+            return Some(
+                serde_json::from_slice(&line)
+                    .map(|record| (record, Some(position.clone())))
+                    .map_err(|e| ParseFailure::new(e, Some(position))),
+            );
+        }
+        None
+    })
+}
+
+/// Processes each record, along with its position in the input if it was read from one, or the error
+/// that occurred while parsing it, in order, with the `ledger`.
+fn process_results<S: TransactionStore>(
+    results: impl Iterator<Item = Result<(Record, Option<Position>), ParseFailure>>,
+    options: &ProcessingOptions,
+    mut ledger: Ledger<'_, S>,
+) -> Result<Processed> {
+    let mut results = results.peekable();
+    if let Some(byte) = ledger.resumed_at {
+        // The rows before the checkpoint are read again, to find it, but not processed.
+        for _ in 0..ledger.report.rows {
+            ensure!(
+                results.next().is_some(),
+                "The input ends before the checkpoint"
+            );
+        }
+        ensure!(
+            results.peek().and_then(result_position).map(Position::byte) == Some(byte),
+            "The input does not match the checkpoint, which is of a row at byte {}",
+            byte
+        );
+    }
+
+    while !options.stop_reading(ledger.report.rows) {
+        let result = match results.next() {
+            Some(result) => result,
+            None => break,
+        };
+        ledger.process_row(result, options)?;
+    }
+
+    ledger.into_processed()
+}
+
+/// The position in the input of the row that was either parsed or failed to parse.
+fn result_position(result: &Result<(Record, Option<Position>), ParseFailure>) -> Option<&Position> {
+    match result {
+        Ok((_, position)) => position.as_ref(),
+        Err(failure) => failure.position.as_ref(),
+    }
+}
+
+/// The number of rows sent to a worker thread at a time by `process_records_threaded`.
+const BATCH_SIZE: usize = 1024;
+
+/// The number of batches that can be waiting for each worker thread before the reader blocks.
+const BATCHES_IN_FLIGHT: usize = 16;
+
+/// Like `process_records`, but spreads the work across `threads` worker threads. Client accounts
+/// are independent, and a dispute can only reference a transaction of the same client, so each
+/// client is assigned to a worker, by `client % threads`, which owns all of the state for that
+/// client. The calling thread reads rows and routes them to the workers, which parse and process
+/// them. The results are the same as `process_records`, but messages that are logged may be
+/// interleaved differently and, when `options.strict` is `true`, which error is returned is not
+/// deterministic. The exception is transfers, which involve two clients that may belong to
+/// different workers, so they can not be processed and are skipped as unsupported.
+pub fn process_records_threaded(
+    reader: impl Read,
+    options: &ProcessingOptions,
+    threads: NonZeroUsize,
+) -> Result<ProcessingResult> {
+    process_threaded(reader, options, threads, BTreeMap::new()).map(ProcessingResult::from)
+}
+
+/// Implements `process_records_threaded`, starting from the accounts in `clients`.
+fn process_threaded(
+    reader: impl Read,
+    options: &ProcessingOptions,
+    threads: NonZeroUsize,
+    clients: BTreeMap<u32, Client>,
+) -> Result<Processed> {
+    ensure!(
+        options.input_format == InputFormat::Csv,
+        "Only CSV input can be processed on multiple threads"
+    );
+    ensure!(
+        !options.diagnose_ordering,
+        "Ordering can not be diagnosed on multiple threads"
+    );
+    ensure!(
+        options.max_clients.is_none(),
+        "The number of clients can not be limited on multiple threads"
+    );
+    ensure!(
+        !options.enforce_order,
+        "The order of timestamps can not be enforced on multiple threads"
+    );
+    ensure!(
+        options.dedupe_window.is_none(),
+        "Replayed records can not be skipped on multiple threads"
+    );
+    let threads = threads.get();
+    let mut csv_reader = options.csv_reader(reader)?;
+    let headers = options.csv_headers(&mut csv_reader)?;
+    let amount_index = options.amount_index(&headers);
+    let client_index = headers.iter().position(|header| header == b"client");
+    // Each worker starts with the accounts of the clients assigned to it.
+    let mut shards = vec![BTreeMap::new(); threads];
+    for (id, client) in clients {
+        shards[id as usize % threads].insert(id, client);
+    }
+
+    std::thread::scope(|scope| {
+        let (senders, workers): (Vec<_>, Vec<_>) = shards
+            .into_iter()
+            .map(|clients| {
+                let (sender, receiver) = sync_channel::<Vec<ByteRecord>>(BATCHES_IN_FLIGHT);
+                let headers = &headers;
+                let worker = scope.spawn(move || -> Result<Processed> {
+                    let mut ledger = Ledger::new(Engine::new(*options)).with(clients, None);
+                    ledger.transfers = false;
+                    for mut row in receiver.into_iter().flatten() {
+                        options.normalize_amount(&mut row, amount_index);
+                        match row.deserialize(Some(headers)) {
+                            Ok(record) => ledger.apply(record, row.position(), options)?,
+                            Err(e) => parse_error(&mut ledger.report, e.into(), options)?,
+                        }
+                    }
+                    ledger.into_processed()
+                });
+                (sender, worker)
+            })
+            .unzip();
+
+        let mut report = ProcessingReport::default();
+        let mut read_error = None;
+        let mut batches = vec![Vec::with_capacity(BATCH_SIZE); threads];
+        while !options.stop_reading(report.rows) {
+            let mut row = ByteRecord::new();
+            let result = csv_reader.read_byte_record(&mut row);
+            if !matches!(result, Ok(false)) {
+                report.rows += 1;
+                // The clients are spread across the workers, so their number is not known here.
+                options.report_progress(report.rows, None);
+            }
+            match result {
+                Ok(true) => {}
+                Ok(false) => break,
+                Err(e) if e.is_io_error() => {
+                    read_error = Some(anyhow::Error::new(e).context("Unable to read csv"));
+                    break;
+                }
+                // Otherwise the row is malformed, e.g. it has the wrong number of fields.
+                Err(e) => match parse_error(&mut report, e.into(), options) {
+                    Ok(_) => continue,
+                    Err(e) => {
+                        read_error = Some(e);
+                        break;
+                    }
+                },
+            }
+            // A row without a valid client ID goes to the first worker, where it will fail to parse.
+            let shard = client_index
+                .and_then(|i| row.get(i))
+                .and_then(|client| std::str::from_utf8(client).ok())
+                .and_then(|client| client.trim().parse::<usize>().ok())
+                .map_or(0, |client| client % threads);
+            batches[shard].push(row);
+            if batches[shard].len() == BATCH_SIZE {
+                let batch = std::mem::replace(&mut batches[shard], Vec::with_capacity(BATCH_SIZE));
+                // If the worker has stopped then it has an error, which we get when we join it.
+                if senders[shard].send(batch).is_err() {
+                    break;
+                }
+            }
+        }
+        // Dropping the senders, once any remaining rows are sent, lets the workers finish.
+        if read_error.is_none() {
+            for (sender, batch) in senders.iter().zip(batches) {
+                let _ = sender.send(batch);
+            }
+        }
+        drop(senders);
+
+        let mut clients = BTreeMap::new();
+        let mut open_disputes = Vec::new();
+        let mut activity = BTreeMap::new();
+        for worker in workers {
+            let mut processed = worker
+                .join()
+                .map_err(|_| anyhow::anyhow!("A worker thread panicked"))??;
+            clients.append(&mut processed.clients);
+            report.merge(processed.report);
+            open_disputes.append(&mut processed.open_disputes);
+            activity.append(&mut processed.activity);
+        }
+        if let Some(e) = read_error {
+            return Err(e);
+        }
+        open_disputes.sort();
+        Ok(Processed {
+            clients,
+            report,
+            open_disputes,
+            activity,
+        })
+    })
+}
+
+/// A row that failed validation.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct ValidationError {
+    /// The line of the input on which the row starts.
+    pub line: u64,
+    /// Why the row is invalid.
+    pub kind: ErrorKind,
+    pub message: String,
+}
+
+/// The outcome of validating transaction records with `validate_records`.
+#[derive(Debug, Default, Clone, Eq, PartialEq)]
+pub struct ValidationReport {
+    /// The number of rows read from the input, whether or not they were valid.
+    pub rows: usize,
+    /// The invalid rows, in the order they were found.
+    pub errors: Vec<ValidationError>,
+}
+
+impl ValidationReport {
+    /// Whether every row was valid.
+    pub fn is_valid(&self) -> bool {
+        self.errors.is_empty()
+    }
+}
+
+/// Produces a line for each invalid row, like `line 3: <message>`, followed by either
+/// `PASS: 4 rows are valid` or `FAIL: 1 of 4 rows are invalid`.
+impl Display for ValidationReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        for error in &self.errors {
+            writeln!(f, "line {}: {}", error.line, error.message)?;
+        }
+        if self.is_valid() {
+            writeln!(f, "PASS: {} rows are valid", self.rows)
+        } else {
+            writeln!(
+                f,
+                "FAIL: {} of {} rows are invalid",
+                self.errors.len(),
+                self.rows
+            )
+        }
+    }
+}
+
+/// Checks the CSV transaction records found in `reader` without computing any balances. A row is
+/// invalid if it cannot be parsed, if it is a deposit, withdrawal or transfer with a negative amount
+/// or a duplicate tx, if it is a transfer without a target client, or if it is a dispute, resolve
+/// or chargeback that does not reference an earlier deposit or withdrawal of the same client, or a
+/// dispute for more than the amount of that transaction. Rows outside of the tx range given by
+/// `options` are ignored. Every row is checked, regardless of `options.strict`.
+pub fn validate_records(
+    reader: impl Read,
+    options: &ProcessingOptions,
+) -> Result<ValidationReport> {
+    ensure!(
+        options.input_format == InputFormat::Csv,
+        "Only CSV input can be validated"
+    );
+    let mut csv_reader = options.csv_reader(reader)?;
+    let headers = options.csv_headers(&mut csv_reader)?;
+    let amount_index = options.amount_index(&headers);
+    let mut report = ValidationReport::default();
+    // Every valid deposit and withdrawal, keyed by tx.
+    let mut transactions = BTreeMap::new();
+    let mut row = ByteRecord::new();
+    loop {
+        let result = csv_reader.read_byte_record(&mut row).and_then(|more| {
+            if more {
+                options.normalize_amount(&mut row, amount_index);
+                row.deserialize::<Record>(Some(&headers)).map(Some)
+            } else {
+                Ok(None)
+            }
+        });
+        let record = match result {
+            Ok(Some(record)) => record,
+            Ok(None) => break,
+            Err(e) if e.is_io_error() => return Err(e).context("Unable to read csv"),
+            Err(e) => {
+                report.rows += 1;
+                report.errors.push(ValidationError {
+                    line: e.position().map_or(0, |position| position.line()),
+                    kind: ErrorKind::Parse,
+                    message: e.to_string(),
+                });
+                continue;
+            }
+        };
+        report.rows += 1;
+        if !options.includes(&record) {
+            continue;
+        }
+        if let Err(e) = check_record(&record, options.scale, &mut transactions) {
+            let kind = match e.kind() {
+                Some(kind) => kind,
+                None => return Err(e.into()),
+            };
+            report.errors.push(ValidationError {
+                line: row.position().map_or(0, |position| position.line()),
+                kind,
+                message: e.to_string(),
+            });
+        }
+    }
+    Ok(report)
+}
+
+/// Checks a single `record` on its own, e.g. before it is applied to an `Engine`, without touching
+/// any account. It is invalid if its amount has more than four places past the decimal, if it is a
+/// deposit, withdrawal or transfer with an amount that is negative or zero, which is what a missing
+/// amount is parsed as, if it is a transfer without a target client, if it is a dispute with a
+/// negative amount, or if it is a resolve or chargeback with any amount. A dispute may have a
+/// positive amount, to dispute only part of a transaction.
+///
+/// ```
+/// use moneybags::{validate_record, ErrorKind, Record, RecordType};
+/// use rust_decimal::Decimal;
+///
+/// assert!(validate_record(&Record::new(RecordType::Deposit, 1, 1, Decimal::new(25, 1))).is_ok());
+/// let err = validate_record(&Record::new(RecordType::Deposit, 1, 1, Decimal::new(-25, 1)));
+/// assert_eq!(err.unwrap_err().kind(), Some(ErrorKind::NegativeAmount));
+/// ```
+pub fn validate_record(record: &Record) -> Result<(), ProcessError> {
+    check_fields(record, DECIMAL_PLACES)?;
+    match record.record_type {
+        RecordType::Deposit | RecordType::Withdrawal | RecordType::Transfer => {
+            if record.amount.is_zero() {
+                return Err(ProcessError::ZeroAmount {
+                    record_type: record.record_type,
+                    tx: record.tx,
+                });
+            }
+        }
+        RecordType::Dispute => {
+            if record.amount < Decimal::ZERO {
+                return Err(ProcessError::NegativeAmount {
+                    record_type: record.record_type,
+                    tx: record.referenced_tx(),
+                    amount: record.amount,
+                });
+            }
+        }
+        RecordType::Resolve | RecordType::Chargeback => {
+            if !record.amount.is_zero() {
+                return Err(ProcessError::UnexpectedAmount {
+                    record_type: record.record_type,
+                    tx: record.referenced_tx(),
+                    amount: record.amount,
+                });
+            }
+        }
+        RecordType::Unlock => {}
+    }
+    Ok(())
+}
+
+/// Checks that the fields of `record` are valid in themselves, shared by `validate_record` and
+/// `check_record`: its amount has at most `scale` places past the decimal, the amount of a
+/// deposit, withdrawal or transfer is not negative, and a transfer has a target client.
+fn check_fields(record: &Record, scale: u32) -> Result<(), ProcessError> {
+    if exceeds_scale(record.amount, scale) {
+        return Err(ProcessError::ExcessPrecision {
+            record_type: record.record_type,
+            tx: record.tx,
+            amount: record.amount,
+            scale,
+        });
+    }
+    if matches!(
+        record.record_type,
+        RecordType::Deposit | RecordType::Withdrawal | RecordType::Transfer
+    ) {
+        if record.record_type == RecordType::Transfer && record.target_client.is_none() {
+            return Err(ProcessError::MissingTargetClient { tx: record.tx });
+        }
+        if record.amount < Decimal::ZERO {
+            return Err(ProcessError::NegativeAmount {
+                record_type: record.record_type,
+                tx: record.tx,
+                amount: record.amount,
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Checks the parts of `record` that do not depend on any balances, where `scale` is the currency
+/// scale and `transactions` holds every valid deposit and withdrawal seen so far, keyed by tx.
+fn check_record(
+    record: &Record,
+    scale: u32,
+    transactions: &mut BTreeMap<u32, Record>,
+) -> Result<(), ProcessError> {
+    check_fields(record, scale)?;
+    match record.record_type {
+        RecordType::Deposit | RecordType::Withdrawal | RecordType::Transfer => {
+            if transactions.contains_key(&record.tx) {
+                return Err(ProcessError::DuplicateTx {
+                    record_type: record.record_type,
+                    tx: record.tx,
+                });
+            }
+            // Transfers can not be disputed, so they are not kept.
+            if record.record_type != RecordType::Transfer {
+                transactions.insert(record.tx, *record);
+            }
+        }
+        RecordType::Dispute | RecordType::Resolve | RecordType::Chargeback => {
+            let tx = record.referenced_tx();
+            let referenced = transactions.get(&tx).ok_or(ProcessError::UnknownTx {
+                record_type: record.record_type,
+                tx,
+            })?;
+            if referenced.client != record.client {
+                return Err(ProcessError::ClientMismatch {
+                    record_type: record.record_type,
+                    tx,
+                    expected: referenced.client,
+                    found: record.client,
+                });
+            }
+            if record.record_type == RecordType::Dispute {
+                dispute_amount(record, referenced)?;
+            }
+        }
+        // Whether unlocking is allowed does not affect the validity of the input.
+        RecordType::Unlock => {}
+    }
+    Ok(())
+}
+
+/// A row that could not be parsed, and where it was found in the input if that is known.
+struct ParseFailure {
+    error: anyhow::Error,
+    position: Option<Position>,
+}
+
+impl ParseFailure {
+    fn new(
+        error: impl std::error::Error + Send + Sync + 'static,
+        position: Option<Position>,
+    ) -> Self {
+        Self {
+            error: anyhow::Error::new(error),
+            position,
+        }
+    }
+}
+
+impl From<csv::Error> for ParseFailure {
+    fn from(e: csv::Error) -> Self {
+        let position = e.position().cloned();
+        Self::new(e, position)
+    }
+}
+
+/// Handles a row that could not be parsed, which is an error if `options.strict` or
+/// `options.fail_fast_on_parse` is `true`, otherwise it is counted in the `report` and skipped.
+fn parse_error(
+    report: &mut ProcessingReport,
+    failure: ParseFailure,
+    options: &ProcessingOptions,
+) -> Result<()> {
+    let row_error = RowError::new(ErrorKind::Parse, failure.position.as_ref());
+    if options.strict || options.fail_fast_on_parse {
+        return Err(failure.error.context(row_error));
+    }
+    warn!("{}: {}", row_error, failure.error);
+    report.skip(ErrorKind::Parse);
+    Ok(())
+}
+
+/// Identifies a row that could not be parsed or processed, and where it was found in the input. In
+/// strict mode this is the context of the returned error, so it can be retrieved with
+/// `downcast_ref::<RowError>()`.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct RowError {
+    /// Why the row could not be parsed or processed.
+    pub kind: ErrorKind,
+    /// The 1-based line on which the row starts, unless the record was not read from CSV input.
+    pub line: Option<u64>,
+    /// The byte offset at which the row starts, unless the record was not read from CSV input.
+    pub byte: Option<u64>,
+}
+
+impl RowError {
+    fn new(kind: ErrorKind, position: Option<&Position>) -> Self {
+        Self {
+            kind,
+            line: position.map(Position::line),
+            byte: position.map(Position::byte),
+        }
+    }
+}
+
+/// Produces a message like `Error parsing csv line 3 (byte 40)` or `Error processing record on line
+/// 3 (byte 40)`.
+impl Display for RowError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        if self.kind == ErrorKind::Parse {
+            f.write_str("Error parsing csv line")?;
+        } else {
+            f.write_str("Error processing record")?;
+            if self.line.is_some() {
+                f.write_str(" on line")?;
+            }
+        }
+        if let Some(line) = self.line {
+            write!(f, " {}", line)?;
+        }
+        if let Some(byte) = self.byte {
+            write!(f, " (byte {})", byte)?;
+        }
+        Ok(())
+    }
+}
+
+/// A row of the audit log.
+#[derive(Debug, Clone, Serialize)]
+struct AuditEntry {
+    tx: u32,
+    client: u32,
+    #[serde(rename = "type")]
+    record_type: RecordType,
+    /// The amount of a deposit, withdrawal or transfer. Other records do not have an amount.
+    amount: Option<Decimal>,
+    /// The balances are formatted to the currency scale, like the output.
+    available: String,
+    held: String,
+    total: String,
+    /// Either `applied` or, for example, `skipped:insufficient_funds`.
+    status: String,
+}
+
+/// A row of the errors file, for a row that was skipped or that stopped processing.
+#[derive(Debug, Clone, Serialize)]
+struct ErrorEntry {
+    /// The line of the input on which the row starts, if it was read from CSV input.
+    line: Option<u64>,
+    /// The tx and client of the record, unless the row could not be parsed.
+    tx: Option<u32>,
+    client: Option<u32>,
+    kind: ErrorKind,
+    message: String,
+}
+
+/// A row of the hold ledger, for a movement of held funds.
+#[derive(Debug, Clone, Serialize)]
+struct HoldEntry {
+    /// The tx of the disputed transaction, rather than that of the dispute, resolve or chargeback.
+    tx: u32,
+    client: u32,
+    action: HoldAction,
+    /// The amounts are formatted to the currency scale, like the output.
+    amount: String,
+    held_after: String,
+}
+
+/// A row of the history, for the balances of an account after a transaction was applied to it.
+#[derive(Debug, Clone, Serialize)]
+struct HistoryEntry {
+    /// The number of transactions applied so far, counting this one. A transfer between two
+    /// accounts has a row for each, with the same index.
+    sequence_index: u64,
+    client: u32,
+    /// The balances are formatted to the currency scale, like the output.
+    available: String,
+    held: String,
+    total: String,
+}
+
+/// How held funds were moved.
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum HoldAction {
+    /// A dispute held funds.
+    Hold,
+    /// A resolve released held funds.
+    Release,
+    /// A chargeback removed held funds.
+    Chargeback,
+}
+
+/// The state of a set of client accounts, to which transaction records can be applied one at a time,
+/// e.g. by a long-running service, and which can be read at any point. Records are processed as by
+/// `process`, except that they are not filtered by `since_tx` and `until_tx`, and that nothing is
+/// logged or counted. The deposits and withdrawals that may be disputed are kept in a
+/// `TransactionStore`, by default in memory.
+///
+/// ```
+/// use moneybags::{Engine, ProcessingOptions, Record, RecordType};
+/// use rust_decimal::Decimal;
+///
+/// let mut engine = Engine::new(ProcessingOptions::default());
+/// engine
+///     .apply(&Record::new(RecordType::Deposit, 1, 1, Decimal::new(25, 1)))
+///     .unwrap();
+/// assert_eq!(engine.snapshot()[0].available(), Decimal::new(25, 1));
+/// ```
+#[derive(Debug)]
+pub struct Engine<S = BTreeMap<u32, Record>> {
+    options: ProcessingOptions,
+    /// The deposits and withdrawals that may be disputed.
+    records: S,
+    /// The dispute state of every transaction that has been disputed.
+    disputes: BTreeMap<u32, DisputeState>,
+    clients: BTreeMap<u32, Client>,
+    /// The number of withdrawals of each client that have failed for insufficient funds since its
+    /// account was last locked by them, if accounts are locked after failed withdrawals.
+    failed_withdrawals: BTreeMap<u32, usize>,
+    /// The latest timestamp of the records that have been processed, if their order is enforced.
+    latest_timestamp: Option<DateTime<Utc>>,
+    /// The most recent records, if replays of them are skipped.
+    recent: RecentRecords,
+    observer: Option<Observer>,
+}
+
+/// The type, client, tx and amount of a record, which identify a replay of it.
+type RecordKey = (RecordType, u32, u32, Decimal);
+
+/// The keys of the most recent records, oldest first, along with how many times each appears.
+#[derive(Debug, Default)]
+struct RecentRecords {
+    keys: VecDeque<RecordKey>,
+    counts: BTreeMap<RecordKey, usize>,
+}
+
+impl RecentRecords {
+    /// Adds the `record`, forgetting the oldest if there are more than `window`, and returns
+    /// whether it was already one of them.
+    fn push(&mut self, record: &Record, window: NonZeroUsize) -> bool {
+        let key = (record.record_type, record.client, record.tx, record.amount);
+        let count = self.counts.entry(key).or_default();
+        let replayed = *count > 0;
+        *count += 1;
+        self.keys.push_back(key);
+        if self.keys.len() > window.get() {
+            if let Some(oldest) = self.keys.pop_front() {
+                if let Some(count) = self.counts.get_mut(&oldest) {
+                    *count -= 1;
+                    if *count == 0 {
+                        self.counts.remove(&oldest);
+                    }
+                }
+            }
+        }
+        replayed
+    }
+}
+
+/// A function that an `Engine` calls with each record that it processes, see
+/// `Engine::with_observer`.
+type ObserverFn = dyn FnMut(&Record, Result<&Client, &ProcessError>) + Send;
 
-/// Processes the transactions found in <CSV_FILE> and outputs a CSV to stdout summarizing the
-/// end state of the accounts found therein.
-#[derive(Parser, Clone, Debug, Eq, PartialEq, Ord, PartialOrd)]
-#[clap(name = "moneybags")]
-#[clap(bin_name = "moneybags")]
-pub struct Moneybags {
-    /// The path to a CSV file containing transaction records.
-    pub csv_file: PathBuf,
+/// Wraps an `ObserverFn` so that an `Engine` can still be debugged.
+struct Observer(Box<ObserverFn>);
+
+impl std::fmt::Debug for Observer {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Observer")
+    }
 }
 
-impl Moneybags {
-    /// Writes a csv-formatted summary of the accounts found in `self.csv_file`. By taking a `Write`
-    /// instead of writing to `stdout`, we make the program easier to test.
-    pub fn run(&self, writer: impl Write) -> Result<()> {
-        let f = BufReader::new(
-            File::open(&self.csv_file)
-                .context(format!("Unable to open file '{}'", self.csv_file.display()))?,
+impl Engine {
+    /// Creates an engine without any client accounts, which keeps transactions in memory.
+    pub fn new(options: ProcessingOptions) -> Self {
+        Self::with_store(options, BTreeMap::new())
+    }
+}
+
+impl<S: TransactionStore> Engine<S> {
+    /// Creates an engine without any client accounts, which keeps transactions in `records`.
+    pub fn with_store(options: ProcessingOptions, records: S) -> Self {
+        Self {
+            options,
+            records,
+            disputes: BTreeMap::new(),
+            clients: BTreeMap::new(),
+            failed_withdrawals: BTreeMap::new(),
+            latest_timestamp: None,
+            recent: RecentRecords::default(),
+            observer: None,
+        }
+    }
+
+    /// Calls `observer` after each record is processed, with the record as it was applied, e.g.
+    /// with the amount of a drained withdrawal, and either the resulting state of the client's
+    /// account or the error that stopped the record from being applied. This can be used to stream
+    /// changes to balances as they happen.
+    pub fn with_observer(
+        mut self,
+        observer: impl FnMut(&Record, Result<&Client, &ProcessError>) + Send + 'static,
+    ) -> Self {
+        self.observer = Some(Observer(Box::new(observer)));
+        self
+    }
+
+    /// Applies the `record` to the client accounts. If it cannot be applied, the balances of the
+    /// accounts are left as they were, and the error says why, but as for any record, an account
+    /// with no funds is created for its client if there was none.
+    pub fn apply(&mut self, record: &Record) -> Result<(), ProcessError> {
+        let mut record = *record;
+        self.process(&mut record)
+    }
+
+    /// The state of every client account, ordered by client ID.
+    pub fn snapshot(&self) -> Vec<Client> {
+        self.clients.values().copied().collect()
+    }
+
+    /// Applies the `record`, which is changed to reflect what was actually applied, e.g. the amount
+    /// of a drained withdrawal.
+    fn process(&mut self, record: &mut Record) -> Result<(), ProcessError> {
+        let result = self.commit(record);
+        if let Some(Observer(observer)) = &mut self.observer {
+            match &result {
+                Ok(()) => observer(record, Ok(&self.clients[&record.client])),
+                Err(e) => observer(record, Err(e)),
+            }
+        }
+        result
+    }
+
+    /// Implements `process`, storing the `record` once it has been applied if it may later be
+    /// disputed.
+    fn commit(&mut self, record: &mut Record) -> Result<(), ProcessError> {
+        self.check_replay(record)?;
+        self.check_order(record)?;
+        let result = process_record(
+            record,
+            &self.options,
+            &self.records,
+            &mut self.disputes,
+            &mut self.clients,
         );
-        let clients = process_records(f)?;
-        let mut csv_writer = WriterBuilder::new().has_headers(true).from_writer(writer);
-        for client in clients {
-            csv_writer.serialize(client)?;
+        if let (RecordType::Withdrawal, Err(ProcessError::InsufficientFunds { client, .. })) =
+            (record.record_type, &result)
+        {
+            self.fail_withdrawal(*client);
+        }
+        result?;
+        // We need to store transactions because they may become disputed later. We do not need to
+        // store dispute, resolve or chargeback records because these can not be further referenced,
+        // nor transactions that were skipped, which can not be disputed.
+        if matches!(
+            record.record_type,
+            RecordType::Deposit | RecordType::Withdrawal
+        ) {
+            self.records.insert(*record)?;
+        }
+        Ok(())
+    }
+
+    /// Checks that the `record` is not identical to one of the records before it, if
+    /// `options.dedupe_window` is given. Replays are remembered too, so a record that keeps being
+    /// replayed is skipped until it has not been seen for the whole window.
+    fn check_replay(&mut self, record: &Record) -> Result<(), ProcessError> {
+        let window = match self.options.dedupe_window {
+            Some(window) => window,
+            None => return Ok(()),
+        };
+        if self.recent.push(record, window) {
+            return Err(ProcessError::Replayed {
+                record_type: record.record_type,
+                tx: record.tx,
+                window: window.get(),
+            });
+        }
+        Ok(())
+    }
+
+    /// Checks that the `record` is not before the latest record, if `options.enforce_order` is
+    /// `true`. A record that is in order becomes the latest even if it then fails to be applied,
+    /// because it still happened when it says it did.
+    fn check_order(&mut self, record: &Record) -> Result<(), ProcessError> {
+        let timestamp = match record.timestamp {
+            Some(timestamp) if self.options.enforce_order => timestamp,
+            _ => return Ok(()),
+        };
+        if let Some(latest) = self.latest_timestamp {
+            if timestamp < latest {
+                return Err(ProcessError::OutOfOrder {
+                    tx: record.tx,
+                    timestamp,
+                    latest,
+                });
+            }
         }
+        self.latest_timestamp = Some(timestamp);
         Ok(())
     }
+
+    /// Counts a withdrawal by the client with the given `id` that failed for insufficient funds,
+    /// and locks its account if that is as many as `options.freeze_after_failed_withdrawals`.
+    fn fail_withdrawal(&mut self, id: u32) {
+        let limit = match self.options.freeze_after_failed_withdrawals {
+            Some(limit) => limit,
+            None => return,
+        };
+        let failures = self.failed_withdrawals.entry(id).or_default();
+        *failures += 1;
+        if *failures < limit.get() {
+            return;
+        }
+        // The count starts again, in case the account is unlocked.
+        self.failed_withdrawals.remove(&id);
+        self.clients
+            .entry(id)
+            .or_insert_with(|| Client::new(id))
+            .locked = true;
+        warn!("Client {} is locked after {} failed withdrawals", id, limit);
+    }
 }
 
-/// Represents the type of record found in input CSV data.
-#[derive(Debug, Clone, Copy, Eq, Ord, PartialEq, PartialOrd, Hash, Serialize, Deserialize)]
-#[serde(rename_all = "snake_case")]
-pub enum RecordType {
-    /// A deposit is a credit to the client’s asset account, meaning it should increase the
-    /// available and total funds of the client account.
-    Deposit,
+/// The state accumulated while processing records, i.e. the `Engine` along with what is reported
+/// about the records applied to it.
+struct Ledger<'a, S> {
+    engine: Engine<S>,
+    report: ProcessingReport,
+    audit: Option<csv::Writer<&'a mut dyn Write>>,
+    /// The output, if the state of each account is written every time a record is applied to it.
+    stream: Option<csv::Writer<&'a mut dyn Write>>,
+    /// Where the rows that are skipped, or that stop processing, are written, if anywhere.
+    errors: Option<csv::Writer<&'a mut dyn Write>>,
+    /// Where every movement of held funds is written, if anywhere.
+    holds: Option<csv::Writer<&'a mut dyn Write>>,
+    /// Where the balances of each account are written after every transaction applied to it, if
+    /// anywhere, along with the number of transactions applied so far.
+    history: Option<(csv::Writer<&'a mut dyn Write>, u64)>,
+    /// Where a checkpoint is written, and every how many rows, if anywhere.
+    checkpoint: Option<(&'a Path, NonZeroUsize)>,
+    /// The byte offset of the row at the checkpoint that processing resumed from, if it did.
+    resumed_at: Option<u64>,
+    /// Whether transfers can be processed, which requires that the ledger has every client.
+    transfers: bool,
+    /// The line of every deposit and withdrawal in the input, keyed by tx, if ordering is diagnosed.
+    transaction_lines: Option<BTreeMap<u32, u64>>,
+    /// The records applied to each client account, keyed by client ID.
+    activity: BTreeMap<u32, Activity>,
+    /// The sum of the deposits applied to each client account, keyed by client ID, if anomalies
+    /// are flagged.
+    deposits: BTreeMap<u32, Decimal>,
+}
 
-    /// A withdraw is a debit to the client’s asset account, meaning it should decrease the
-    /// available and total funds of the client account.
-    Withdrawal,
+impl<'a, S: TransactionStore> Ledger<'a, S> {
+    fn new(engine: Engine<S>) -> Self {
+        Self {
+            engine,
+            report: ProcessingReport::default(),
+            audit: None,
+            stream: None,
+            errors: None,
+            holds: None,
+            history: None,
+            checkpoint: None,
+            resumed_at: None,
+            transfers: true,
+            transaction_lines: None,
+            activity: BTreeMap::new(),
+            deposits: BTreeMap::new(),
+        }
+    }
 
-    /// A dispute represents a client’s claim that a transaction was erroneous and should be
-    /// reversed. The transaction shouldn’t be reversed yet but the associated funds should be held.
-    /// This means that the clients available funds should decrease by the amount disputed, their
-    /// held funds should increase by the amount disputed, while their total funds should remain the
-    /// same.
-    Dispute,
+    /// Starts from the accounts in `clients`, and writes an audit log to `audit` if given.
+    fn with(mut self, clients: BTreeMap<u32, Client>, audit: Option<&'a mut dyn Write>) -> Self {
+        self.engine.clients = clients;
+        self.audit = audit.map(csv::Writer::from_writer);
+        self
+    }
 
-    /// A resolve represents a resolution to a dispute, releasing the associated held funds. Funds
-    /// that were previously disputed are no longer disputed. This means that the clients held funds
-    /// should decrease by the amount no longer disputed, their available funds should increase by
-    /// the amount no longer disputed, and their total funds should remain the same.
-    Resolve,
+    /// Writes the state of each account to `stream`, if given, every time a record is applied to it.
+    /// Dispute, resolve and chargeback records are then an error, even if processing is not strict.
+    fn streaming(mut self, stream: Option<csv::Writer<&'a mut dyn Write>>) -> Self {
+        self.stream = stream;
+        self
+    }
 
-    /// A chargeback is the final state of a dispute and represents the client reversing a
-    /// transaction. Funds that were held have now been withdrawn. This means that the clients held
-    /// funds and total funds should decrease by the amount previously disputed. If a chargeback
-    /// occurs the client’s account should be immediately frozen.
-    Chargeback,
-}
+    /// Writes every row that is skipped, or that stops processing, to `errors`, if given.
+    fn logging_errors(mut self, errors: Option<&'a mut dyn Write>) -> Self {
+        self.errors = errors.map(csv::Writer::from_writer);
+        self
+    }
 
-impl Default for RecordType {
-    fn default() -> Self {
-        Self::Deposit
+    /// Writes every movement of held funds to `holds`, if given.
+    fn logging_holds(mut self, holds: Option<&'a mut dyn Write>) -> Self {
+        self.holds = holds.map(csv::Writer::from_writer);
+        self
     }
-}
 
-derive_fromstr_from_deserialize!(RecordType);
-derive_display_from_serialize!(RecordType);
+    /// Writes the balances of each account after every transaction applied to it to `history`, if
+    /// given.
+    fn logging_history(mut self, history: Option<&'a mut dyn Write>) -> Self {
+        self.history = history.map(|history| (csv::Writer::from_writer(history), 0));
+        self
+    }
 
-/// Represents an input row in CSV transaction data.
-#[derive(Debug, Default, Clone, Eq, Ord, PartialEq, PartialOrd, Hash, Serialize, Deserialize)]
-#[serde(rename_all = "snake_case")]
-pub struct Record {
-    #[serde(rename = "type")]
-    /// The type of record.
-    record_type: RecordType,
+    /// Writes a checkpoint to the given path every given number of rows, if given.
+    fn checkpointing(mut self, checkpoint: Option<(&'a Path, NonZeroUsize)>) -> Self {
+        self.checkpoint = checkpoint;
+        self
+    }
 
-    /// The client ID will be unique per client though are not guaranteed to be ordered.
-    client: u32,
+    /// Restores the state saved in `checkpoint`, if given, so that processing resumes from it.
+    fn resuming(mut self, checkpoint: Option<Checkpoint>) -> Result<Self> {
+        if let Some(checkpoint) = checkpoint {
+            self.resumed_at = Some(checkpoint.restore(&mut self)?);
+        }
+        Ok(self)
+    }
 
-    /// Transaction IDs (tx) are globally unique and not guaranteed to be ordered.
-    tx: u32,
+    /// Writes a checkpoint before the row at `position`, if there is a checkpoint and one is due.
+    fn write_checkpoint(&self, position: Option<&Position>) -> Result<()> {
+        let (path, interval) = match self.checkpoint {
+            Some(checkpoint) => checkpoint,
+            None => return Ok(()),
+        };
+        let rows = self.report.rows;
+        if rows == 0 || !rows.is_multiple_of(interval.get()) {
+            return Ok(());
+        }
+        if let Some(position) = position {
+            Checkpoint::new(self, position)?.write(path)?;
+        }
+        Ok(())
+    }
 
-    /// The amount of the transaction, in fixed-precision decimal type. This type will not
-    /// accumulate errors like a floating point type would.
-    #[serde(default)]
-    #[serde(deserialize_with = "parse_decimal")]
-    amount: Decimal,
-}
+    /// Processes the `record`, found at `position` in the input. If it cannot be processed, this is
+    /// an error if `options.strict` is `true`, otherwise the record is skipped. Records outside of
+    /// the tx range given by `options`, and records for locked accounts when the locked policy is
+    /// `Ignore`, are ignored, which is counted apart from the records that are skipped.
+    fn apply(
+        &mut self,
+        mut record: Record,
+        position: Option<&Position>,
+        options: &ProcessingOptions,
+    ) -> Result<()> {
+        if !options.includes(&record) {
+            self.report.ignored += 1;
+            return Ok(());
+        }
+        if options.alert_on_locked_activity {
+            self.alert_locked_activity(&record);
+        }
+        // A dispute could change an account that has already been streamed, which can not be
+        // undone, so it can not just be skipped.
+        if self.stream.is_some()
+            && matches!(
+                record.record_type,
+                RecordType::Dispute | RecordType::Resolve | RecordType::Chargeback
+            )
+        {
+            let e = ProcessError::UnexpectedDispute {
+                record_type: record.record_type,
+                tx: record.referenced_tx(),
+            };
+            let row_error = RowError::new(ErrorKind::UnexpectedDispute, position);
+            self.write_error(Some(&record), row_error, &e)?;
+            return Err(anyhow::Error::new(e).context(row_error));
+        }
+        // A resolve or chargeback settles the whole dispute, so an amount suggests that whatever
+        // produced the input has misunderstood it. The amount is ignored unless processing is
+        // strict.
+        if matches!(
+            record.record_type,
+            RecordType::Resolve | RecordType::Chargeback
+        ) && !record.amount.is_zero()
+        {
+            let e = ProcessError::UnexpectedAmount {
+                record_type: record.record_type,
+                tx: record.referenced_tx(),
+                amount: record.amount,
+            };
+            if options.strict {
+                let row_error = RowError::new(ErrorKind::UnexpectedAmount, position);
+                self.write_error(Some(&record), row_error, &e)?;
+                return Err(anyhow::Error::new(e).context(row_error));
+            }
+            match position {
+                Some(position) => warn!(
+                    "Warning for record on line {} (byte {}): {}",
+                    position.line(),
+                    position.byte(),
+                    e
+                ),
+                None => warn!("Warning for record: {}", e),
+            }
+        }
+        // The amount of a hold, release or chargeback is how much it changed the held funds.
+        let held_before = self
+            .engine
+            .clients
+            .get(&record.client)
+            .map(|client| client.held);
+        let result = if record.record_type == RecordType::Transfer && !self.transfers {
+            Err(ProcessError::UnsupportedTransfer { tx: record.tx })
+        } else {
+            self.engine.process(&mut record)
+        };
+        if let Err(e) = result {
+            let e = self.diagnose(e, position);
+            let kind = match e.kind() {
+                Some(kind) => kind,
+                None => return Err(e.into()),
+            };
+            self.write_audit(&record, Some(kind), options)?;
+            if kind == ErrorKind::Locked && options.locked_policy == LockedPolicy::Ignore {
+                self.report.ignored += 1;
+                return Ok(());
+            }
+            let row_error = RowError::new(kind, position);
+            self.write_error(Some(&record), row_error, &e)?;
+            if options.strict {
+                return Err(anyhow::Error::new(e).context(row_error));
+            }
+            warn!("{}: {}", row_error, e);
+            self.report.skip(kind);
+            return Ok(());
+        }
+        self.write_audit(&record, None, options)?;
+        self.write_stream(&record, options)?;
+        self.write_hold(&record, held_before.unwrap_or_default(), options)?;
+        self.write_history(&record, options)?;
+        if options.check_invariants {
+            self.check_balances(&record)?;
+        }
+        if options.flag_anomalies {
+            self.flag_anomaly(&record);
+        }
+        self.activity
+            .entry(record.client)
+            .or_default()
+            .record(&record);
+        if let (RecordType::Transfer, Some(target_client)) =
+            (record.record_type, record.target_client)
+        {
+            self.activity
+                .entry(target_client)
+                .or_default()
+                .record(&record);
+        }
+        Ok(())
+    }
 
-/// A custom deserializer for the fixed decimal type.
-fn parse_decimal<'de, D>(d: D) -> Result<Decimal, D::Error>
-where
-    D: Deserializer<'de>,
-{
-    let value: String = match Option::deserialize(d)? {
-        Some(value) => value,
-        // We do not need to distinguish between nulls and zeros.
-        None => return Ok(Default::default()),
-    };
-    let parsed =
-        Decimal::from_str(&value).map_err(|e| serde::de::Error::custom(format!("{}", e)))?;
-    Ok(parsed)
-}
+    /// Adds the applied `record` to the client's deposits if it is a deposit, or logs a warning if
+    /// it is a dispute of more than the client has deposited.
+    fn flag_anomaly(&mut self, record: &Record) {
+        let deposits = self.deposits.entry(record.client).or_default();
+        match record.record_type {
+            RecordType::Deposit => *deposits = deposits.saturating_add(record.amount),
+            RecordType::Dispute => {
+                let tx = record.referenced_tx();
+                if let Some(DisputeState::Disputed { amount }) = self.engine.disputes.get(&tx) {
+                    if amount > deposits {
+                        warn!(
+                            "Anomaly: dispute of tx {} for {} is more than the {} that client {} has \
+                             deposited",
+                            tx, amount, deposits, record.client
+                        );
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
 
-/// Represents the status of a client/account.
-#[derive(
-    Debug, Default, Clone, Copy, Eq, Ord, PartialEq, PartialOrd, Hash, Serialize, Deserialize,
-)]
-#[serde(rename_all = "snake_case")]
-pub struct Client {
-    #[serde(rename = "client")]
-    id: u32,
-    available: Decimal,
-    held: Decimal,
-    total: Decimal,
-    locked: bool,
-}
+    /// Logs an alert, and counts the `record`, if it is for a client whose account is locked, or is a
+    /// transfer to one, before it is applied.
+    fn alert_locked_activity(&mut self, record: &Record) {
+        let target = match record.record_type {
+            RecordType::Transfer => record.target_client.filter(|&id| id != record.client),
+            _ => None,
+        };
+        let mut alerted = false;
+        for id in std::iter::once(record.client).chain(target) {
+            if self
+                .engine
+                .clients
+                .get(&id)
+                .is_some_and(|client| client.locked)
+            {
+                warn!(
+                    "Alert: {} tx {} is for client {}, whose account is locked",
+                    record.record_type, record.tx, id
+                );
+                alerted = true;
+            }
+        }
+        if alerted {
+            self.report.locked_activity += 1;
+        }
+    }
 
-impl Client {
-    pub fn new(id: u32) -> Self {
-        Self {
-            id,
-            ..Default::default()
+    /// Explains why the transaction referenced by a record at `position` could not be found, if
+    /// ordering is diagnosed. Other errors are returned as they are.
+    fn diagnose(&self, e: ProcessError, position: Option<&Position>) -> ProcessError {
+        let (lines, record_type, tx) = match (&self.transaction_lines, e) {
+            (Some(lines), ProcessError::UnknownTx { record_type, tx }) => (lines, record_type, tx),
+            (_, e) => return e,
+        };
+        match lines.get(&tx) {
+            Some(&line) if position.is_none_or(|position| line > position.line()) => {
+                ProcessError::LaterTx {
+                    record_type,
+                    tx,
+                    line,
+                }
+            }
+            Some(&line) => ProcessError::SkippedTx {
+                record_type,
+                tx,
+                line,
+            },
+            None => ProcessError::MissingTx { record_type, tx },
         }
     }
-}
 
-fn process_records(reader: impl Read) -> Result<Vec<Client>> {
-    let mut csv_reader = csv::Reader::from_reader(reader);
-    let mut records = BTreeMap::new();
-    let mut clients = BTreeMap::new();
+    /// Writes a row to the audit log, if there is one, for a `record` that was either applied or
+    /// skipped for the reason given by `skipped`, with balances formatted as `options` specify.
+    fn write_audit(
+        &mut self,
+        record: &Record,
+        skipped: Option<ErrorKind>,
+        options: &ProcessingOptions,
+    ) -> Result<()> {
+        let audit = match &mut self.audit {
+            Some(audit) => audit,
+            None => return Ok(()),
+        };
+        let client = self
+            .engine
+            .clients
+            .get(&record.client)
+            .copied()
+            .unwrap_or_else(|| Client::new(record.client));
+        let status = match skipped {
+            Some(kind) => format!("skipped:{}", serde_plain::to_string(&kind)?),
+            None => "applied".to_string(),
+        };
+        audit
+            .serialize(AuditEntry {
+                tx: record.tx,
+                client: record.client,
+                record_type: record.record_type,
+                amount: matches!(
+                    record.record_type,
+                    RecordType::Deposit | RecordType::Withdrawal | RecordType::Transfer
+                )
+                .then(|| record.amount),
+                available: format_decimal(client.available, options.scale, options.rounding),
+                held: format_decimal(client.held, options.scale, options.rounding),
+                total: format_decimal(client.total, options.scale, options.rounding),
+                status,
+            })
+            .context("Unable to write audit log")
+    }
 
-    for result in csv_reader.deserialize() {
-        let record: Record = match result {
-            Ok(ok) => ok,
-            Err(e) => {
-                eprintln!("Error parsing csv line: {}", e);
-                continue;
+    /// Processes the next row of the input, which is either a record along with its position in the
+    /// input, or the error that occurred while parsing it.
+    fn process_row(
+        &mut self,
+        result: Result<(Record, Option<Position>), ParseFailure>,
+        options: &ProcessingOptions,
+    ) -> Result<()> {
+        self.write_checkpoint(result_position(&result))?;
+        self.report.rows += 1;
+        match result {
+            Ok((record, position)) => self.apply(record, position.as_ref(), options)?,
+            Err(failure) => self.parse_error(failure, options)?,
+        }
+        options.report_progress(self.report.rows, Some(self.engine.clients.len()));
+        Ok(())
+    }
+
+    /// Skips a row that could not be parsed, as `parse_error` does, after writing it to the errors
+    /// file, if there is one.
+    fn parse_error(&mut self, failure: ParseFailure, options: &ProcessingOptions) -> Result<()> {
+        let row_error = RowError::new(ErrorKind::Parse, failure.position.as_ref());
+        self.write_error(None, row_error, &failure.error)?;
+        parse_error(&mut self.report, failure, options)
+    }
+
+    /// Writes a row to the hold ledger, if there is one, for the `record` if it is a dispute,
+    /// resolve or chargeback that has been applied, where `held_before` is the client's held funds
+    /// before it was applied.
+    fn write_hold(
+        &mut self,
+        record: &Record,
+        held_before: Decimal,
+        options: &ProcessingOptions,
+    ) -> Result<()> {
+        let holds = match &mut self.holds {
+            Some(holds) => holds,
+            None => return Ok(()),
+        };
+        let action = match record.record_type {
+            RecordType::Dispute => HoldAction::Hold,
+            RecordType::Resolve => HoldAction::Release,
+            RecordType::Chargeback => HoldAction::Chargeback,
+            _ => return Ok(()),
+        };
+        let held_after = self.engine.clients[&record.client].held;
+        holds
+            .serialize(HoldEntry {
+                tx: record.referenced_tx(),
+                client: record.client,
+                action,
+                amount: format_decimal(
+                    (held_after - held_before).abs(),
+                    options.scale,
+                    options.rounding,
+                ),
+                held_after: format_decimal(held_after, options.scale, options.rounding),
+            })
+            .context("Unable to write hold ledger")
+    }
+
+    /// Writes a row to the history, if there is one, for each account that the applied `record`
+    /// changed, with balances formatted as `options` specify.
+    fn write_history(&mut self, record: &Record, options: &ProcessingOptions) -> Result<()> {
+        let (history, sequence_index) = match &mut self.history {
+            Some(history) => history,
+            None => return Ok(()),
+        };
+        *sequence_index += 1;
+        let target = match record.record_type {
+            RecordType::Transfer => record.target_client.filter(|&id| id != record.client),
+            _ => None,
+        };
+        for id in std::iter::once(record.client).chain(target) {
+            if let Some(client) = self.engine.clients.get(&id) {
+                history
+                    .serialize(HistoryEntry {
+                        sequence_index: *sequence_index,
+                        client: id,
+                        available: format_decimal(
+                            client.available,
+                            options.scale,
+                            options.rounding,
+                        ),
+                        held: format_decimal(client.held, options.scale, options.rounding),
+                        total: format_decimal(client.total, options.scale, options.rounding),
+                    })
+                    .context("Unable to write history")?;
             }
+        }
+        Ok(())
+    }
+
+    /// Writes a row to the errors file, if there is one, for the row identified by `row_error`,
+    /// which is the `record` unless it could not be parsed, with the `error` as its message.
+    fn write_error(
+        &mut self,
+        record: Option<&Record>,
+        row_error: RowError,
+        error: &dyn Display,
+    ) -> Result<()> {
+        let errors = match &mut self.errors {
+            Some(errors) => errors,
+            None => return Ok(()),
+        };
+        errors
+            .serialize(ErrorEntry {
+                line: row_error.line,
+                tx: record.map(|record| record.tx),
+                client: record.map(|record| record.client),
+                kind: row_error.kind,
+                message: error.to_string(),
+            })
+            .context("Unable to write errors")
+    }
+
+    /// Writes the state of each account that the applied `record` changed to the stream, if there
+    /// is one, with balances formatted as `options` specify.
+    fn write_stream(&mut self, record: &Record, options: &ProcessingOptions) -> Result<()> {
+        let stream = match &mut self.stream {
+            Some(stream) => stream,
+            None => return Ok(()),
+        };
+        let target = match record.record_type {
+            RecordType::Transfer => record.target_client.filter(|&id| id != record.client),
+            _ => None,
         };
+        for id in std::iter::once(record.client).chain(target) {
+            if let Some(client) = self.engine.clients.get(&id) {
+                stream.serialize(ClientSummary::new(client, options.scale, options.rounding))?;
+            }
+        }
+        Ok(())
+    }
 
-        if let Err(e) = process_record(&record, &records, &mut clients) {
-            eprintln!("Error processing record: {}", e);
+    /// Checks the balance of each client that the applied `record` could have changed.
+    fn check_balances(&self, record: &Record) -> Result<()> {
+        for id in std::iter::once(record.client).chain(record.target_client) {
+            if let Some(client) = self.engine.clients.get(&id) {
+                client
+                    .check_balance()
+                    .context(format!("Invariant violated by tx {}", record.tx))?;
+            }
         }
+        Ok(())
+    }
 
-        // We need to store transactions because they may become disputed later. We do not need to
-        // store dispute, resolve or chargeback records because these can not be further referenced.
-        if matches!(
-            record.record_type,
-            RecordType::Deposit | RecordType::Withdrawal
-        ) {
-            records.insert(record.tx, record);
+    fn into_processed(self) -> Result<Processed> {
+        if let Some(mut audit) = self.audit {
+            audit.flush().context("Unable to write audit log")?;
+        }
+        if let Some(mut stream) = self.stream {
+            stream.flush()?;
+        }
+        if let Some(mut errors) = self.errors {
+            errors.flush().context("Unable to write errors")?;
+        }
+        if let Some(mut holds) = self.holds {
+            holds.flush().context("Unable to write hold ledger")?;
+        }
+        if let Some((mut history, _)) = self.history {
+            history.flush().context("Unable to write history")?;
+        }
+        let mut open_disputes = Vec::new();
+        for (&tx, dispute) in &self.engine.disputes {
+            if let DisputeState::Disputed { amount } = *dispute {
+                // Only stored transactions can be disputed, so the record is always found.
+                if let Some(record) = self.engine.records.get(tx)? {
+                    open_disputes.push(OpenDispute {
+                        tx,
+                        client: record.client,
+                        amount,
+                    });
+                }
+            }
         }
+        Ok(Processed {
+            clients: self.engine.clients,
+            report: self.report,
+            open_disputes,
+            activity: self.activity,
+        })
     }
+}
+
+/// Checks that an account for the client with the given `id` either exists or can be created
+/// without there being more than `options.max_clients`.
+fn check_client_limit(
+    id: u32,
+    options: &ProcessingOptions,
+    clients: &BTreeMap<u32, Client>,
+) -> Result<(), ProcessError> {
+    match options.max_clients {
+        Some(max) if clients.len() >= max && !clients.contains_key(&id) => {
+            Err(ProcessError::TooManyClients { client: id, max })
+        }
+        _ => Ok(()),
+    }
+}
 
-    Ok(clients.into_iter().map(|(_, client)| client).collect())
+/// Whether the `client` has the funds to withdraw `amount` without its available funds going below
+/// the overdraft limit. A withdrawal so large that the result would be beyond the range of amounts
+/// is always more than the client has.
+fn has_funds(client: &Client, amount: Decimal, options: &ProcessingOptions) -> bool {
+    client
+        .available
+        .checked_sub(amount)
+        .is_some_and(|remaining| remaining >= -options.overdraft_limit)
 }
 
+/// Applies `record` to the client accounts. A withdrawal drained under `OverdrawPolicy::Drain` has
+/// its `amount` reduced to the amount that was actually withdrawn.
 fn process_record(
-    record: &Record,
-    records: &BTreeMap<u32, Record>,
+    record: &mut Record,
+    options: &ProcessingOptions,
+    records: &impl TransactionStore,
+    disputes: &mut BTreeMap<u32, DisputeState>,
     clients: &mut BTreeMap<u32, Client>,
-) -> Result<()> {
+) -> Result<(), ProcessError> {
+    // This comes before the account is created, which happens even if the record is then skipped.
+    check_client_limit(record.client, options, clients)?;
     // We take a copy of the `Client` and overwrite it later to ensure atomicity.
     let mut client = *clients
         .entry(record.client)
         .or_insert_with(|| Client::new(record.client));
 
     // TODO - what if it is locked? https://github.com/webern/moneybags/issues/4
-    // In the absence of guidance on locked accounts, we will assume that we
-    // should not process records for accounts that are locked. Note that there
-    // is no way for an account to become unlocked.
-    ensure!(!client.locked, "Client account is locked");
+    // In the absence of guidance on locked accounts, by default we do not process records for
+    // accounts that are locked, but the locked policy can say otherwise. An unlock record is the
+    // only way for an account to become unlocked, so it is always processed.
+    if client.locked
+        && record.record_type != RecordType::Unlock
+        && options.locked_policy != LockedPolicy::Process
+    {
+        return Err(ProcessError::Locked { client: client.id });
+    }
 
-    match record.record_type {
-        RecordType::Deposit => {
-            client.available += record.amount;
-            client.total += record.amount;
+    // The amounts of resolves, chargebacks and unlocks are ignored, but are not expected to be
+    // given at all, so they are checked too.
+    if exceeds_scale(record.amount, options.scale) {
+        return Err(ProcessError::ExcessPrecision {
+            record_type: record.record_type,
+            tx: record.tx,
+            amount: record.amount,
+            scale: options.scale,
+        });
+    }
+
+    // A negative deposit would be a withdrawal, and vice versa, without any of the checks. Zero
+    // amounts are allowed, though they have no effect, unless they are rejected.
+    if matches!(
+        record.record_type,
+        RecordType::Deposit | RecordType::Withdrawal | RecordType::Transfer
+    ) {
+        if record.amount < Decimal::ZERO {
+            return Err(ProcessError::NegativeAmount {
+                record_type: record.record_type,
+                tx: record.tx,
+                amount: record.amount,
+            });
         }
+        if let Some(max) = options.max_amount.filter(|&max| record.amount > max) {
+            return Err(ProcessError::AmountTooLarge {
+                record_type: record.record_type,
+                tx: record.tx,
+                amount: record.amount,
+                max,
+            });
+        }
+        if options.reject_zero_amount
+            && record.record_type != RecordType::Transfer
+            && record.amount.is_zero()
+        {
+            return Err(ProcessError::ZeroAmount {
+                record_type: record.record_type,
+                tx: record.tx,
+            });
+        }
+        // Transaction IDs are supposed to be globally unique, but if one is reused we keep the
+        // first transaction, so that disputes reference what was actually applied. Transactions
+        // that were skipped are not stored, so their IDs can be reused.
+        if records.get(record.tx)?.is_some() {
+            return Err(ProcessError::DuplicateTx {
+                record_type: record.record_type,
+                tx: record.tx,
+            });
+        }
+    }
+
+    match record.record_type {
+        RecordType::Deposit => client.credit(record.amount, record.tx)?,
         RecordType::Withdrawal => {
-            ensure!(
-                client.available >= record.amount,
-                "Withdrawal failed. Available funds insufficient."
-            );
-            client.available -= record.amount;
-            client.total -= record.amount;
+            // Available funds may go as far below zero as the overdraft limit allows.
+            if !has_funds(&client, record.amount, options) {
+                if options.overdraw_policy == OverdrawPolicy::Reject {
+                    return Err(ProcessError::InsufficientFunds {
+                        client: client.id,
+                        tx: record.tx,
+                    });
+                }
+                // Available funds may already be past the limit, e.g. after a dispute, in which case
+                // nothing is withdrawn.
+                let drained = (client.available + options.overdraft_limit).max(Decimal::ZERO);
+                info!(
+                    "Withdrawal tx {} for client {} drained {} of the requested {}",
+                    record.tx, client.id, drained, record.amount
+                );
+                record.amount = drained;
+            }
+            client.debit(record.amount, record.tx)?;
         }
         RecordType::Dispute => {
-            let disputed_record = records.get(&record.tx).context(format!(
-                "Disputed record tx {} could not be found",
-                record.tx
-            ))?;
-            ensure!(
-                disputed_record.client == record.client,
-                "Disputed record and current record have different client IDs"
-            );
-            client.available -= disputed_record.amount;
-            client.held += disputed_record.amount;
+            let tx = record.referenced_tx();
+            let disputed_record = records.get(tx)?.ok_or(ProcessError::UnknownTx {
+                record_type: record.record_type,
+                tx,
+            })?;
+            if disputed_record.client != record.client {
+                return Err(ProcessError::ClientMismatch {
+                    record_type: record.record_type,
+                    tx,
+                    expected: disputed_record.client,
+                    found: record.client,
+                });
+            }
+            // A transaction can only be disputed once, even if that dispute has been settled.
+            if disputes.contains_key(&tx) {
+                return Err(ProcessError::AlreadyDisputed { tx });
+            }
+            let amount = dispute_amount(record, &disputed_record)?;
+            // The funds of a disputed deposit may already have been withdrawn, and available funds
+            // can go below zero.
+            if disputed_record.record_type != RecordType::Withdrawal
+                && options.dispute_requires_funds
+                && client.available < amount
+            {
+                return Err(ProcessError::FundsUnavailable {
+                    client: client.id,
+                    tx,
+                });
+            }
+            client.apply_hold(amount, tx, disputed_record.record_type)?;
+            disputes.insert(tx, DisputeState::Disputed { amount });
         }
         RecordType::Resolve => {
-            let resolved_record = records.get(&record.tx).context(format!(
-                "Resolved record tx {} could not be found",
-                record.tx
-            ))?;
-            ensure!(
-                resolved_record.client == record.client,
-                "Resolved record and current record have different client IDs"
-            );
-            // TODO - what happens if held is less than resolved amount?
-            client.available += resolved_record.amount;
-            client.held -= resolved_record.amount;
+            let tx = record.referenced_tx();
+            let resolved_record = records.get(tx)?.ok_or(ProcessError::UnknownTx {
+                record_type: record.record_type,
+                tx,
+            })?;
+            if resolved_record.client != record.client {
+                return Err(ProcessError::ClientMismatch {
+                    record_type: record.record_type,
+                    tx,
+                    expected: resolved_record.client,
+                    found: record.client,
+                });
+            }
+            let amount = disputed_amount(record, disputes)?;
+            if client.held < amount {
+                return Err(ProcessError::InsufficientHeldFunds {
+                    record_type: record.record_type,
+                    client: client.id,
+                    tx,
+                });
+            }
+            client.release_hold(amount, tx, resolved_record.record_type)?;
+            disputes.insert(tx, DisputeState::Resolved);
         }
+        // A chargeback is only applied if the referenced transaction belongs to the client, is
+        // currently disputed, i.e. it has not been resolved or charged back already, and the amount
+        // held by that dispute is still held. Held funds only come from disputes, so the last of
+        // these should always be true, but checking it means that a chargeback can never take held
+        // funds below zero. A chargeback that is skipped does not lock the account.
         RecordType::Chargeback => {
-            let chargeback_record = records.get(&record.tx).context(format!(
-                "Chargeback record tx {} could not be found",
-                record.tx
-            ))?;
-            ensure!(
-                chargeback_record.client == record.client,
-                "Chargeback record and current record have different client IDs"
-            );
-            // TODO - what happens if available/held are less than chargeback amount?
-            client.total -= chargeback_record.amount;
-            client.held -= chargeback_record.amount;
-            client.locked = true;
+            let tx = record.referenced_tx();
+            let chargeback_record = records.get(tx)?.ok_or(ProcessError::UnknownTx {
+                record_type: record.record_type,
+                tx,
+            })?;
+            if chargeback_record.client != record.client {
+                return Err(ProcessError::ClientMismatch {
+                    record_type: record.record_type,
+                    tx,
+                    expected: chargeback_record.client,
+                    found: record.client,
+                });
+            }
+            let amount = disputed_amount(record, disputes)?;
+            if client.held < amount {
+                return Err(ProcessError::InsufficientHeldFunds {
+                    record_type: record.record_type,
+                    client: client.id,
+                    tx,
+                });
+            }
+            client.finalize_chargeback(
+                amount,
+                tx,
+                chargeback_record.record_type,
+                !options.no_freeze_on_chargeback,
+            )?;
+            disputes.insert(tx, DisputeState::ChargedBack);
+        }
+        RecordType::Transfer => {
+            let target_id = record
+                .target_client
+                .ok_or(ProcessError::MissingTargetClient { tx: record.tx })?;
+            if !has_funds(&client, record.amount, options) {
+                return Err(ProcessError::InsufficientFunds {
+                    client: client.id,
+                    tx: record.tx,
+                });
+            }
+            client.debit(record.amount, record.tx)?;
+            // A transfer to the same client has no effect, but is otherwise treated like any other.
+            if target_id == client.id {
+                client.credit(record.amount, record.tx)?;
+            } else {
+                check_client_limit(target_id, options, clients)?;
+                let mut target = clients
+                    .get(&target_id)
+                    .copied()
+                    .unwrap_or_else(|| Client::new(target_id));
+                if target.locked && options.locked_policy != LockedPolicy::Process {
+                    return Err(ProcessError::Locked { client: target.id });
+                }
+                target.credit(record.amount, record.tx)?;
+                // Nothing can fail once the target is updated, so both clients change together.
+                clients.insert(target.id, target);
+            }
+        }
+        RecordType::Unlock => {
+            if !options.allow_unlock {
+                return Err(ProcessError::UnlockNotAllowed { client: client.id });
+            }
+            client.locked = false;
         }
     }
 
@@ -249,3 +5093,42 @@ fn process_record(
 
     Ok(())
 }
+
+/// The amount of `disputed_record` that is held by the `dispute`. A dispute with an amount of zero
+/// disputes the whole transaction, otherwise only the given part of it is disputed.
+fn dispute_amount(dispute: &Record, disputed_record: &Record) -> Result<Decimal, ProcessError> {
+    if dispute.amount < Decimal::ZERO {
+        return Err(ProcessError::NegativeAmount {
+            record_type: dispute.record_type,
+            tx: dispute.referenced_tx(),
+            amount: dispute.amount,
+        });
+    }
+    if dispute.amount > disputed_record.amount {
+        return Err(ProcessError::DisputeTooLarge {
+            tx: dispute.referenced_tx(),
+            amount: dispute.amount,
+            disputed: disputed_record.amount,
+        });
+    }
+    if dispute.amount == Decimal::ZERO {
+        Ok(disputed_record.amount)
+    } else {
+        Ok(dispute.amount)
+    }
+}
+
+/// The amount held by the dispute that the resolve or chargeback `record` settles, which is an
+/// error if the referenced transaction is not currently disputed.
+fn disputed_amount(
+    record: &Record,
+    disputes: &BTreeMap<u32, DisputeState>,
+) -> Result<Decimal, ProcessError> {
+    match disputes.get(&record.referenced_tx()) {
+        Some(DisputeState::Disputed { amount }) => Ok(*amount),
+        _ => Err(ProcessError::NotDisputed {
+            record_type: record.record_type,
+            tx: record.referenced_tx(),
+        }),
+    }
+}
@@ -1,14 +1,78 @@
+use anyhow::{Context, Result};
 use clap::Parser;
-use moneybags::Moneybags;
-use std::io::stdout;
+use log::{LevelFilter, Log, Metadata, Record};
+use moneybags::{Interrupt, Moneybags, ProcessingReport};
+use std::fs::File;
+use std::io::{stdout, BufWriter};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// The exit status when processing is interrupted, which is conventionally 128 plus SIGINT.
+const INTERRUPTED_EXIT_STATUS: i32 = 130;
+
+/// Set by the first Ctrl-C, which stops processing and writes the accounts as they are.
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
 
 fn main() -> ! {
-    let moneybags = Moneybags::parse();
-    match moneybags.run(stdout()) {
+    init_logger();
+    let mut moneybags = Moneybags::parse();
+    // A second Ctrl-C exits at once, in case reading is blocked, e.g. on stdin.
+    let handler = ctrlc::set_handler(|| {
+        if INTERRUPTED.swap(true, Ordering::SeqCst) {
+            std::process::exit(INTERRUPTED_EXIT_STATUS)
+        }
+    });
+    if handler.is_ok() {
+        moneybags.interrupt = Some(Interrupt::new(&INTERRUPTED));
+    }
+    match run(&moneybags) {
+        Ok(_) if INTERRUPTED.load(Ordering::SeqCst) => std::process::exit(INTERRUPTED_EXIT_STATUS),
+        Ok(report) if report.total_skipped() > 0 => std::process::exit(2),
         Ok(_) => std::process::exit(0),
         Err(e) => {
-            eprintln!("{}", e);
+            eprintln!("{:#}", e);
             std::process::exit(1)
         }
     }
 }
+
+/// Runs `moneybags`, writing to either the `--output` file or `stdout`. The writer is dropped, and
+/// thus flushed, before this function returns so that nothing is lost when we call `exit`.
+fn run(moneybags: &Moneybags) -> Result<ProcessingReport> {
+    match &moneybags.output {
+        Some(path) => {
+            let f = File::create(path)
+                .context(format!("Unable to create file '{}'", path.display()))?;
+            moneybags.run(BufWriter::new(f))
+        }
+        None => moneybags.run(stdout()),
+    }
+}
+
+/// Writes log messages to `stderr` as they are, without a level or timestamp.
+struct StderrLogger;
+
+impl Log for StderrLogger {
+    fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record<'_>) {
+        if self.enabled(record.metadata()) {
+            eprintln!("{}", record.args());
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+/// Installs the `StderrLogger`, at the level given by the `RUST_LOG` environment variable, e.g.
+/// `RUST_LOG=error` or `RUST_LOG=off`, or at the `info` level if it is not set or not a level.
+fn init_logger() {
+    let level = std::env::var("RUST_LOG")
+        .ok()
+        .and_then(|level| level.parse().ok())
+        .unwrap_or(LevelFilter::Info);
+    // This can only fail if a logger has already been installed.
+    let _ = log::set_logger(&StderrLogger);
+    log::set_max_level(level);
+}
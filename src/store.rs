@@ -0,0 +1,168 @@
+/*!
+
+Storage for the deposit and withdrawal records that may later be referenced by dispute, resolve or
+chargeback records.
+
+*/
+use crate::{Record, RecordType};
+use anyhow::{bail, Context, Result};
+use rust_decimal::Decimal;
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{File, OpenOptions};
+use std::io::{ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+/// A store of transaction records keyed by tx. Errors returned by a store are not the fault of any
+/// particular record, so they stop processing rather than causing a record to be skipped.
+pub trait TransactionStore {
+    /// Returns the record with the given `tx`, if it has been stored.
+    fn get(&self, tx: u32) -> Result<Option<Record>>;
+
+    /// Stores the `record`, replacing any record that was previously stored with the same tx.
+    fn insert(&mut self, record: Record) -> Result<()>;
+
+    /// Every stored record, in any order, so that they can be saved in a checkpoint, or `None` if
+    /// the store can not list them.
+    fn records(&self) -> Option<Vec<Record>> {
+        None
+    }
+}
+
+/// The default, in-memory, transaction store.
+impl TransactionStore for BTreeMap<u32, Record> {
+    fn get(&self, tx: u32) -> Result<Option<Record>> {
+        Ok(BTreeMap::get(self, &tx).copied())
+    }
+
+    fn insert(&mut self, record: Record) -> Result<()> {
+        BTreeMap::insert(self, record.tx, record);
+        Ok(())
+    }
+
+    fn records(&self) -> Option<Vec<Record>> {
+        Some(self.values().copied().collect())
+    }
+}
+
+/// An in-memory transaction store that can be created with room for a given number of records, e.g.
+/// with `HashMap::with_capacity`, so that it does not need to grow while processing.
+impl TransactionStore for HashMap<u32, Record> {
+    fn get(&self, tx: u32) -> Result<Option<Record>> {
+        Ok(HashMap::get(self, &tx).copied())
+    }
+
+    fn insert(&mut self, record: Record) -> Result<()> {
+        HashMap::insert(self, record.tx, record);
+        Ok(())
+    }
+
+    fn records(&self) -> Option<Vec<Record>> {
+        Some(self.values().copied().collect())
+    }
+}
+
+/// The number of bytes used to store each record in a `DiskStore`: a presence flag, the record
+/// type, the client ID and the amount.
+const SLOT_SIZE: u64 = 1 + 1 + 4 + 16;
+
+/// A transaction store that keeps records in a file rather than in memory, so that memory use does
+/// not grow with the number of transactions.
+///
+/// Each possible tx has a fixed-size slot in the file at offset `tx * SLOT_SIZE`, so no index needs
+/// to be held in memory. The file is sparse: only the slots that have been written take up space on
+/// disk, but its apparent size can be as large as the highest tx times `SLOT_SIZE`. This relies on
+/// the filesystem supporting sparse files, which most do. The file is removed when the store is
+/// dropped.
+#[derive(Debug)]
+pub struct DiskStore {
+    path: PathBuf,
+    file: File,
+}
+
+impl DiskStore {
+    /// Creates the file at `path`, truncating it if it already exists, to hold the records.
+    pub fn create(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)
+            .context(format!(
+                "Unable to create transaction store '{}'",
+                path.display()
+            ))?;
+        Ok(Self { path, file })
+    }
+}
+
+impl Drop for DiskStore {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+impl TransactionStore for DiskStore {
+    fn get(&self, tx: u32) -> Result<Option<Record>> {
+        let mut slot = [0u8; SLOT_SIZE as usize];
+        let mut file = &self.file;
+        file.seek(SeekFrom::Start(u64::from(tx) * SLOT_SIZE))?;
+        match file.read_exact(&mut slot) {
+            Ok(_) => {}
+            // Slots past the end of the file have never been written.
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e).context("Unable to read from transaction store"),
+        }
+        // Slots that have not been written read back as zeros.
+        if slot[0] == 0 {
+            return Ok(None);
+        }
+        let record_type = match slot[1] {
+            0 => RecordType::Deposit,
+            1 => RecordType::Withdrawal,
+            2 => RecordType::Dispute,
+            3 => RecordType::Resolve,
+            4 => RecordType::Chargeback,
+            5 => RecordType::Transfer,
+            6 => RecordType::Unlock,
+            unknown => bail!("Unknown record type {} in transaction store", unknown),
+        };
+        let mut client = [0u8; 4];
+        client.copy_from_slice(&slot[2..6]);
+        let mut amount = [0u8; 16];
+        amount.copy_from_slice(&slot[6..22]);
+        Ok(Some(Record {
+            record_type,
+            client: u32::from_le_bytes(client),
+            tx,
+            amount: Decimal::deserialize(amount),
+            // Only deposits and withdrawals are stored, which have neither a target client nor a
+            // reference tx. Their timestamps are not needed once they have been processed.
+            target_client: None,
+            reference_tx: None,
+            timestamp: None,
+        }))
+    }
+
+    fn insert(&mut self, record: Record) -> Result<()> {
+        let mut slot = [0u8; SLOT_SIZE as usize];
+        slot[0] = 1;
+        slot[1] = match record.record_type {
+            RecordType::Deposit => 0,
+            RecordType::Withdrawal => 1,
+            RecordType::Dispute => 2,
+            RecordType::Resolve => 3,
+            RecordType::Chargeback => 4,
+            RecordType::Transfer => 5,
+            RecordType::Unlock => 6,
+        };
+        slot[2..6].copy_from_slice(&record.client.to_le_bytes());
+        slot[6..22].copy_from_slice(&record.amount.serialize());
+        self.file
+            .seek(SeekFrom::Start(u64::from(record.tx) * SLOT_SIZE))?;
+        self.file
+            .write_all(&slot)
+            .context("Unable to write to transaction store")
+    }
+}
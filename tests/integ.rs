@@ -1,6 +1,18 @@
-use moneybags::Moneybags;
-use std::io::Cursor;
+use chrono::{DateTime, Utc};
+use maplit::btreemap;
+use moneybags::{
+    diff_clients, merge, process, process_records, process_records_threaded, validate_record,
+    validate_records, Client, CurrencyScale, DecimalSeparator, Delimiter, DiffReport, Difference,
+    Engine, ErrorKind, InputEncoding, InputFormat, LockedPolicy, Moneybags, MoneybagsCommand,
+    OpenDispute, OutputFormat, OverdrawPolicy, ProcessError, ProcessingOptions, Record, RecordType,
+    RoundingMode, RowError, SortKey, Totals,
+};
+use rust_decimal::Decimal;
+use std::io::{Cursor, Read, Write};
+use std::num::{NonZeroU32, NonZeroUsize};
 use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
 
 fn path(filename: impl AsRef<str>) -> PathBuf {
     PathBuf::from(env!("CARGO_MANIFEST_DIR"))
@@ -9,21 +21,45 @@ fn path(filename: impl AsRef<str>) -> PathBuf {
         .join(filename.as_ref())
 }
 
+/// Options for processing in strict mode.
+fn strict() -> ProcessingOptions {
+    ProcessingOptions {
+        strict: true,
+        ..Default::default()
+    }
+}
+
+/// Runs `moneybags` and returns the output.
+fn output(moneybags: Moneybags) -> String {
+    let mut output_bytes = Cursor::new(Vec::<u8>::new());
+    moneybags.run(&mut output_bytes).unwrap();
+    String::from_utf8(output_bytes.into_inner()).unwrap()
+}
+
+/// Runs `moneybags` against the named file in `tests/data` and returns the output.
+fn run(filename: impl AsRef<str>) -> String {
+    output(Moneybags {
+        csv_file: vec![path(filename)],
+        ..Default::default()
+    })
+}
+
 /// The example given in the specification should throw an error because a withdrawal attempt is
 /// made with insufficient funds.
 #[test]
 fn given_example() {
     let mut output_bytes = Cursor::new(Vec::<u8>::new());
     Moneybags {
-        csv_file: path("given-example.csv"),
+        csv_file: vec![path("given-example.csv")],
+        ..Default::default()
     }
     .run(&mut output_bytes)
     .unwrap();
 
     let output = String::from_utf8(output_bytes.into_inner()).unwrap();
     let expected = r#"client,available,held,total,locked
-1,1.5,0,1.5,false
-2,2.0,0,2.0,false
+1,1.5000,0.0000,1.5000,false
+2,2.0000,0.0000,2.0000,false
 "#;
     assert_eq!(output, expected);
 }
@@ -35,15 +71,3295 @@ fn given_example() {
 fn resolve_and_chargeback() {
     let mut output_bytes = Cursor::new(Vec::<u8>::new());
     Moneybags {
-        csv_file: path("resolve-and-chargeback.csv"),
+        csv_file: vec![path("resolve-and-chargeback.csv")],
+        ..Default::default()
+    }
+    .run(&mut output_bytes)
+    .unwrap();
+
+    let output = String::from_utf8(output_bytes.into_inner()).unwrap();
+    let expected = r#"client,available,held,total,locked
+1,3.4000,0.0000,3.4000,false
+2,1.4999,0.0000,1.4999,true
+"#;
+    assert_eq!(output, expected);
+}
+
+/// With `--locked-only`, only client 2, whose account was locked by a chargeback, is output.
+#[test]
+fn locked_only() {
+    let locked = output(Moneybags {
+        csv_file: vec![path("resolve-and-chargeback.csv")],
+        locked_only: true,
+        ..Default::default()
+    });
+    let expected = r#"client,available,held,total,locked
+2,1.4999,0.0000,1.4999,true
+"#;
+    assert_eq!(locked, expected);
+
+    let none = output(Moneybags {
+        csv_file: vec![path("resolve-and-chargeback.csv")],
+        locked_only: true,
+        clients: vec![1],
+        ..Default::default()
+    });
+    assert_eq!(none, "client,available,held,total,locked\n");
+}
+
+/// `explain chargeback` says that the account is frozen, and how that interacts with the options
+/// for locked accounts, without reading any input.
+#[test]
+fn explain() {
+    let chargeback = output(Moneybags {
+        command: Some(MoneybagsCommand::Explain {
+            record_type: RecordType::Chargeback,
+        }),
+        ..Default::default()
+    });
+    assert!(chargeback.contains("the account is then locked, i.e. frozen, for good"));
+    assert!(chargeback.contains("A chargeback for a locked account is reported and skipped."));
+
+    let chargeback = output(Moneybags {
+        command: Some(MoneybagsCommand::Explain {
+            record_type: RecordType::Chargeback,
+        }),
+        allow_unlock: true,
+        locked_policy: LockedPolicy::Process,
+        strict: true,
+        ..Default::default()
+    });
+    assert!(chargeback.contains("frozen, until an unlock record unlocks it"));
+    assert!(chargeback.contains("is processed as for any other, and the account remains locked"));
+    assert!(chargeback.contains("not currently disputed stops processing with an error"));
+
+    let withdrawal = ProcessingOptions::default()
+        .with_overdraft_limit(Decimal::new(5, 0))
+        .with_overdraw_policy(OverdrawPolicy::Drain)
+        .explain(RecordType::Withdrawal);
+    assert!(withdrawal.contains(
+        "A withdrawal that would take the available funds below the overdraft limit of -5 \
+         withdraws only as much as it can"
+    ));
+}
+
+/// Client IDs of 0 and `u32::MAX` are accounts like any other, in order in the output, on one thread
+/// or several, while a row without a client ID is skipped rather than taken to be client 0.
+#[test]
+fn client_id_bounds() {
+    let expected = r#"client,available,held,total,locked
+0,0.5000,0.0000,0.5000,false
+4294967295,0.0000,2.0000,2.0000,false
+"#;
+    assert_eq!(run("client-id-bounds.csv"), expected);
+    let threaded = output(Moneybags {
+        csv_file: vec![path("client-id-bounds.csv")],
+        threads: NonZeroUsize::new(3),
+        ..Default::default()
+    });
+    assert_eq!(threaded, expected);
+    let client_0 = output(Moneybags {
+        csv_file: vec![path("client-id-bounds.csv")],
+        clients: vec![0],
+        ..Default::default()
+    });
+    assert_eq!(
+        client_0,
+        "client,available,held,total,locked\n0,0.5000,0.0000,0.5000,false\n"
+    );
+
+    let input = std::fs::read(path("client-id-bounds.csv")).unwrap();
+    let result = process_records(Cursor::new(input), &ProcessingOptions::default()).unwrap();
+    assert_eq!(result.report.skipped, btreemap! {ErrorKind::Parse => 1});
+    assert_eq!(result.client(0).unwrap().total(), Decimal::new(5, 1));
+    assert_eq!(result.client(u32::MAX).unwrap().held(), Decimal::new(2, 0));
+}
+
+/// Records can be processed from memory, without a file, and the account state inspected directly.
+#[test]
+fn process_records_from_memory() {
+    let input = r#"type,client,tx,amount
+deposit,1,1,1.0
+withdrawal,2,2,3.0
+"#;
+    let clients = process_records(Cursor::new(input), &ProcessingOptions::default())
+        .unwrap()
+        .clients;
+    assert_eq!(clients.len(), 2);
+    // Client 2's only transaction failed, so its account is untouched.
+    assert_eq!(clients[1], Client::new(2));
+    assert_ne!(clients[0], Client::new(1));
+}
+
+/// Records can be processed from an async reader, with the same results as from a sync one, even
+/// for rows that can not be parsed and for a file with a byte order mark.
+#[cfg(feature = "async")]
+#[tokio::test]
+async fn process_records_async_from_memory() {
+    let options = ProcessingOptions::default();
+    for filename in [
+        "given-example.csv",
+        "malformed.csv",
+        "given-example-excel.csv",
+    ] {
+        let input = std::fs::read(path(filename)).unwrap();
+        let expected = process_records(Cursor::new(&input), &options).unwrap();
+        let actual = moneybags::process_records_async(Cursor::new(&input), &options)
+            .await
+            .unwrap();
+        assert_eq!(actual, expected, "{}", filename);
+    }
+    let strict = strict();
+    let input = "type,client,tx,amount\ndeposit,1,1,1.0\ndeposit,1,x,1.0\n";
+    let err = moneybags::process_records_async(Cursor::new(input), &strict)
+        .await
+        .unwrap_err();
+    let row_error = err.downcast_ref::<RowError>().unwrap();
+    assert_eq!(row_error.kind, ErrorKind::Parse);
+    assert_eq!(row_error.line, Some(3));
+}
+
+/// The binary writes to the `--output` file instead of stdout, truncating anything already there.
+#[test]
+fn output_flag() {
+    let output_file = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("output_flag.csv");
+    std::fs::write(&output_file, "this should be overwritten\n".repeat(10)).unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_moneybags"))
+        .arg(path("given-example.csv"))
+        .arg("--output")
+        .arg(&output_file)
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(2));
+    assert!(output.stdout.is_empty());
+    let expected = r#"client,available,held,total,locked
+1,1.5000,0.0000,1.5000,false
+2,2.0000,0.0000,2.0000,false
+"#;
+    assert_eq!(std::fs::read_to_string(&output_file).unwrap(), expected);
+}
+
+/// Output amounts always have exactly four places past the decimal, no matter how much precision
+/// was accumulated along the way.
+#[test]
+fn output_precision() {
+    let output = run("excess-precision.csv");
+    let expected = r#"client,available,held,total,locked
+1,3.4000,0.0000,3.4000,false
+2,1.2346,0.0000,1.2346,false
+3,7.0000,0.0000,7.0000,false
+"#;
+    assert_eq!(output, expected);
+}
+
+/// An input amount with more than four places past the decimal is skipped.
+#[test]
+fn input_precision() {
+    let input = r#"type,client,tx,amount
+deposit,1,1,1.00001
+withdrawal,1,2,1.0
+"#;
+    let clients = process_records(Cursor::new(input), &ProcessingOptions::default())
+        .unwrap()
+        .clients;
+    assert_eq!(clients, vec![Client::new(1)]);
+}
+
+/// Disputing a withdrawal returns the withdrawn funds to the account as held funds. Client 1's
+/// withdrawal is charged back, making the funds available, while client 2's withdrawal is resolved,
+/// so it stands.
+#[test]
+fn dispute_withdrawal() {
+    let output = run("dispute-withdrawal.csv");
+    let expected = r#"client,available,held,total,locked
+1,5.0000,0.0000,5.0000,true
+2,3.0000,0.0000,3.0000,false
+"#;
+    assert_eq!(output, expected);
+}
+
+/// A resolve is only applied to a transaction that is currently disputed. Client 1's resolve has no
+/// dispute and client 2's transaction is resolved twice, so both of these resolves are skipped.
+#[test]
+fn resolve_without_dispute() {
+    let output = run("resolve-without-dispute.csv");
+    let expected = r#"client,available,held,total,locked
+1,10.0000,0.0000,10.0000,false
+2,8.0000,0.0000,8.0000,false
+"#;
+    assert_eq!(output, expected);
+}
+
+/// A chargeback is only applied to a transaction that is currently disputed, so client 1's account
+/// is neither debited nor locked.
+#[test]
+fn chargeback_without_dispute() {
+    let output = run("chargeback-without-dispute.csv");
+    let expected = r#"client,available,held,total,locked
+1,10.0000,0.0000,10.0000,false
+"#;
+    assert_eq!(output, expected);
+}
+
+/// A transaction can only be disputed once. Client 1's second dispute would double the hold and
+/// client 2's transaction is disputed again after its dispute was resolved, so both are skipped.
+#[test]
+fn double_dispute() {
+    let output = run("double-dispute.csv");
+    let expected = r#"client,available,held,total,locked
+1,0.0000,10.0000,10.0000,false
+2,5.0000,0.0000,5.0000,false
+"#;
+    assert_eq!(output, expected);
+}
+
+/// By default, rows that cannot be parsed or processed are skipped.
+#[test]
+fn malformed_lenient() {
+    let output = run("malformed.csv");
+    let expected = r#"client,available,held,total,locked
+1,1.0000,0.0000,1.0000,false
+2,3.0000,0.0000,3.0000,false
+"#;
+    assert_eq!(output, expected);
+}
+
+/// In strict mode the first row that cannot be parsed is an error.
+#[test]
+fn malformed_strict() {
+    let result = Moneybags {
+        csv_file: vec![path("malformed.csv")],
+        strict: true,
+        ..Default::default()
+    }
+    .run(Cursor::new(Vec::<u8>::new()));
+    let message = format!("{:#}", result.unwrap_err());
+    assert!(message.starts_with("Error parsing csv line"), "{}", message);
+}
+
+/// In strict mode the first row that cannot be processed is an error.
+#[test]
+fn insufficient_funds_strict() {
+    let input = r#"type,client,tx,amount
+deposit,1,1,1.0
+withdrawal,1,2,3.0
+deposit,1,3,1.0
+"#;
+    let message = format!(
+        "{:#}",
+        process_records(Cursor::new(input), &strict()).unwrap_err()
+    );
+    assert!(
+        message.contains("Available funds insufficient"),
+        "{}",
+        message
+    );
+}
+
+/// The error that stops strict processing can be matched on to find out what went wrong.
+#[test]
+fn process_error_strict() {
+    let input = r#"type,client,tx,amount
+deposit,1,1,1.0
+withdrawal,1,2,3.0
+deposit,2,3,1.0
+dispute,1,3,
+"#;
+    let err = process_records(Cursor::new(input), &strict()).unwrap_err();
+    let process_error = err.downcast_ref::<ProcessError>().unwrap();
+    assert!(matches!(
+        process_error,
+        ProcessError::InsufficientFunds { client: 1, tx: 2 }
+    ));
+    assert_eq!(process_error.kind(), Some(ErrorKind::InsufficientFunds));
+
+    let input = input.replace("withdrawal,1,2,3.0", "withdrawal,1,2,0.5");
+    let err = process_records(Cursor::new(input), &strict()).unwrap_err();
+    assert!(matches!(
+        err.downcast_ref::<ProcessError>().unwrap(),
+        ProcessError::ClientMismatch {
+            record_type: RecordType::Dispute,
+            tx: 3,
+            expected: 2,
+            found: 1,
+        }
+    ));
+}
+
+/// The binary exits with an error in strict mode when a row cannot be parsed.
+#[test]
+fn malformed_strict_exit_code() {
+    let output = Command::new(env!("CARGO_BIN_EXE_moneybags"))
+        .arg(path("malformed.csv"))
+        .arg("--strict")
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(1));
+    assert!(output.stdout.is_empty());
+}
+
+/// Skipped rows are counted by the reason they were skipped.
+#[test]
+fn processing_report() {
+    let f = std::fs::File::open(path("malformed.csv")).unwrap();
+    let report = process_records(f, &ProcessingOptions::default())
+        .unwrap()
+        .report;
+    assert_eq!(report.rows, 4);
+    assert_eq!(
+        report.skipped,
+        btreemap! {
+            ErrorKind::Parse => 1,
+            ErrorKind::InsufficientFunds => 1,
+        }
+    );
+    assert_eq!(
+        report.to_string(),
+        "skipped 2 rows (1 parse, 1 insufficient funds)"
+    );
+
+    let f = std::fs::File::open(path("resolve-and-chargeback.csv")).unwrap();
+    let report = process_records(f, &ProcessingOptions::default())
+        .unwrap()
+        .report;
+    assert_eq!(report.skipped, btreemap! { ErrorKind::Locked => 1 });
+}
+
+/// Transaction records can come from any reader, such as stdin, rather than a file.
+#[test]
+fn run_reader() {
+    let input = std::fs::read(path("given-example.csv")).unwrap();
+    let mut output_bytes = Cursor::new(Vec::<u8>::new());
+    Moneybags::default()
+        .run_reader(Cursor::new(input), &mut output_bytes)
+        .unwrap();
+    let output = String::from_utf8(output_bytes.into_inner()).unwrap();
+    assert_eq!(output, run("given-example.csv"));
+}
+
+/// The binary reads from stdin when no file is given.
+#[test]
+fn stdin() {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_moneybags"))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(&std::fs::read(path("given-example.csv")).unwrap())
+        .unwrap();
+    let output = child.wait_with_output().unwrap();
+    assert_eq!(output.status.code(), Some(2));
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        run("given-example.csv")
+    );
+}
+
+/// The account summary can be written as JSON, with amounts as strings.
+#[test]
+fn json_output() {
+    let mut output_bytes = Cursor::new(Vec::<u8>::new());
+    Moneybags {
+        csv_file: vec![path("resolve-and-chargeback.csv")],
+        format: OutputFormat::Json,
+        ..Default::default()
     }
     .run(&mut output_bytes)
     .unwrap();
 
     let output = String::from_utf8(output_bytes.into_inner()).unwrap();
+    let expected = r#"[
+  {
+    "client": 1,
+    "available": "3.4000",
+    "held": "0.0000",
+    "total": "3.4000",
+    "locked": false
+  },
+  {
+    "client": 2,
+    "available": "1.4999",
+    "held": "0.0000",
+    "total": "1.4999",
+    "locked": true
+  }
+]
+"#;
+    assert_eq!(output, expected);
+}
+
+/// Keeping transactions on disk produces the same results as keeping them in memory.
+#[test]
+fn disk_tx_store() {
+    let tx_store = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("disk_tx_store.bin");
+    for filename in [
+        "resolve-and-chargeback.csv",
+        "dispute-withdrawal.csv",
+        "double-dispute.csv",
+    ] {
+        let mut output_bytes = Cursor::new(Vec::<u8>::new());
+        Moneybags {
+            csv_file: vec![path(filename)],
+            tx_store: Some(tx_store.clone()),
+            ..Default::default()
+        }
+        .run(&mut output_bytes)
+        .unwrap();
+        let output = String::from_utf8(output_bytes.into_inner()).unwrap();
+        assert_eq!(output, run(filename), "{}", filename);
+        assert!(!tx_store.exists());
+    }
+}
+
+/// Keeping transactions in a hash map produces the same results as keeping them in an ordered map,
+/// whether or not the capacity is enough for all of them.
+#[test]
+fn tx_store_capacity() {
+    for filename in [
+        "resolve-and-chargeback.csv",
+        "dispute-withdrawal.csv",
+        "double-dispute.csv",
+    ] {
+        for capacity in [0, 1024] {
+            let output = output(Moneybags {
+                csv_file: vec![path(filename)],
+                tx_store_capacity: Some(capacity),
+                ..Default::default()
+            });
+            assert_eq!(output, run(filename), "{}", filename);
+        }
+    }
+}
+
+/// Processing on multiple threads produces the same results as processing on one.
+#[test]
+fn threads() {
+    for filename in [
+        "resolve-and-chargeback.csv",
+        "dispute-withdrawal.csv",
+        "double-dispute.csv",
+        "malformed.csv",
+    ] {
+        let sequential = process_records(
+            std::fs::File::open(path(filename)).unwrap(),
+            &ProcessingOptions::default(),
+        );
+        for threads in [1, 2, 3] {
+            let threaded = process_records_threaded(
+                std::fs::File::open(path(filename)).unwrap(),
+                &ProcessingOptions::default(),
+                NonZeroUsize::new(threads).unwrap(),
+            );
+            assert_eq!(
+                threaded.unwrap(),
+                *sequential.as_ref().unwrap(),
+                "{} on {} threads",
+                filename,
+                threads
+            );
+        }
+    }
+}
+
+/// Processing on multiple threads stops at the first bad row in strict mode.
+#[test]
+fn threads_strict() {
+    let result = process_records_threaded(
+        std::fs::File::open(path("malformed.csv")).unwrap(),
+        &strict(),
+        NonZeroUsize::new(2).unwrap(),
+    );
+    assert!(result.is_err());
+}
+
+/// Records constructed in memory are processed the same way as records parsed from CSV.
+#[test]
+fn process_in_memory_records() {
+    let records = vec![
+        Record::new(RecordType::Deposit, 1, 1, Decimal::new(10, 1)),
+        Record::new(RecordType::Deposit, 2, 2, Decimal::new(20, 1)),
+        Record::new(RecordType::Deposit, 1, 3, Decimal::new(20, 1)),
+        Record::new(RecordType::Withdrawal, 1, 4, Decimal::new(15, 1)),
+        Record::new(RecordType::Withdrawal, 2, 5, Decimal::new(30, 1)),
+    ];
+    let from_memory = process(records, &ProcessingOptions::default()).unwrap();
+    let from_csv = process_records(
+        std::fs::File::open(path("given-example.csv")).unwrap(),
+        &ProcessingOptions::default(),
+    )
+    .unwrap();
+    assert_eq!(from_memory, from_csv);
+    assert_eq!(from_memory.report.total_skipped(), 1);
+}
+
+/// Deposits and withdrawals with negative amounts are skipped, and can not be disputed, while zero
+/// amounts are allowed.
+#[test]
+fn negative_amounts() {
+    let output = run("negative-amounts.csv");
+    let expected = r#"client,available,held,total,locked
+1,10.0000,0.0000,10.0000,false
+2,0.0000,0.0000,0.0000,false
+"#;
+    assert_eq!(output, expected);
+
+    let f = std::fs::File::open(path("negative-amounts.csv")).unwrap();
+    let report = process_records(f, &ProcessingOptions::default())
+        .unwrap()
+        .report;
+    assert_eq!(
+        report.skipped,
+        btreemap! {
+            ErrorKind::NegativeAmount => 2,
+            ErrorKind::UnknownTx => 1,
+        }
+    );
+}
+
+/// Only the first of the transactions sharing a tx is applied, and it is the one that is disputed.
+#[test]
+fn duplicate_tx() {
+    let output = run("duplicate-tx.csv");
+    let expected = r#"client,available,held,total,locked
+1,0.0000,1.0000,1.0000,false
+"#;
+    assert_eq!(output, expected);
+
+    let f = std::fs::File::open(path("duplicate-tx.csv")).unwrap();
+    let report = process_records(f, &ProcessingOptions::default())
+        .unwrap()
+        .report;
+    assert_eq!(report.skipped, btreemap! { ErrorKind::DuplicateTx => 2 });
+}
+
+/// The binary exits with 2 when rows were skipped, and 0 when every row was processed.
+#[test]
+fn exit_code() {
+    for (filename, code) in [("given-example.csv", 2), ("dispute-withdrawal.csv", 0)] {
+        let output = Command::new(env!("CARGO_BIN_EXE_moneybags"))
+            .arg(path(filename))
+            .output()
+            .unwrap();
+        assert_eq!(output.status.code(), Some(code), "{}", filename);
+        assert_eq!(String::from_utf8(output.stdout).unwrap(), run(filename));
+    }
+}
+
+/// The input can use a delimiter other than a comma.
+#[test]
+fn semicolon_delimiter() {
+    let output = output(Moneybags {
+        csv_file: vec![path("given-example-semicolon.csv")],
+        delimiter: Delimiter(b';'),
+        ..Default::default()
+    });
+    assert_eq!(output, run("given-example.csv"));
+}
+
+/// Amounts can use a comma as the decimal separator, as long as it is not also the delimiter.
+#[test]
+fn decimal_separator() {
+    let moneybags = Moneybags {
+        csv_file: vec![path("given-example-decimal-comma.csv")],
+        delimiter: Delimiter(b';'),
+        decimal_separator: DecimalSeparator(b','),
+        ..Default::default()
+    };
+    assert_eq!(output(moneybags.clone()), run("given-example.csv"));
+    let error = Moneybags {
+        delimiter: Delimiter(b','),
+        ..moneybags.clone()
+    }
+    .run(Cursor::new(Vec::<u8>::new()))
+    .unwrap_err();
+    assert_eq!(
+        error.to_string(),
+        "The decimal separator ',' can not also be the delimiter"
+    );
+
+    // An amount that uses a period can not be parsed.
+    let input = r#"type;client;tx;amount
+deposit;1;1;1.5
+deposit;1;2;2,5
+"#;
+    let result = process_records(Cursor::new(input), &moneybags.options()).unwrap();
+    assert_eq!(result.report.skipped, btreemap! { ErrorKind::Parse => 1 });
+    assert_eq!(result.clients[0].available(), Decimal::new(25, 1));
+}
+
+/// The output can use a delimiter other than a comma, regardless of the input delimiter.
+#[test]
+fn output_delimiter() {
+    let expected = "client\tavailable\theld\ttotal\tlocked
+1\t1.5000\t0.0000\t1.5000\tfalse
+2\t2.0000\t0.0000\t2.0000\tfalse
+";
+    for (filename, delimiter) in [
+        ("given-example.csv", b','),
+        ("given-example-semicolon.csv", b';'),
+    ] {
+        let output = output(Moneybags {
+            csv_file: vec![path(filename)],
+            delimiter: Delimiter(delimiter),
+            output_delimiter: Delimiter(b'\t'),
+            ..Default::default()
+        });
+        assert_eq!(output, expected, "{}", filename);
+    }
+}
+
+/// Whitespace around fields, including headers, is ignored.
+#[test]
+fn padded_fields() {
+    assert_eq!(run("given-example-padded.csv"), run("given-example.csv"));
+}
+
+/// A dispute referencing another client's transaction is skipped by default.
+#[test]
+fn cross_client_dispute_lenient() {
+    let output = run("cross-client.csv");
+    let expected = r#"client,available,held,total,locked
+1,10.0000,0.0000,10.0000,false
+2,6.0000,0.0000,6.0000,false
+"#;
+    assert_eq!(output, expected);
+}
+
+/// In strict mode, a dispute referencing another client's transaction stops processing with an
+/// error naming the tx and both clients.
+#[test]
+fn cross_client_dispute_strict() {
+    let f = std::fs::File::open(path("cross-client.csv")).unwrap();
+    let message = format!("{:#}", process_records(f, &strict()).unwrap_err());
+    assert_eq!(
+        message,
+        "Error processing record on line 4 (byte 55): Disputed record tx 1 belongs to client 1 but \
+        the dispute is for client 2"
+    );
+}
+
+/// The `--audit` file shows every record with the client's balances after it, whether or not it
+/// was applied, and does not change the summary output.
+#[test]
+fn audit_log() {
+    let audit_file = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("audit_log.csv");
+    let mut output_bytes = Cursor::new(Vec::<u8>::new());
+    Moneybags {
+        csv_file: vec![path("resolve-and-chargeback.csv")],
+        audit: Some(audit_file.clone()),
+        ..Default::default()
+    }
+    .run(&mut output_bytes)
+    .unwrap();
+    let output = String::from_utf8(output_bytes.into_inner()).unwrap();
+    assert_eq!(output, run("resolve-and-chargeback.csv"));
+    let expected = r#"tx,client,type,amount,available,held,total,status
+1,1,deposit,1.0,1.0000,0.0000,1.0000,applied
+2,1,deposit,1.2,2.2000,0.0000,2.2000,applied
+3,1,deposit,1.3,3.5000,0.0000,3.5000,applied
+2,1,dispute,,2.3000,1.2000,3.5000,applied
+4,1,withdrawal,0.1,2.2000,1.2000,3.4000,applied
+2,1,resolve,,3.4000,0.0000,3.4000,applied
+5,2,deposit,1.0,1.0000,0.0000,1.0000,applied
+6,2,deposit,1.2,2.2000,0.0000,2.2000,applied
+8,2,withdrawal,1.0001,1.1999,0.0000,1.1999,applied
+7,2,deposit,1.3,2.4999,0.0000,2.4999,applied
+5,2,dispute,,1.4999,1.0000,2.4999,applied
+5,2,chargeback,,1.4999,0.0000,1.4999,applied
+9,2,withdrawal,0.2,1.4999,0.0000,1.4999,skipped:locked
+"#;
+    assert_eq!(std::fs::read_to_string(&audit_file).unwrap(), expected);
+}
+
+/// Processing that is interrupted after a checkpoint resumes from it, with the same result as if it
+/// had not been interrupted, including a dispute of a deposit made before the checkpoint. Stopping
+/// at `--max-rows` stands in for the interruption.
+#[test]
+fn checkpoint() {
+    let checkpoint_file = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("checkpoint.json");
+    let _ = std::fs::remove_file(&checkpoint_file);
+    let moneybags = Moneybags {
+        csv_file: vec![path("checkpoint.csv")],
+        checkpoint: Some(checkpoint_file.clone()),
+        checkpoint_interval: NonZeroUsize::new(3),
+        ..Default::default()
+    };
+    let mut output_bytes = Cursor::new(Vec::<u8>::new());
+    let report = Moneybags {
+        max_rows: Some(5),
+        ..moneybags.clone()
+    }
+    .run(&mut output_bytes)
+    .unwrap();
+    assert_eq!(report.rows, 5);
+    assert!(checkpoint_file.exists());
+
+    // The rows before the checkpoint, including the skipped withdrawal, are not processed again.
+    let mut output_bytes = Cursor::new(Vec::<u8>::new());
+    let report = Moneybags {
+        resume: true,
+        ..moneybags.clone()
+    }
+    .run(&mut output_bytes)
+    .unwrap();
+    assert_eq!(report.rows, 8);
+    assert_eq!(
+        report.skipped,
+        btreemap! { ErrorKind::InsufficientFunds => 1 }
+    );
+    let expected = r#"client,available,held,total,locked
+1,-2.5000,0.0000,-2.5000,true
+2,5.2500,0.0000,5.2500,false
+"#;
+    assert_eq!(
+        String::from_utf8(output_bytes.into_inner()).unwrap(),
+        expected
+    );
+    assert_eq!(run("checkpoint.csv"), expected);
+
+    // A checkpoint, which is now of the seventh row, can not be resumed with different input.
+    let error = Moneybags {
+        csv_file: vec![path("given-example.csv")],
+        resume: true,
+        ..moneybags
+    }
+    .run(Cursor::new(Vec::<u8>::new()))
+    .unwrap_err();
+    assert_eq!(error.to_string(), "The input ends before the checkpoint");
+}
+
+/// Every skipped row is written to the errors file, with its line, tx, client and the kind of error.
+#[test]
+fn errors_file() {
+    let errors_file = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("errors_file.csv");
+    let skipped = output(Moneybags {
+        csv_file: vec![path("malformed.csv")],
+        errors: Some(errors_file.clone()),
+        ..Default::default()
+    });
+    assert_eq!(skipped, run("malformed.csv"));
+    let expected = r#"line,tx,client,kind,message
+3,,,parse,"CSV deserialize error: record 2 (line: 3, byte: 38): Invalid decimal: unknown character"
+5,4,2,insufficient_funds,Withdrawal failed. Available funds insufficient.
+"#;
+    assert_eq!(std::fs::read_to_string(&errors_file).unwrap(), expected);
+
+    // The row that stops strict processing is written too.
+    let mut output_bytes = Cursor::new(Vec::<u8>::new());
+    Moneybags {
+        csv_file: vec![path("overdraw.csv")],
+        strict: true,
+        errors: Some(errors_file.clone()),
+        ..Default::default()
+    }
+    .run(&mut output_bytes)
+    .unwrap_err();
+    let errors = std::fs::read_to_string(&errors_file).unwrap();
+    assert_eq!(errors.lines().count(), 2);
+    assert!(errors.contains(",insufficient_funds,"));
+}
+
+/// The SHA-256 digest of given-example.csv.
+const GIVEN_EXAMPLE_CHECKSUM: &str =
+    "be4ee0b059804b7aecc5d56fa88cb3dc12c68fc9f55754909cf5a7f4e47a0801";
+
+/// Processing proceeds when the input matches the expected checksum, and the checksum can be
+/// printed to stderr.
+#[test]
+fn checksum_matches() {
+    let output = Command::new(env!("CARGO_BIN_EXE_moneybags"))
+        .arg(path("given-example.csv"))
+        .arg("--verify-checksum")
+        .arg(GIVEN_EXAMPLE_CHECKSUM.to_uppercase())
+        .arg("--print-checksum")
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(2));
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        run("given-example.csv")
+    );
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(
+        stderr.lines().any(|line| line == GIVEN_EXAMPLE_CHECKSUM),
+        "{}",
+        stderr
+    );
+}
+
+/// Processing fails, without any output, when the input does not match the expected checksum, or
+/// when it is several files, which can not be hashed as they are.
+#[test]
+fn checksum_mismatch() {
+    let mut output_bytes = Cursor::new(Vec::<u8>::new());
+    let err = Moneybags {
+        csv_file: vec![path("given-example-padded.csv")],
+        verify_checksum: Some(GIVEN_EXAMPLE_CHECKSUM.to_string()),
+        ..Default::default()
+    }
+    .run(&mut output_bytes)
+    .unwrap_err();
+    assert!(err.to_string().contains("does not match"), "{}", err);
+    assert!(output_bytes.into_inner().is_empty());
+
+    // Several files are not hashed as they are, so they can not be verified.
+    let mut output_bytes = Cursor::new(Vec::<u8>::new());
+    let err = Moneybags {
+        csv_file: vec![path("given-example.csv"), path("given-example.csv")],
+        verify_checksum: Some(GIVEN_EXAMPLE_CHECKSUM.to_string()),
+        ..Default::default()
+    }
+    .run(&mut output_bytes)
+    .unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "The checksum of the input can only be printed or verified for a single file"
+    );
+    assert!(output_bytes.into_inner().is_empty());
+}
+
+/// A withdrawal may take available funds down to exactly minus the overdraft limit, but no further.
+#[test]
+fn overdraft_limit() {
+    let input = std::fs::read_to_string(path("overdraft.csv")).unwrap();
+    let options = ProcessingOptions {
+        overdraft_limit: Decimal::new(25, 1),
+        ..strict()
+    };
+    let err = process_records(Cursor::new(&input), &options).unwrap_err();
+    assert!(matches!(
+        err.downcast_ref::<ProcessError>().unwrap(),
+        ProcessError::InsufficientFunds { client: 2, tx: 4 }
+    ));
+
+    let options = ProcessingOptions {
+        overdraft_limit: Decimal::new(25, 1),
+        ..Default::default()
+    };
+    let result = process_records(Cursor::new(&input), &options).unwrap();
+    assert_eq!(
+        result.report.skipped,
+        btreemap! {ErrorKind::InsufficientFunds => 1}
+    );
+
+    let output = Command::new(env!("CARGO_BIN_EXE_moneybags"))
+        .arg(path("overdraft.csv"))
+        .arg("--overdraft-limit")
+        .arg("2.5")
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(2));
+    let expected = r#"client,available,held,total,locked
+1,-2.5000,0.0000,-2.5000,false
+2,1.0000,0.0000,1.0000,false
+"#;
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), expected);
+}
+
+/// A negative overdraft limit is rejected when parsing arguments.
+#[test]
+fn overdraft_limit_negative() {
+    let output = Command::new(env!("CARGO_BIN_EXE_moneybags"))
+        .arg(path("overdraft.csv"))
+        .arg("--overdraft-limit")
+        .arg("-1")
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    assert!(output.stdout.is_empty());
+}
+
+/// Only rows with a tx in the inclusive range given by `--since-tx` and `--until-tx` are processed,
+/// and rows outside of it are not counted as skipped.
+#[test]
+fn tx_window() {
+    let output = Command::new(env!("CARGO_BIN_EXE_moneybags"))
+        .arg(path("tx-window.csv"))
+        .arg("--since-tx")
+        .arg("2")
+        .arg("--until-tx")
+        .arg("3")
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(0));
+    let expected = r#"client,available,held,total,locked
+1,4.0000,2.0000,6.0000,false
+"#;
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), expected);
+
+    let output = self::output(Moneybags {
+        csv_file: vec![path("tx-window.csv")],
+        since_tx: Some(4),
+        ..Default::default()
+    });
+    let expected = r#"client,available,held,total,locked
+1,0.0000,8.0000,8.0000,false
+"#;
+    assert_eq!(output, expected);
+
+    let output = self::output(Moneybags {
+        csv_file: vec![path("tx-window.csv")],
+        until_tx: Some(1),
+        ..Default::default()
+    });
     let expected = r#"client,available,held,total,locked
-1,3.4,0.0,3.4,false
-2,1.4999,0.0,1.4999,true
+1,1.0000,0.0000,1.0000,false
 "#;
     assert_eq!(output, expected);
 }
+
+/// Clients are output in ascending order of ID regardless of the order in which they appear in the
+/// input.
+#[test]
+fn output_ordering() {
+    let descending = (1..=20).rev().collect::<Vec<u32>>();
+    // A fixed permutation of 1 through 20.
+    let shuffled = (1..=20).map(|i| i * 7 % 20 + 1).collect::<Vec<u32>>();
+    let expected = (1..=20).map(|id| id.to_string()).collect::<Vec<_>>();
+    for ids in [descending, shuffled] {
+        let input = ids
+            .iter()
+            .fold("type,client,tx,amount\n".to_string(), |input, id| {
+                input + &format!("deposit,{},{},1.0\n", id, id)
+            });
+        for threads in [None, NonZeroUsize::new(3)] {
+            let mut output_bytes = Cursor::new(Vec::<u8>::new());
+            Moneybags {
+                threads,
+                ..Default::default()
+            }
+            .run_reader(Cursor::new(&input), &mut output_bytes)
+            .unwrap();
+            let output = String::from_utf8(output_bytes.into_inner()).unwrap();
+            let client_ids = output
+                .lines()
+                .skip(1)
+                .map(|line| line.split(',').next().unwrap().to_string())
+                .collect::<Vec<_>>();
+            assert_eq!(client_ids, expected);
+        }
+    }
+}
+
+/// Validation reports the line of each row that can not be parsed, and fails without writing the
+/// account summary.
+#[test]
+fn validate() {
+    let f = std::fs::File::open(path("malformed.csv")).unwrap();
+    let report = validate_records(f, &ProcessingOptions::default()).unwrap();
+    assert_eq!(report.rows, 4);
+    assert!(!report.is_valid());
+    assert_eq!(report.errors.len(), 1);
+    assert_eq!(report.errors[0].line, 3);
+    assert_eq!(report.errors[0].kind, ErrorKind::Parse);
+
+    let output = Command::new(env!("CARGO_BIN_EXE_moneybags"))
+        .arg(path("malformed.csv"))
+        .arg("--validate")
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(1));
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.starts_with("line 3: "), "{}", stdout);
+    assert!(
+        stdout.ends_with("FAIL: 1 of 4 rows are invalid\n"),
+        "{}",
+        stdout
+    );
+    assert!(!stdout.contains("client,available"), "{}", stdout);
+
+    // Insufficient funds are not checked, but references to other transactions are.
+    let f = std::fs::File::open(path("invalid-references.csv")).unwrap();
+    let report = validate_records(f, &ProcessingOptions::default()).unwrap();
+    let errors = report
+        .errors
+        .iter()
+        .map(|error| (error.line, error.kind))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        errors,
+        vec![(3, ErrorKind::ClientMismatch), (4, ErrorKind::UnknownTx)]
+    );
+
+    let output = Command::new(env!("CARGO_BIN_EXE_moneybags"))
+        .arg(path("given-example.csv"))
+        .arg("--validate")
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "PASS: 5 rows are valid\n"
+    );
+}
+
+/// Rows that can not be parsed or processed are identified by their line and byte offset, both in
+/// the error returned in strict mode and in the messages written to stderr otherwise.
+#[test]
+fn error_line_numbers() {
+    let f = std::fs::File::open(path("malformed.csv")).unwrap();
+    let err = process_records(f, &strict()).unwrap_err();
+    assert_eq!(
+        err.downcast_ref::<RowError>(),
+        Some(&RowError {
+            kind: ErrorKind::Parse,
+            line: Some(3),
+            byte: Some(38),
+        })
+    );
+
+    let input = r#"type,client,tx,amount
+deposit,1,1,1.0
+withdrawal,1,2,3.0
+"#;
+    let err =
+        process_records_threaded(Cursor::new(input), &strict(), NonZeroUsize::MIN).unwrap_err();
+    let row_error = err.downcast_ref::<RowError>().unwrap();
+    assert_eq!(row_error.kind, ErrorKind::InsufficientFunds);
+    assert_eq!(row_error.line, Some(3));
+
+    // Records that were not read from CSV input have no position.
+    let records = [Record::new(RecordType::Withdrawal, 1, 1, Decimal::ONE)];
+    let err = process(records, &strict()).unwrap_err();
+    assert_eq!(err.downcast_ref::<RowError>().unwrap().line, None);
+    assert!(
+        err.to_string().starts_with("Error processing record"),
+        "{}",
+        err
+    );
+
+    let output = Command::new(env!("CARGO_BIN_EXE_moneybags"))
+        .arg(path("malformed.csv"))
+        .output()
+        .unwrap();
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(
+        stderr.contains("Error parsing csv line 3 (byte 38): "),
+        "{}",
+        stderr
+    );
+    assert!(
+        stderr.contains("Error processing record on line 5 (byte 70): "),
+        "{}",
+        stderr
+    );
+}
+
+/// A transfer moves funds from one client to another, and is skipped if the target client is
+/// missing.
+#[test]
+fn transfer() {
+    let f = std::fs::File::open(path("transfers.csv")).unwrap();
+    let report = process_records(f, &ProcessingOptions::default())
+        .unwrap()
+        .report;
+    assert_eq!(
+        report.skipped,
+        btreemap! {
+            ErrorKind::InsufficientFunds => 1,
+            ErrorKind::MissingTargetClient => 1,
+        }
+    );
+    let output = run("transfers.csv");
+    let expected = r#"client,available,held,total,locked
+1,3.0000,0.0000,3.0000,false
+2,3.0000,0.0000,3.0000,false
+"#;
+    assert_eq!(output, expected);
+
+    // Transfers can not be processed across worker threads.
+    let f = std::fs::File::open(path("transfers.csv")).unwrap();
+    let report = process_records_threaded(f, &ProcessingOptions::default(), NonZeroUsize::MIN)
+        .unwrap()
+        .report;
+    assert_eq!(
+        report.skipped,
+        btreemap! { ErrorKind::UnsupportedTransfer => 3 }
+    );
+}
+
+/// A transfer larger than the source client's available funds changes neither client.
+#[test]
+fn transfer_insufficient_funds() {
+    let records = [
+        Record::new(RecordType::Deposit, 1, 1, Decimal::new(5, 0)),
+        Record::transfer(1, 2, 2, Decimal::new(6, 0)),
+    ];
+    let err = process(records, &strict()).unwrap_err();
+    assert!(matches!(
+        err.downcast_ref::<ProcessError>().unwrap(),
+        ProcessError::InsufficientFunds { client: 1, tx: 2 }
+    ));
+
+    let clients = process(records, &ProcessingOptions::default())
+        .unwrap()
+        .clients;
+    assert_eq!(
+        clients,
+        process(records[..1].to_vec(), &ProcessingOptions::default())
+            .unwrap()
+            .clients
+    );
+}
+
+/// A deposit for an account locked by a chargeback is handled according to the locked policy.
+#[test]
+fn locked_policy() {
+    let locked = |locked_policy| {
+        let f = std::fs::File::open(path("locked-deposit.csv")).unwrap();
+        let options = ProcessingOptions {
+            locked_policy,
+            ..Default::default()
+        };
+        let report = process_records(f, &options).unwrap().report;
+        let output = output(Moneybags {
+            csv_file: vec![path("locked-deposit.csv")],
+            locked_policy,
+            ..Default::default()
+        });
+        (report.skipped, output)
+    };
+
+    let (skipped, output) = locked(LockedPolicy::Reject);
+    assert_eq!(skipped, btreemap! { ErrorKind::Locked => 1 });
+    let expected = r#"client,available,held,total,locked
+1,0.0000,0.0000,0.0000,true
+"#;
+    assert_eq!(output, expected);
+
+    let (skipped, output) = locked(LockedPolicy::Ignore);
+    assert!(skipped.is_empty());
+    assert_eq!(output, expected);
+
+    let (skipped, output) = locked(LockedPolicy::Process);
+    assert!(skipped.is_empty());
+    let expected = r#"client,available,held,total,locked
+1,3.0000,0.0000,3.0000,true
+"#;
+    assert_eq!(output, expected);
+
+    // Ignored records are not an error in strict mode.
+    let f = std::fs::File::open(path("locked-deposit.csv")).unwrap();
+    let options = ProcessingOptions {
+        locked_policy: LockedPolicy::Ignore,
+        ..strict()
+    };
+    assert!(process_records(f, &options).is_ok());
+    let f = std::fs::File::open(path("locked-deposit.csv")).unwrap();
+    let err = process_records(f, &strict()).unwrap_err();
+    assert!(matches!(
+        err.downcast_ref::<ProcessError>().unwrap(),
+        ProcessError::Locked { client: 1 }
+    ));
+}
+
+/// An unlock record unlocks a locked account when unlocking is allowed, and is skipped otherwise.
+#[test]
+fn unlock() {
+    let output = output(Moneybags {
+        csv_file: vec![path("unlock.csv")],
+        allow_unlock: true,
+        ..Default::default()
+    });
+    let expected = r#"client,available,held,total,locked
+1,3.0000,0.0000,3.0000,false
+"#;
+    assert_eq!(output, expected);
+
+    let f = std::fs::File::open(path("unlock.csv")).unwrap();
+    let report = process_records(f, &ProcessingOptions::default())
+        .unwrap()
+        .report;
+    assert_eq!(
+        report.skipped,
+        btreemap! {
+            ErrorKind::UnlockNotAllowed => 1,
+            ErrorKind::Locked => 1,
+        }
+    );
+    let output = run("unlock.csv");
+    let expected = r#"client,available,held,total,locked
+1,0.0000,0.0000,0.0000,true
+"#;
+    assert_eq!(output, expected);
+}
+
+/// A dispute with an amount holds only that part of the disputed transaction, and a resolve releases
+/// what the dispute held. A dispute for more than the transaction is skipped.
+#[test]
+fn partial_dispute() {
+    let f = std::fs::File::open(path("partial-dispute.csv")).unwrap();
+    let report = process_records(f, &ProcessingOptions::default())
+        .unwrap()
+        .report;
+    assert_eq!(
+        report.skipped,
+        btreemap! { ErrorKind::DisputeTooLarge => 1 }
+    );
+
+    let input = r#"type,client,tx,amount
+deposit,1,1,10.0
+dispute,1,1,4.0
+"#;
+    let mut output_bytes = Cursor::new(Vec::<u8>::new());
+    Moneybags::default()
+        .run_reader(Cursor::new(input), &mut output_bytes)
+        .unwrap();
+    let expected = r#"client,available,held,total,locked
+1,6.0000,4.0000,10.0000,false
+"#;
+    assert_eq!(
+        String::from_utf8(output_bytes.into_inner()).unwrap(),
+        expected
+    );
+
+    let output = run("partial-dispute.csv");
+    let expected = r#"client,available,held,total,locked
+1,14.0000,0.0000,14.0000,false
+"#;
+    assert_eq!(output, expected);
+}
+
+/// The balance check catches a client whose funds do not add up, and processing with
+/// `--check-invariants` succeeds when every client stays in balance.
+#[test]
+fn check_invariants() {
+    let imbalanced: Client = serde_json::from_str(
+        r#"{"client": 7, "available": "1.5", "held": "1.0", "total": "3.0", "locked": false}"#,
+    )
+    .unwrap();
+    let err = imbalanced.check_balance().unwrap_err();
+    assert!(matches!(err, ProcessError::Imbalance { client: 7, .. }));
+    assert_eq!(
+        err.to_string(),
+        "Client 7 is out of balance: available 1.5 + held 1.0 != total 3.0"
+    );
+    assert!(Client::new(7).check_balance().is_ok());
+
+    for filename in [
+        "resolve-and-chargeback.csv",
+        "dispute-withdrawal.csv",
+        "partial-dispute.csv",
+        "transfers.csv",
+    ] {
+        let f = std::fs::File::open(path(filename)).unwrap();
+        let options = ProcessingOptions {
+            check_invariants: true,
+            ..Default::default()
+        };
+        assert!(process_records(f, &options).is_ok(), "{}", filename);
+    }
+}
+
+/// The currency scale sets the number of places past the decimal in the output, and amounts with
+/// more places are skipped.
+#[test]
+fn currency_scale() {
+    let scaled = |scale| {
+        let f = std::fs::File::open(path("currency-scale.csv")).unwrap();
+        let options = ProcessingOptions {
+            scale,
+            ..Default::default()
+        };
+        let report = process_records(f, &options).unwrap().report;
+        let output = output(Moneybags {
+            csv_file: vec![path("currency-scale.csv")],
+            currency_scale: CurrencyScale(scale),
+            ..Default::default()
+        });
+        (
+            report.skipped.get(&ErrorKind::ExcessPrecision).copied(),
+            output,
+        )
+    };
+
+    let (skipped, output) = scaled(0);
+    assert_eq!(skipped, Some(2));
+    let expected = r#"client,available,held,total,locked
+1,0,0,0,false
+2,0,0,0,false
+3,100,0,100,false
+"#;
+    assert_eq!(output, expected);
+
+    let (skipped, output) = scaled(2);
+    assert_eq!(skipped, Some(1));
+    let expected = r#"client,available,held,total,locked
+1,1.50,0.00,1.50,false
+2,0.00,0.00,0.00,false
+3,100.00,0.00,100.00,false
+"#;
+    assert_eq!(output, expected);
+
+    let (skipped, output) = scaled(8);
+    assert_eq!(skipped, None);
+    let expected = r#"client,available,held,total,locked
+1,1.50000000,0.00000000,1.50000000,false
+2,1.23456789,0.00000000,1.23456789,false
+3,100.00000000,0.00000000,100.00000000,false
+"#;
+    assert_eq!(output, expected);
+
+    assert!("29".parse::<CurrencyScale>().is_err());
+}
+
+/// A chargeback is skipped, without locking the account, unless its transaction is currently
+/// disputed. Client 1's dispute has already been resolved and client 2's transaction was never
+/// disputed.
+#[test]
+fn chargeback_not_disputed() {
+    let f = std::fs::File::open(path("chargeback-after-resolve.csv")).unwrap();
+    let report = process_records(f, &ProcessingOptions::default())
+        .unwrap()
+        .report;
+    assert_eq!(report.skipped, btreemap! { ErrorKind::NotDisputed => 2 });
+    let output = run("chargeback-after-resolve.csv");
+    let expected = r#"client,available,held,total,locked
+1,10.0000,0.0000,10.0000,false
+2,5.0000,0.0000,5.0000,false
+"#;
+    assert_eq!(output, expected);
+}
+
+/// A gzip-compressed input is decompressed, either because of its `.gz` extension or because of the
+/// `--gzip` flag, and gives the same output as the uncompressed input.
+#[test]
+fn gzip() {
+    let expected = run("given-example.csv");
+    assert_eq!(run("given-example.csv.gz"), expected);
+
+    let output = Command::new(env!("CARGO_BIN_EXE_moneybags"))
+        .arg("--gzip")
+        .stdin(std::fs::File::open(path("given-example.csv.gz")).unwrap())
+        .output()
+        .unwrap();
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), expected);
+}
+
+/// A UTF-8 byte order mark, which would otherwise become part of the `type` header, is removed, and
+/// input in another encoding is decoded. The Windows-1252 example is padded with non-breaking
+/// spaces, byte 0xA0, which is not valid UTF-8.
+#[test]
+fn encoding() {
+    let expected = run("given-example.csv");
+    assert_eq!(run("given-example-bom.csv"), expected);
+
+    let windows_1252 = || Moneybags {
+        csv_file: vec![path("given-example-windows-1252.csv")],
+        ..Default::default()
+    };
+    let error = windows_1252()
+        .run(&mut Cursor::new(Vec::new()))
+        .unwrap_err();
+    assert!(error.to_string().contains("header"), "{}", error);
+    assert_eq!(
+        output(Moneybags {
+            encoding: "latin1".parse().unwrap(),
+            ..windows_1252()
+        }),
+        expected
+    );
+    assert_eq!(
+        "windows-1252".parse::<InputEncoding>().unwrap(),
+        "latin1".parse().unwrap()
+    );
+    assert!("klingon".parse::<InputEncoding>().is_err());
+}
+
+/// Files saved by Excel on Windows, which start with a UTF-8 byte order mark and have CRLF line
+/// endings, are parsed without skipping any rows, both as input and as `--state`.
+#[test]
+fn excel_files() {
+    let f = std::fs::File::open(path("given-example-excel.csv")).unwrap();
+    let result = process_records(f, &ProcessingOptions::default()).unwrap();
+    assert_eq!(
+        result.report.skipped,
+        btreemap! { ErrorKind::InsufficientFunds => 1 }
+    );
+    let f = std::fs::File::open(path("given-example.csv")).unwrap();
+    let expected = process_records(f, &ProcessingOptions::default()).unwrap();
+    assert_eq!(result.clients, expected.clients);
+
+    let with_state = |state| {
+        output(Moneybags {
+            csv_file: vec![path("given-example.csv")],
+            state: Some(path(state)),
+            ..Default::default()
+        })
+    };
+    assert_eq!(with_state("state-excel.csv"), with_state("state.csv"));
+}
+
+/// Multiple files are processed as one, so a transaction in one file can be disputed in another.
+/// The header of the second file is skipped, and the first file does not need to end with a
+/// newline.
+#[test]
+fn multiple_files() {
+    let output = output(Moneybags {
+        csv_file: vec![path("multiple-files-1.csv"), path("multiple-files-2.csv")],
+        ..Default::default()
+    });
+    let expected = r#"client,available,held,total,locked
+1,0.0000,10.0000,10.0000,false
+2,4.0000,0.0000,4.0000,false
+"#;
+    assert_eq!(output, expected);
+
+    let output = Command::new(env!("CARGO_BIN_EXE_moneybags"))
+        .arg(path("multiple-files-1.csv"))
+        .arg(path("multiple-files-2.csv"))
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), expected);
+}
+
+/// With `--client`, only the accounts of the given clients are output.
+#[test]
+fn client_filter() {
+    let output = output(Moneybags {
+        csv_file: vec![path("resolve-and-chargeback.csv")],
+        clients: vec![2],
+        ..Default::default()
+    });
+    let expected = r#"client,available,held,total,locked
+2,1.4999,0.0000,1.4999,true
+"#;
+    assert_eq!(output, expected);
+
+    let output = Command::new(env!("CARGO_BIN_EXE_moneybags"))
+        .arg(path("cross-client.csv"))
+        .args(["--client", "2", "--client", "3"])
+        .output()
+        .unwrap();
+    let expected = r#"client,available,held,total,locked
+2,6.0000,0.0000,6.0000,false
+"#;
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), expected);
+}
+
+/// With `--input-format jsonl`, each line of the input is a JSON record. Blank lines are ignored,
+/// and lines that cannot be parsed are skipped.
+#[test]
+fn jsonl_input() {
+    let output = output(Moneybags {
+        csv_file: vec![path("given-example.jsonl")],
+        input_format: InputFormat::Jsonl,
+        ..Default::default()
+    });
+    assert_eq!(output, run("given-example.csv"));
+
+    let input = r#"{"type": "deposit", "client": 1, "tx": 1, "amount": "1.0"}
+{"type": "deposit", "client": 1, "tx": 2, "amount": 2.0}
+{"type": "deposit", "client": 1, "tx": 3
+"#;
+    let options = ProcessingOptions {
+        input_format: InputFormat::Jsonl,
+        ..Default::default()
+    };
+    let result = process_records(input.as_bytes(), &options).unwrap();
+    assert_eq!(result.clients.len(), 1);
+    assert_eq!(result.report.skipped, btreemap! { ErrorKind::Parse => 2 });
+
+    let error = process_records(
+        input.as_bytes(),
+        &ProcessingOptions {
+            strict: true,
+            ..options
+        },
+    )
+    .unwrap_err();
+    let row_error = error.downcast_ref::<RowError>().unwrap();
+    assert_eq!(row_error.kind, ErrorKind::Parse);
+    assert_eq!(row_error.line, Some(2));
+    assert_eq!(row_error.byte, Some(59));
+}
+
+/// With `--stats`, a line of `key=value` counts and timings is written to stderr, in which ignored
+/// rows are not counted as processed.
+#[test]
+fn stats() {
+    let output = Command::new(env!("CARGO_BIN_EXE_moneybags"))
+        .arg(path("given-example.csv"))
+        .arg("--stats")
+        .output()
+        .unwrap();
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        run("given-example.csv")
+    );
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    let line = stderr
+        .lines()
+        .find_map(|line| line.strip_prefix("stats: "))
+        .unwrap();
+    let stats = line
+        .split(' ')
+        .map(|pair| pair.split_once('=').unwrap())
+        .collect::<std::collections::BTreeMap<_, _>>();
+    assert_eq!(stats["rows"], "5");
+    assert_eq!(stats["processed"], "4");
+    assert_eq!(stats["skipped"], "1");
+    assert_eq!(stats["ignored"], "0");
+    assert!(stats["elapsed_secs"].parse::<f64>().unwrap() >= 0.0);
+    assert!(stats["rows_per_sec"].parse::<f64>().unwrap() >= 0.0);
+
+    // Rows outside of the tx range are ignored, not processed.
+    let output = Command::new(env!("CARGO_BIN_EXE_moneybags"))
+        .arg(path("given-example.csv"))
+        .arg("--stats")
+        .arg("--since-tx")
+        .arg("3")
+        .output()
+        .unwrap();
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(
+        stderr.contains("stats: rows=5 processed=2 skipped=1 ignored=2 "),
+        "{}",
+        stderr
+    );
+}
+
+/// A dispute, resolve or chargeback can have its own tx and reference the disputed transaction with
+/// `reference_tx`. Without one, `tx` is the referenced transaction.
+#[test]
+fn reference_tx() {
+    let expected = r#"client,available,held,total,locked
+1,10.0000,0.0000,10.0000,true
+2,0.0000,3.0000,3.0000,false
+"#;
+    assert_eq!(run("reference-tx.csv"), expected);
+
+    let records = vec![
+        Record::new(RecordType::Deposit, 1, 1, Decimal::new(10, 0)),
+        Record::referencing(RecordType::Dispute, 1, 2, 1, Decimal::new(4, 0)),
+        // The dispute's own tx can not be disputed.
+        Record::referencing(RecordType::Dispute, 1, 3, 2, Decimal::ZERO),
+        Record::referencing(RecordType::Chargeback, 1, 4, 1, Decimal::ZERO),
+    ];
+    let result = process(records, &ProcessingOptions::default()).unwrap();
+    let expected: Client = serde_json::from_str(
+        r#"{"client": 1, "available": "6", "held": "0", "total": "6", "locked": true}"#,
+    )
+    .unwrap();
+    assert_eq!(result.clients, vec![expected]);
+    assert_eq!(
+        result.report.skipped,
+        btreemap! { ErrorKind::UnknownTx => 1 }
+    );
+}
+
+/// With `--max-rows`, processing stops once that many rows have been read, counting rows that were
+/// skipped, and the accounts are output as they were at that point.
+#[test]
+fn max_rows() {
+    let output = Command::new(env!("CARGO_BIN_EXE_moneybags"))
+        .arg(path("max-rows.csv"))
+        .args(["--max-rows", "3"])
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(2));
+    let expected = r#"client,available,held,total,locked
+1,1.0000,0.0000,1.0000,false
+2,2.0000,0.0000,2.0000,false
+"#;
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), expected);
+    assert!(String::from_utf8(output.stderr)
+        .unwrap()
+        .contains("stopped after reading 3 rows"));
+
+    for threads in [None, NonZeroUsize::new(2)] {
+        let options = ProcessingOptions {
+            max_rows: Some(4),
+            ..Default::default()
+        };
+        let input = std::fs::File::open(path("max-rows.csv")).unwrap();
+        let result = match threads {
+            Some(threads) => process_records_threaded(input, &options, threads),
+            None => process_records(input, &options),
+        }
+        .unwrap();
+        assert_eq!(result.report.rows, 4);
+        assert_eq!(result.clients.len(), 2);
+    }
+}
+
+/// A CSV header with a misspelled, missing or duplicate column is an error before any row is read,
+/// but a column that is not part of the schema is ignored.
+#[test]
+fn invalid_header() {
+    let input = "type,client,tx,ammount\ndeposit,1,1,1.0\n";
+    let err = process_records(input.as_bytes(), &ProcessingOptions::default()).unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "Missing column 'amount' in the CSV header, expected the columns type, client, tx, \
+         amount in any order, optionally with target_client, reference_tx, timestamp"
+    );
+
+    for (input, message) in [
+        ("client,tx,amount\n", "Missing column 'type'"),
+        ("type,client,tx,amount,tx\n", "Duplicate column 'tx'"),
+    ] {
+        let err = process_records(input.as_bytes(), &ProcessingOptions::default()).unwrap_err();
+        assert!(err.to_string().starts_with(message), "{}", err);
+        let err = validate_records(input.as_bytes(), &ProcessingOptions::default()).unwrap_err();
+        assert!(err.to_string().starts_with(message), "{}", err);
+    }
+
+    // A column that is not part of the schema is ignored.
+    let input = "type,client,tx,amount,memo\ndeposit,1,1,1.0,rent\n";
+    let result = process_records(input.as_bytes(), &strict()).unwrap();
+    assert_eq!(result.report.rows, 1);
+
+    // The order of the columns does not matter.
+    let input = "amount,tx,type,client\n1.0,1,deposit,1\n";
+    let result = process_records(input.as_bytes(), &strict()).unwrap();
+    assert_eq!(result.report.rows, 1);
+}
+
+/// A withdrawal of more than the available funds is skipped by default, but with
+/// `--overdraw-policy drain` it withdraws what is available, and that amount is audited and can be
+/// disputed.
+#[test]
+fn overdraw_policy() {
+    let rejected = output(Moneybags {
+        csv_file: vec![path("overdraw.csv")],
+        overdraw_policy: OverdrawPolicy::Reject,
+        ..Default::default()
+    });
+    let expected = r#"client,available,held,total,locked
+1,3.0000,0.0000,3.0000,false
+"#;
+    assert_eq!(rejected, expected);
+
+    let audit_file = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("overdraw_policy.csv");
+    let drained = output(Moneybags {
+        csv_file: vec![path("overdraw.csv")],
+        overdraw_policy: OverdrawPolicy::Drain,
+        audit: Some(audit_file.clone()),
+        ..Default::default()
+    });
+    let expected = r#"client,available,held,total,locked
+1,0.0000,3.0000,3.0000,false
+"#;
+    assert_eq!(drained, expected);
+    let expected = r#"tx,client,type,amount,available,held,total,status
+1,1,deposit,3.0,3.0000,0.0000,3.0000,applied
+2,1,withdrawal,3.0,0.0000,0.0000,0.0000,applied
+2,1,dispute,,0.0000,3.0000,3.0000,applied
+"#;
+    assert_eq!(std::fs::read_to_string(&audit_file).unwrap(), expected);
+
+    // With an overdraft limit, a drained withdrawal takes the available funds to the limit.
+    let records = vec![
+        Record::new(RecordType::Deposit, 1, 1, Decimal::new(3, 0)),
+        Record::new(RecordType::Withdrawal, 1, 2, Decimal::new(5, 0)),
+    ];
+    let options = ProcessingOptions {
+        overdraft_limit: Decimal::ONE,
+        overdraw_policy: OverdrawPolicy::Drain,
+        ..strict()
+    };
+    let result = process(records, &options).unwrap();
+    let expected: Client = serde_json::from_str(
+        r#"{"client": 1, "available": "-1", "held": "0", "total": "-1", "locked": false}"#,
+    )
+    .unwrap();
+    assert_eq!(result.clients, vec![expected]);
+}
+
+/// Transactions whose disputes are never settled are listed as open disputes, along with the
+/// amount that they hold.
+#[test]
+fn open_disputes() {
+    let records = vec![
+        Record::new(RecordType::Deposit, 1, 1, Decimal::new(10, 0)),
+        Record::new(RecordType::Deposit, 2, 2, Decimal::new(5, 0)),
+        Record::new(RecordType::Deposit, 2, 3, Decimal::new(7, 0)),
+        Record::new(RecordType::Deposit, 1, 4, Decimal::new(2, 0)),
+        Record::new(RecordType::Dispute, 1, 1, Decimal::new(4, 0)),
+        Record::new(RecordType::Dispute, 2, 2, Decimal::ZERO),
+        Record::new(RecordType::Dispute, 2, 3, Decimal::ZERO),
+        Record::new(RecordType::Resolve, 2, 2, Decimal::ZERO),
+    ];
+    let expected = vec![
+        OpenDispute {
+            tx: 1,
+            client: 1,
+            amount: Decimal::new(4, 0),
+        },
+        OpenDispute {
+            tx: 3,
+            client: 2,
+            amount: Decimal::new(7, 0),
+        },
+    ];
+    let result = process(records, &strict()).unwrap();
+    assert_eq!(result.open_disputes, expected);
+
+    let input = "type,client,tx,amount\n\
+                 deposit,1,1,10.0\n\
+                 deposit,2,3,7.0\n\
+                 dispute,2,3,\n\
+                 dispute,1,1,4.0\n";
+    let result =
+        process_records_threaded(input.as_bytes(), &strict(), NonZeroUsize::new(2).unwrap())
+            .unwrap();
+    assert_eq!(result.open_disputes, expected);
+}
+
+/// A midpoint is rounded differently by each rounding mode, and the mode can be chosen with
+/// `--rounding`.
+#[test]
+fn rounding() {
+    let positive = Decimal::new(100005, 5);
+    let negative = Decimal::new(-100005, 5);
+    for (mode, expected_positive, expected_negative) in [
+        (RoundingMode::MidpointNearestEven, 10000, -10000),
+        (RoundingMode::MidpointAwayFromZero, 10001, -10001),
+        (RoundingMode::MidpointTowardZero, 10000, -10000),
+        (RoundingMode::ToZero, 10000, -10000),
+        (RoundingMode::AwayFromZero, 10001, -10001),
+        (RoundingMode::ToNegativeInfinity, 10000, -10001),
+        (RoundingMode::ToPositiveInfinity, 10001, -10000),
+    ] {
+        assert_eq!(
+            mode.round(positive, 4),
+            Decimal::new(expected_positive, 4),
+            "{}",
+            mode
+        );
+        assert_eq!(
+            mode.round(negative, 4),
+            Decimal::new(expected_negative, 4),
+            "{}",
+            mode
+        );
+    }
+    // Banker's rounding goes to the even digit, which is up from an odd one.
+    assert_eq!(
+        RoundingMode::MidpointNearestEven.round(Decimal::new(100015, 5), 4),
+        Decimal::new(10002, 4)
+    );
+
+    let output = Command::new(env!("CARGO_BIN_EXE_moneybags"))
+        .arg(path("given-example.csv"))
+        .args(["--rounding", "midpoint-away-from-zero"])
+        .output()
+        .unwrap();
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        run("given-example.csv")
+    );
+}
+
+/// The clients from separate runs can be merged, unless a client is in more than one of them.
+#[test]
+fn merge_results() {
+    let first = process_records(
+        std::fs::File::open(path("given-example.csv")).unwrap(),
+        &ProcessingOptions::default(),
+    )
+    .unwrap()
+    .clients;
+    let second = process(
+        vec![
+            Record::new(RecordType::Deposit, 5, 1, Decimal::new(3, 0)),
+            Record::new(RecordType::Deposit, 3, 2, Decimal::new(4, 0)),
+        ],
+        &strict(),
+    )
+    .unwrap()
+    .clients;
+    let merged = merge(vec![second.clone(), first.clone()]).unwrap();
+    assert_eq!(
+        merged,
+        vec![first[0], first[1], second[0], second[1]],
+        "clients are ordered by ID"
+    );
+
+    let overlapping = vec![Client::new(2)];
+    let err = merge(vec![first, second, overlapping]).unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "Client 2 appears in more than one result, including result 3 of them"
+    );
+}
+
+/// With `--omit-empty`, accounts without funds that are not locked are not output, such as that of
+/// a client whose only transaction failed.
+#[test]
+fn omit_empty() {
+    let expected = r#"client,available,held,total,locked
+1,1.0000,0.0000,1.0000,false
+2,0.0000,0.0000,0.0000,false
+3,0.0000,0.0000,0.0000,false
+4,0.0000,0.0000,0.0000,true
+"#;
+    assert_eq!(run("omit-empty.csv"), expected);
+
+    // A locked account is output even if it has no funds.
+    let output = output(Moneybags {
+        csv_file: vec![path("omit-empty.csv")],
+        omit_empty: true,
+        ..Default::default()
+    });
+    let expected = r#"client,available,held,total,locked
+1,1.0000,0.0000,1.0000,false
+4,0.0000,0.0000,0.0000,true
+"#;
+    assert_eq!(output, expected);
+}
+
+/// With `--state`, processing starts from the accounts of a previous run, so a locked client stays
+/// locked, while a dispute of a transaction from the previous run is of an unknown tx.
+#[test]
+fn state() {
+    let expected = r#"client,available,held,total,locked
+1,6.0000,0.0000,6.0000,false
+2,3.0000,0.0000,3.0000,true
+3,2.0000,0.0000,2.0000,false
+"#;
+    for threads in [None, NonZeroUsize::new(2)] {
+        let output = Command::new(env!("CARGO_BIN_EXE_moneybags"))
+            .arg(path("after-state.csv"))
+            .arg("--state")
+            .arg(path("state.csv"))
+            .args(threads.map(|threads| format!("--threads={}", threads)))
+            .output()
+            .unwrap();
+        assert_eq!(output.status.code(), Some(2));
+        assert_eq!(String::from_utf8(output.stdout).unwrap(), expected);
+        let stderr = String::from_utf8(output.stderr).unwrap();
+        assert!(
+            stderr.contains("skipped 2 rows (1 unknown tx, 1 locked)"),
+            "{}",
+            stderr
+        );
+    }
+}
+
+/// A logger that keeps the level and message of every log record.
+struct CapturingLogger(std::sync::Mutex<Vec<(log::Level, String)>>);
+
+impl log::Log for CapturingLogger {
+    fn enabled(&self, _: &log::Metadata<'_>) -> bool {
+        true
+    }
+
+    fn log(&self, record: &log::Record<'_>) {
+        let message = record.args().to_string();
+        self.0.lock().unwrap().push((record.level(), message));
+    }
+
+    fn flush(&self) {}
+}
+
+static LOGGER: CapturingLogger = CapturingLogger(std::sync::Mutex::new(Vec::new()));
+
+/// Skipped rows are logged as warnings with the `log` crate, so that a library user can install
+/// their own logger.
+#[test]
+fn logging() {
+    // Other tests log too, while this one runs, so the message is looked for among theirs.
+    let _ = log::set_logger(&LOGGER);
+    log::set_max_level(log::LevelFilter::Info);
+    let records = vec![Record::new(RecordType::Withdrawal, 1, 1, Decimal::ONE)];
+    process(records, &ProcessingOptions::default()).unwrap();
+    let expected = (
+        log::Level::Warn,
+        "Error processing record: Withdrawal failed. Available funds insufficient.".to_string(),
+    );
+    let logged = LOGGER.0.lock().unwrap();
+    assert!(logged.contains(&expected), "{:?}", logged);
+}
+
+/// With `--flag-anomalies`, a dispute of more than the client has deposited is logged as a warning,
+/// and still processed. Client 2 disputes the withdrawal of funds transferred to it by client 1.
+#[test]
+fn flag_anomalies() {
+    let _ = log::set_logger(&LOGGER);
+    log::set_max_level(log::LevelFilter::Info);
+    let records = vec![
+        Record::new(RecordType::Deposit, 1, 1, Decimal::new(10, 0)),
+        Record::transfer(1, 2, 2, Decimal::new(8, 0)),
+        Record::new(RecordType::Withdrawal, 2, 3, Decimal::new(8, 0)),
+        Record::new(RecordType::Deposit, 2, 4, Decimal::ONE),
+        Record::new(RecordType::Dispute, 2, 3, Decimal::ZERO),
+        Record::new(RecordType::Dispute, 1, 1, Decimal::ZERO),
+    ];
+    let options = ProcessingOptions::default().with_flag_anomalies(true);
+    let result = process(records, &options).unwrap();
+    assert_eq!(result.report.total_skipped(), 0);
+    assert_eq!(result.clients[1].held(), Decimal::new(8, 0));
+    let logged = LOGGER.0.lock().unwrap();
+    let anomalies = logged
+        .iter()
+        .filter(|(_, message)| message.starts_with("Anomaly"))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        anomalies,
+        [&(
+            log::Level::Warn,
+            "Anomaly: dispute of tx 3 for 8 is more than the 1 that client 2 has deposited"
+                .to_string()
+        )]
+    );
+}
+
+/// A dispute of a deposit whose funds have been withdrawn takes the available funds below zero,
+/// unless `--dispute-requires-funds` is given, in which case it is skipped.
+#[test]
+fn dispute_requires_funds() {
+    let expected = r#"client,available,held,total,locked
+1,-8.0000,10.0000,2.0000,false
+2,0.0000,10.0000,10.0000,false
+"#;
+    assert_eq!(run("dispute-spent-funds.csv"), expected);
+
+    let output = output(Moneybags {
+        csv_file: vec![path("dispute-spent-funds.csv")],
+        dispute_requires_funds: true,
+        ..Default::default()
+    });
+    let expected = r#"client,available,held,total,locked
+1,2.0000,0.0000,2.0000,false
+2,0.0000,10.0000,10.0000,false
+"#;
+    assert_eq!(output, expected);
+
+    let input = std::fs::File::open(path("dispute-spent-funds.csv")).unwrap();
+    let options = ProcessingOptions {
+        dispute_requires_funds: true,
+        ..strict()
+    };
+    let err = process_records(input, &options).unwrap_err();
+    assert!(matches!(
+        err.downcast_ref::<ProcessError>().unwrap(),
+        ProcessError::FundsUnavailable { client: 1, tx: 1 }
+    ));
+}
+
+/// With `--progress`, a line is written to stderr each time another `--progress-interval` rows
+/// have been read, so there are none when there are fewer rows than that.
+#[test]
+fn progress() {
+    let progress = |interval: &str| {
+        let output = Command::new(env!("CARGO_BIN_EXE_moneybags"))
+            .arg(path("resolve-and-chargeback.csv"))
+            .args(["--progress", "--progress-interval", interval])
+            .output()
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(output.stdout).unwrap(),
+            run("resolve-and-chargeback.csv")
+        );
+        String::from_utf8(output.stderr)
+            .unwrap()
+            .lines()
+            .filter(|line| line.starts_with("progress: "))
+            .map(str::to_string)
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(
+        progress("5"),
+        vec![
+            "progress: 5 rows, 1 clients",
+            "progress: 10 rows, 2 clients"
+        ]
+    );
+    assert!(progress("100").is_empty());
+}
+
+/// `ProcessingOptions` can be built up from the defaults without going through the command line.
+#[test]
+fn options_builder() {
+    let options = ProcessingOptions::default()
+        .with_strict(true)
+        .with_delimiter(b';')
+        .with_overdraft_limit(Decimal::new(5, 0))
+        .with_until_tx(4);
+    assert_eq!(
+        options,
+        ProcessingOptions {
+            strict: true,
+            delimiter: b';',
+            overdraft_limit: Decimal::new(5, 0),
+            until_tx: Some(4),
+            ..Default::default()
+        }
+    );
+
+    // The withdrawal that fails by default is tx 5, so it is ignored and strict mode does not stop.
+    let input = std::fs::File::open(path("given-example-semicolon.csv")).unwrap();
+    let result = process_records(input, &options).unwrap();
+    let expected: Vec<Client> = serde_json::from_str(
+        r#"[
+            {"client": 1, "available": "1.5", "held": "0", "total": "1.5", "locked": false},
+            {"client": 2, "available": "2", "held": "0", "total": "2", "locked": false}
+        ]"#,
+    )
+    .unwrap();
+    assert_eq!(result.clients, expected);
+    assert_eq!(result.report.total_skipped(), 0);
+}
+
+/// A resolve or chargeback with an amount is processed as if it had none, with a warning, except
+/// in strict mode, where it is an error, and it is written to the errors file like any other.
+#[test]
+fn unexpected_amount() {
+    let output = Command::new(env!("CARGO_BIN_EXE_moneybags"))
+        .arg(path("resolve-with-amount.csv"))
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(0));
+    let expected = r#"client,available,held,total,locked
+1,10.0000,0.0000,10.0000,false
+"#;
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), expected);
+    assert_eq!(
+        String::from_utf8(output.stderr).unwrap(),
+        "Warning for record on line 4 (byte 52): Resolve of tx 1 has an amount 4.0, but it \
+         settles the whole dispute\n"
+    );
+
+    let input = std::fs::File::open(path("resolve-with-amount.csv")).unwrap();
+    let err = process_records(input, &strict()).unwrap_err();
+    assert_eq!(
+        err.downcast_ref::<RowError>().unwrap().kind,
+        ErrorKind::UnexpectedAmount
+    );
+
+    // The resolve that stops strict processing is written to the errors file.
+    let errors_file = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("unexpected_amount.csv");
+    let output = Command::new(env!("CARGO_BIN_EXE_moneybags"))
+        .arg(path("resolve-with-amount.csv"))
+        .arg("--strict")
+        .arg("--errors")
+        .arg(&errors_file)
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(1));
+    let expected = r#"line,tx,client,kind,message
+4,1,1,unexpected_amount,"Resolve of tx 1 has an amount 4.0, but it settles the whole dispute"
+"#;
+    assert_eq!(std::fs::read_to_string(&errors_file).unwrap(), expected);
+}
+
+/// With `--fail-fast-on-parse`, a row that can not be parsed is an error, but a row that can not be
+/// processed is still skipped.
+#[test]
+fn fail_fast_on_parse() {
+    let options = ProcessingOptions::default().with_fail_fast_on_parse(true);
+    for threads in [None, NonZeroUsize::new(2)] {
+        let input = std::fs::File::open(path("parse-after-overdraw.csv")).unwrap();
+        let err = match threads {
+            Some(threads) => process_records_threaded(input, &options, threads),
+            None => process_records(input, &options),
+        }
+        .unwrap_err();
+        let row_error = err.downcast_ref::<RowError>().unwrap();
+        assert_eq!(row_error.kind, ErrorKind::Parse);
+        assert_eq!(row_error.line, Some(4));
+    }
+
+    // Without the malformed row, the withdrawal is skipped as usual.
+    let input = "type,client,tx,amount\ndeposit,1,1,1.0\nwithdrawal,1,2,5.0\n";
+    let result = process_records(input.as_bytes(), &options).unwrap();
+    assert_eq!(
+        result.report.skipped,
+        btreemap! { ErrorKind::InsufficientFunds => 1 }
+    );
+
+    let output = Command::new(env!("CARGO_BIN_EXE_moneybags"))
+        .arg(path("parse-after-overdraw.csv"))
+        .arg("--fail-fast-on-parse")
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(1));
+    assert!(output.stdout.is_empty());
+}
+
+/// With `--diagnose-ordering`, a dispute of a transaction that could not be found says whether the
+/// transaction comes later in the input, was skipped, or is not in the input at all.
+#[test]
+fn diagnose_ordering() {
+    let command = Command::new(env!("CARGO_BIN_EXE_moneybags"))
+        .arg(path("out-of-order.csv"))
+        .arg("--diagnose-ordering")
+        .output()
+        .unwrap();
+    let expected = r#"client,available,held,total,locked
+1,8.0000,0.0000,8.0000,false
+"#;
+    assert_eq!(String::from_utf8(command.stdout).unwrap(), expected);
+    let stderr = String::from_utf8(command.stderr).unwrap();
+    for message in [
+        "Error processing record on line 3 (byte 38): Disputed record tx 2 could not be found \
+        because it is on line 4, after the dispute",
+        "Error processing record on line 6 (byte 87): Disputed record tx 3 could not be found \
+        because it was skipped on line 5",
+        "Error processing record on line 7 (byte 100): Disputed record tx 9 could not be found \
+        anywhere in the input",
+    ] {
+        assert!(stderr.contains(message), "{}", stderr);
+    }
+
+    // Without it, the three errors are the same.
+    let input = std::fs::File::open(path("out-of-order.csv")).unwrap();
+    let err = process_records(input, &strict()).unwrap_err();
+    assert_eq!(
+        err.downcast_ref::<ProcessError>().unwrap().to_string(),
+        "Disputed record tx 2 could not be found"
+    );
+
+    let input = std::fs::File::open(path("out-of-order.csv")).unwrap();
+    let options = ProcessingOptions::default().with_diagnose_ordering(true);
+    let result = process_records(input, &options).unwrap();
+    assert_eq!(result.report.skipped[&ErrorKind::UnknownTx], 3);
+}
+
+/// With `--totals`, the sums of the funds of the accounts in the output, and the number that are
+/// locked, are written to stderr, leaving the output as it is.
+#[test]
+fn totals() {
+    let output = Command::new(env!("CARGO_BIN_EXE_moneybags"))
+        .arg(path("given-example.csv"))
+        .arg("--totals")
+        .output()
+        .unwrap();
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        run("given-example.csv")
+    );
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(
+        stderr
+            .lines()
+            .any(|line| line
+                == "totals: clients=2 available=3.5000 held=0.0000 total=3.5000 locked=0"),
+        "{}",
+        stderr
+    );
+
+    let input = std::fs::File::open(path("given-example.csv")).unwrap();
+    let result = process_records(input, &ProcessingOptions::default()).unwrap();
+    let expected = Totals {
+        clients: 2,
+        available: Decimal::new(35, 1),
+        held: Decimal::ZERO,
+        total: Decimal::new(35, 1),
+        locked: 0,
+    };
+    assert_eq!(result.totals().unwrap(), expected);
+
+    let input = std::fs::File::open(path("resolve-and-chargeback.csv")).unwrap();
+    let result = process_records(input, &ProcessingOptions::default()).unwrap();
+    assert_eq!(result.totals().unwrap().locked, 1);
+}
+
+/// The funds of each account can be in range while their sums are not, which is an error rather
+/// than a panic, and with `--totals` nothing is written.
+#[test]
+fn totals_overflow() {
+    let input = std::fs::File::open(path("totals-overflow.csv")).unwrap();
+    let result = process_records(input, &ProcessingOptions::default()).unwrap();
+    assert_eq!(result.clients.len(), 2);
+    let err = result.totals().unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "The sum of the funds of the accounts is beyond the range of amounts"
+    );
+
+    let output = Command::new(env!("CARGO_BIN_EXE_moneybags"))
+        .arg(path("totals-overflow.csv"))
+        .arg("--totals")
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(1));
+    assert!(output.stdout.is_empty());
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("beyond the range of amounts"), "{}", stderr);
+}
+
+/// With `--extended`, the output has the number of records applied to each account and the highest
+/// tx among them. A record that was skipped is not counted.
+#[test]
+fn extended() {
+    let sequential = output(Moneybags {
+        csv_file: vec![path("extended.csv")],
+        extended: true,
+        ..Default::default()
+    });
+    let expected = r#"client,available,held,total,locked,tx_count,last_tx
+1,6.0000,0.0000,6.0000,false,3,7
+2,2.0000,0.0000,2.0000,false,1,2
+"#;
+    assert_eq!(sequential, expected);
+
+    let threaded = output(Moneybags {
+        csv_file: vec![path("extended.csv")],
+        extended: true,
+        threads: NonZeroUsize::new(2),
+        ..Default::default()
+    });
+    assert_eq!(threaded, expected);
+}
+
+/// Input with a header but no rows produces output with a header but no accounts, while input
+/// without even a header is an error.
+#[test]
+fn empty_input() {
+    let output = Command::new(env!("CARGO_BIN_EXE_moneybags"))
+        .arg(path("header-only.csv"))
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "client,available,held,total,locked\n"
+    );
+
+    let output = Command::new(env!("CARGO_BIN_EXE_moneybags"))
+        .arg(path("empty.csv"))
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(1));
+    assert!(output.stdout.is_empty());
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(
+        stderr.contains("The input is empty, without a CSV header"),
+        "{}",
+        stderr
+    );
+
+    let result = process_records(&b""[..], &ProcessingOptions::default());
+    assert!(result.is_err());
+}
+
+/// An `Engine` can have records applied one at a time, with the state of the accounts available at
+/// any point, and ends up in the same state as processing the records all at once.
+#[test]
+fn engine() {
+    let records = vec![
+        Record::new(RecordType::Deposit, 1, 1, Decimal::new(5, 0)),
+        Record::new(RecordType::Deposit, 2, 2, Decimal::new(2, 0)),
+        Record::new(RecordType::Dispute, 1, 1, Decimal::ZERO),
+        Record::new(RecordType::Withdrawal, 2, 3, Decimal::new(3, 0)),
+        Record::new(RecordType::Chargeback, 1, 1, Decimal::ZERO),
+    ];
+    let mut engine = Engine::new(ProcessingOptions::default());
+    assert!(engine.snapshot().is_empty());
+    for record in &records[..3] {
+        engine.apply(record).unwrap();
+    }
+    let expected: Vec<Client> = serde_json::from_str(
+        r#"[
+            {"client": 1, "available": "0", "held": "5", "total": "5", "locked": false},
+            {"client": 2, "available": "2", "held": "0", "total": "2", "locked": false}
+        ]"#,
+    )
+    .unwrap();
+    assert_eq!(engine.snapshot(), expected);
+
+    // A record that can not be applied leaves the balances as they were.
+    let err = engine.apply(&records[3]).unwrap_err();
+    assert!(matches!(
+        err,
+        ProcessError::InsufficientFunds { client: 2, tx: 3 }
+    ));
+    assert_eq!(engine.snapshot(), expected);
+
+    // Except that the client of a record that can not be applied still gets an account.
+    let mut created = Engine::new(ProcessingOptions::default());
+    created
+        .apply(&Record::new(RecordType::Withdrawal, 3, 4, Decimal::ONE))
+        .unwrap_err();
+    assert_eq!(created.snapshot(), vec![Client::new(3)]);
+
+    engine.apply(&records[4]).unwrap();
+    let processed = process(records, &ProcessingOptions::default()).unwrap();
+    assert_eq!(engine.snapshot(), processed.clients);
+    assert!(engine.snapshot()[0].locked());
+}
+
+/// With `--freeze-after-failed-withdrawals 3`, client 1's third failed withdrawal, which need not
+/// follow the others, locks its account, so its next deposit is skipped. Client 2 only fails twice.
+#[test]
+fn freeze_after_failed_withdrawals() {
+    let options = ProcessingOptions::default()
+        .with_freeze_after_failed_withdrawals(NonZeroUsize::new(3).unwrap());
+    let f = std::fs::File::open(path("failed-withdrawals.csv")).unwrap();
+    let result = process_records(f, &options).unwrap();
+    assert_eq!(
+        result.report.skipped,
+        btreemap! { ErrorKind::InsufficientFunds => 5, ErrorKind::Locked => 1 }
+    );
+    let expected = r#"client,available,held,total,locked
+1,0.5000,0.0000,0.5000,true
+2,2.0000,0.0000,2.0000,false
+"#;
+    let frozen = output(Moneybags {
+        csv_file: vec![path("failed-withdrawals.csv")],
+        freeze_after_failed_withdrawals: NonZeroUsize::new(3),
+        ..Default::default()
+    });
+    assert_eq!(frozen, expected);
+    assert!(!run("failed-withdrawals.csv").contains("true"));
+}
+
+/// A record that would take the funds of an account beyond the range of amounts is skipped, rather
+/// than panicking, and leaves the account as it was.
+#[test]
+fn overflow() {
+    let records = vec![
+        Record::new(RecordType::Deposit, 1, 1, Decimal::MAX),
+        Record::new(RecordType::Deposit, 1, 2, Decimal::MAX),
+        Record::new(RecordType::Withdrawal, 1, 3, Decimal::ONE),
+    ];
+    let result = process(records.clone(), &ProcessingOptions::default()).unwrap();
+    assert_eq!(
+        result.report.skipped,
+        btreemap! { ErrorKind::Overflow => 1 }
+    );
+    assert_eq!(result.clients[0].available(), Decimal::MAX - Decimal::ONE);
+    assert_eq!(result.clients[0].total(), Decimal::MAX - Decimal::ONE);
+
+    let mut engine = Engine::new(ProcessingOptions::default());
+    engine.apply(&records[0]).unwrap();
+    let err = engine.apply(&records[1]).unwrap_err();
+    assert!(matches!(err, ProcessError::Overflow { client: 1, tx: 2 }));
+    assert_eq!(
+        err.to_string(),
+        "Tx 2 would take the funds of client 1 beyond the range of amounts"
+    );
+}
+
+/// Holding funds for a dispute, and releasing them with a resolve or removing them with a
+/// chargeback, keeps `available + held == total`, for disputes of both deposits and withdrawals.
+#[test]
+fn holds_keep_balance() {
+    let amount = |value| Decimal::new(value, 0);
+    // The funds of the account after the dispute, then after the resolve or chargeback, as
+    // available, held and total.
+    for (disputed, ending, held, ended) in [
+        (1, RecordType::Resolve, [-4, 10, 6], [6, 0, 6]),
+        (1, RecordType::Chargeback, [-4, 10, 6], [-4, 0, -4]),
+        (2, RecordType::Resolve, [6, 4, 10], [6, 0, 6]),
+        (2, RecordType::Chargeback, [6, 4, 10], [10, 0, 10]),
+    ] {
+        let mut engine = Engine::new(ProcessingOptions::default());
+        let funds = |engine: &Engine| {
+            let client = engine.snapshot()[0];
+            client.check_balance().unwrap();
+            [client.available(), client.held(), client.total()]
+        };
+        engine
+            .apply(&Record::new(RecordType::Deposit, 1, 1, amount(10)))
+            .unwrap();
+        engine
+            .apply(&Record::new(RecordType::Withdrawal, 1, 2, amount(4)))
+            .unwrap();
+        assert_eq!(funds(&engine), [amount(6), amount(0), amount(6)]);
+        engine
+            .apply(&Record::new(
+                RecordType::Dispute,
+                1,
+                disputed,
+                Decimal::ZERO,
+            ))
+            .unwrap();
+        assert_eq!(funds(&engine), held.map(amount));
+        engine
+            .apply(&Record::new(ending, 1, disputed, Decimal::ZERO))
+            .unwrap();
+        assert_eq!(funds(&engine), ended.map(amount));
+    }
+}
+
+/// With `--max-clients`, the records of clients beyond the first few, including the targets of
+/// transfers, are skipped, while the records of the first few are still processed.
+#[test]
+fn max_clients() {
+    let limited = output(Moneybags {
+        csv_file: vec![path("max-clients.csv")],
+        max_clients: Some(2),
+        ..Default::default()
+    });
+    let expected = r#"client,available,held,total,locked
+1,3.0000,0.0000,3.0000,false
+2,1.0000,0.0000,1.0000,false
+"#;
+    assert_eq!(limited, expected);
+    assert_eq!(run("max-clients.csv").lines().count(), 4);
+
+    let input = std::fs::File::open(path("max-clients.csv")).unwrap();
+    let result = process_records(input, &ProcessingOptions::default().with_max_clients(2)).unwrap();
+    assert_eq!(result.report.skipped[&ErrorKind::TooManyClients], 2);
+
+    let input = std::fs::File::open(path("max-clients.csv")).unwrap();
+    let options = ProcessingOptions {
+        max_clients: Some(2),
+        ..strict()
+    };
+    let err = process_records(input, &options).unwrap_err();
+    assert_eq!(
+        err.downcast_ref::<ProcessError>().unwrap().to_string(),
+        "Client 3 would be more than the maximum of 2 clients"
+    );
+}
+
+/// With `--sort-by`, the accounts in the output are sorted by that column, in ascending order unless
+/// `--sort-desc` is given, with ties in ascending order of client ID.
+#[test]
+fn sort_by() {
+    for (sort_by, ascending, descending) in [
+        (SortKey::Client, "12345", "54321"),
+        (SortKey::Available, "45213", "13245"),
+        (SortKey::Held, "12354", "41235"),
+        (SortKey::Total, "52413", "13425"),
+        (SortKey::Locked, "12345", "51234"),
+    ] {
+        for (sort_desc, expected) in [(false, ascending), (true, descending)] {
+            let sorted = output(Moneybags {
+                csv_file: vec![path("sort.csv")],
+                sort_by,
+                sort_desc,
+                ..Default::default()
+            });
+            let order = sorted
+                .lines()
+                .skip(1)
+                .map(|line| line.split(',').next().unwrap())
+                .collect::<String>();
+            assert_eq!(order, expected, "{} {}", sort_by, sort_desc);
+        }
+    }
+    assert_eq!("total".parse::<SortKey>().unwrap(), SortKey::Total);
+}
+
+/// With `--round-trip-test`, the output is read back and compared with the accounts that were
+/// written, so that a loss of precision in formatting the output is an error. Balances from
+/// `--state` can have more places than the currency scale, so they can not round trip.
+#[test]
+fn round_trip_test() {
+    for filename in [
+        "given-example.csv",
+        "resolve-and-chargeback.csv",
+        "sort.csv",
+    ] {
+        for (format, extended, output_delimiter) in [
+            (OutputFormat::Csv, false, b','),
+            (OutputFormat::Csv, true, b'\t'),
+            (OutputFormat::Json, false, b','),
+        ] {
+            let moneybags = Moneybags {
+                csv_file: vec![path(filename)],
+                format,
+                extended,
+                output_delimiter: Delimiter(output_delimiter),
+                ..Default::default()
+            };
+            let checked = output(Moneybags {
+                round_trip_test: true,
+                ..moneybags.clone()
+            });
+            assert_eq!(checked, output(moneybags), "{}", filename);
+        }
+    }
+
+    let precise = Moneybags {
+        csv_file: vec![path("given-example.csv")],
+        state: Some(path("state-precise.csv")),
+        ..Default::default()
+    };
+    assert!(output(precise.clone()).contains("1,2.7346,0.0000,2.7346,false"));
+    let mut output_bytes = Cursor::new(Vec::<u8>::new());
+    let err = Moneybags {
+        round_trip_test: true,
+        ..precise
+    }
+    .run(&mut output_bytes)
+    .unwrap_err();
+    assert!(
+        err.to_string()
+            .starts_with("Client 1 reads back from the output as"),
+        "{}",
+        err
+    );
+    assert!(output_bytes.into_inner().is_empty());
+}
+
+/// In no-dispute mode, the state of an account is written every time a record is applied to it,
+/// so the last row for each client is the same as its row in the usual output, and a dispute is an
+/// error even when processing is not strict. The checksum of the input can not be verified.
+#[test]
+fn no_dispute_mode() {
+    let streamed = output(Moneybags {
+        csv_file: vec![path("no-disputes.csv")],
+        no_dispute_mode: true,
+        ..Default::default()
+    });
+    let expected = r#"client,available,held,total,locked
+1,5.0000,0.0000,5.0000,false
+2,1.0000,0.0000,1.0000,false
+1,3.0000,0.0000,3.0000,false
+1,2.0000,0.0000,2.0000,false
+2,2.0000,0.0000,2.0000,false
+"#;
+    assert_eq!(streamed, expected);
+    assert_eq!(
+        run("no-disputes.csv"),
+        "client,available,held,total,locked\n1,2.0000,0.0000,2.0000,false\n\
+         2,2.0000,0.0000,2.0000,false\n"
+    );
+
+    let output = Command::new(env!("CARGO_BIN_EXE_moneybags"))
+        .arg(path("resolve-and-chargeback.csv"))
+        .arg("--no-dispute-mode")
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(1));
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    // The accounts written before the dispute are still output.
+    assert_eq!(stdout.lines().count(), 4, "{}", stdout);
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(
+        stderr.contains(
+            "Error processing record on line 5 (byte 70): Dispute of tx 2 is not allowed in \
+             no-dispute mode"
+        ),
+        "{}",
+        stderr
+    );
+
+    // The checksum can not be verified before the accounts are written.
+    let output = Command::new(env!("CARGO_BIN_EXE_moneybags"))
+        .arg(path("no-disputes.csv"))
+        .arg("--no-dispute-mode")
+        .arg("--verify-checksum")
+        .arg("deadbeef")
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(2));
+    assert!(output.stdout.is_empty());
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("cannot be used with"), "{}", stderr);
+}
+
+/// The accounts can be compared with those of a previous output, with every field that differs
+/// written instead of the accounts, and it is an error if any do.
+#[test]
+fn diff() {
+    let moneybags = Moneybags {
+        csv_file: vec![path("given-example.csv")],
+        diff: Some(path("given-example-output.csv")),
+        ..Default::default()
+    };
+    assert_eq!(output(moneybags.clone()), "PASS: 2 clients are the same\n");
+
+    let mut output_bytes = Cursor::new(Vec::<u8>::new());
+    let error = Moneybags {
+        diff: Some(path("given-example-divergent.csv")),
+        ..moneybags
+    }
+    .run(&mut output_bytes)
+    .unwrap_err();
+    assert!(error.to_string().starts_with("The accounts differ from '"));
+    assert!(error
+        .to_string()
+        .ends_with("given-example-divergent.csv' for 3 clients"));
+    let expected = r#"client 1: available is 1.5000, expected 1.0000
+client 1: held is 0.0000, expected 0.5000
+client 2: not in the reference
+client 3: missing
+FAIL: 3 of 3 clients differ
+"#;
+    assert_eq!(
+        String::from_utf8(output_bytes.into_inner()).unwrap(),
+        expected
+    );
+
+    let actual = btreemap! { 1 => Client::new(1) };
+    let expected = btreemap! { 2 => Client::new(2) };
+    assert_eq!(
+        diff_clients(&actual, &expected, 4, RoundingMode::MidpointNearestEven),
+        DiffReport {
+            clients: 2,
+            differences: vec![
+                Difference::Unexpected { client: 1 },
+                Difference::Missing { client: 2 },
+            ],
+        }
+    );
+}
+
+/// Rows with a timestamp outside of the window given by `--from` and `--to`, where `--to` is
+/// exclusive, are ignored, while rows without a timestamp are kept.
+#[test]
+fn time_window() {
+    let all = r#"client,available,held,total,locked
+1,12.0000,0.0000,12.0000,false
+2,4.0000,0.0000,4.0000,false
+"#;
+    assert_eq!(run("timestamps.csv"), all);
+
+    let windowed = output(Moneybags {
+        csv_file: vec![path("timestamps.csv")],
+        from: Some("2024-01-02T00:00:00Z".parse().unwrap()),
+        to: Some("2024-01-04T00:00:00Z".parse().unwrap()),
+        ..Default::default()
+    });
+    let expected = r#"client,available,held,total,locked
+1,2.0000,0.0000,2.0000,false
+2,3.0000,0.0000,3.0000,false
+"#;
+    assert_eq!(windowed, expected);
+}
+
+/// With `--enforce-order`, a row with a timestamp before that of an earlier row is skipped, or is an
+/// error in strict mode.
+#[test]
+fn enforce_order() {
+    let input = std::fs::read(path("timestamps.csv")).unwrap();
+    let options = ProcessingOptions::default().with_enforce_order(true);
+    let result = process_records(Cursor::new(&input), &options).unwrap();
+    assert_eq!(
+        result.report.skipped,
+        btreemap! { ErrorKind::OutOfOrder => 1 }
+    );
+    assert_eq!(result.client(1).unwrap().total(), Decimal::new(14, 0));
+
+    let err = process_records(Cursor::new(&input), &options.with_strict(true)).unwrap_err();
+    let row_error = err.downcast_ref::<RowError>().unwrap();
+    assert_eq!(row_error.kind, ErrorKind::OutOfOrder);
+    assert_eq!(row_error.line, Some(6));
+    assert_eq!(
+        err.root_cause().to_string(),
+        "Tx 5 has the timestamp 2024-01-02 08:00:00 UTC, which is before 2024-01-03 09:00:00 UTC, \
+         that of an earlier row"
+    );
+
+    let at = |s: &str| s.parse::<DateTime<Utc>>().unwrap();
+    let mut engine = Engine::new(options);
+    engine
+        .apply(
+            &Record::new(RecordType::Deposit, 1, 1, Decimal::ONE)
+                .with_timestamp(at("2024-01-02T00:00:00Z")),
+        )
+        .unwrap();
+    let err = engine
+        .apply(
+            &Record::new(RecordType::Deposit, 1, 2, Decimal::ONE)
+                .with_timestamp(at("2024-01-01T00:00:00Z")),
+        )
+        .unwrap_err();
+    assert!(matches!(err, ProcessError::OutOfOrder { tx: 2, .. }));
+    // A record without a timestamp is not checked.
+    engine
+        .apply(&Record::new(RecordType::Deposit, 1, 3, Decimal::ONE))
+        .unwrap();
+}
+
+/// With `--no-headers`, the first row is a record and the columns are positional, so a headerless
+/// file gives the same result as the same file with a header, including when the second of multiple
+/// files is headerless too, on multiple threads, and when validating.
+#[test]
+fn no_headers() {
+    let headerless = |csv_file: Vec<PathBuf>| Moneybags {
+        csv_file,
+        no_headers: true,
+        ..Default::default()
+    };
+    let expected = run("given-example.csv");
+    assert_eq!(
+        output(headerless(vec![path("given-example-no-headers.csv")])),
+        expected
+    );
+    assert_eq!(
+        output(Moneybags {
+            threads: NonZeroUsize::new(2),
+            ..headerless(vec![path("given-example-no-headers.csv")])
+        }),
+        expected
+    );
+    let more = output(headerless(vec![
+        path("given-example-no-headers.csv"),
+        path("no-headers-more.csv"),
+    ]));
+    // The only row of the second file is not taken to be a header.
+    assert_eq!(
+        more,
+        "client,available,held,total,locked\n1,1.0000,0.0000,1.0000,false\n2,2.0000,0.0000,2.0000,false\n"
+    );
+
+    let input = "transfer,1,2,1.0,2\ndeposit,1,1,3.0,\n";
+    let options = ProcessingOptions::default().with_no_headers(true);
+    let report = validate_records(input.as_bytes(), &options).unwrap();
+    assert_eq!(report.rows, 2);
+    let result = process_records(input.as_bytes(), &options).unwrap();
+    assert_eq!(
+        result.report.skipped,
+        btreemap! { ErrorKind::InsufficientFunds => 1 }
+    );
+    assert_eq!(result.client(1).unwrap().total(), Decimal::new(3, 0));
+
+    // A header is just a row that can not be parsed.
+    let result = process_records(
+        Cursor::new(std::fs::read(path("given-example.csv")).unwrap()),
+        &options,
+    )
+    .unwrap();
+    assert_eq!(
+        result.report.skipped,
+        btreemap! { ErrorKind::Parse => 1, ErrorKind::InsufficientFunds => 1 }
+    );
+}
+
+/// Deposits and withdrawals of zero are processed by default, to no effect, so that one can be
+/// disputed. With `--reject-zero-amount` they are skipped and not stored, so the dispute is of an
+/// unknown tx.
+#[test]
+fn reject_zero_amount() {
+    let expected = "client,available,held,total,locked\n1,5.0000,0.0000,5.0000,false\n";
+    assert_eq!(run("zero-amount.csv"), expected);
+    let input = std::fs::read(path("zero-amount.csv")).unwrap();
+    let result = process_records(Cursor::new(&input), &ProcessingOptions::default()).unwrap();
+    assert!(result.report.skipped.is_empty());
+
+    let options = ProcessingOptions::default().with_reject_zero_amount(true);
+    let result = process_records(Cursor::new(&input), &options).unwrap();
+    assert_eq!(
+        result.report.skipped,
+        btreemap! { ErrorKind::ZeroAmount => 2, ErrorKind::UnknownTx => 1 }
+    );
+    assert_eq!(result.client(1).unwrap().available(), Decimal::new(5, 0));
+
+    let mut engine = Engine::new(options);
+    let err = engine
+        .apply(&Record::new(RecordType::Withdrawal, 1, 3, Decimal::ZERO))
+        .unwrap_err();
+    assert_eq!(err.kind(), Some(ErrorKind::ZeroAmount));
+    assert_eq!(err.to_string(), "Withdrawal tx 3 has an amount of zero");
+}
+
+/// An observer sees each record as it is applied, along with the resulting account or the error
+/// that stopped it from being applied, in order.
+#[test]
+fn engine_observer() {
+    let events = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+    let observed = events.clone();
+    let options = ProcessingOptions::default().with_overdraw_policy(OverdrawPolicy::Drain);
+    let mut engine = Engine::new(options).with_observer(move |record, result| {
+        let event = result
+            .map(|client| (client.available(), client.held()))
+            .map_err(|e| e.kind());
+        observed.lock().unwrap().push((*record, event));
+    });
+    let records = [
+        Record::new(RecordType::Deposit, 1, 1, Decimal::new(3, 0)),
+        Record::new(RecordType::Withdrawal, 1, 2, Decimal::new(5, 0)),
+        Record::new(RecordType::Dispute, 1, 1, Decimal::ZERO),
+        Record::new(RecordType::Dispute, 1, 9, Decimal::ZERO),
+    ];
+    for record in &records {
+        let _ = engine.apply(record);
+    }
+    let events = events.lock().unwrap();
+    assert_eq!(
+        *events,
+        vec![
+            (records[0], Ok((Decimal::new(3, 0), Decimal::ZERO))),
+            // The observer sees the amount that was actually withdrawn.
+            (
+                Record::new(RecordType::Withdrawal, 1, 2, Decimal::new(3, 0)),
+                Ok((Decimal::ZERO, Decimal::ZERO))
+            ),
+            (records[2], Ok((Decimal::new(-3, 0), Decimal::new(3, 0)))),
+            (records[3], Err(Some(ErrorKind::UnknownTx))),
+        ]
+    );
+}
+
+/// `--hold-ledger` writes every movement of held funds, in order, for disputes of both deposits and
+/// withdrawals, but not for disputes that were skipped.
+#[test]
+fn hold_ledger() {
+    let hold_ledger = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("hold_ledger.csv");
+    let holds = |filename: &str| {
+        let accounts = output(Moneybags {
+            csv_file: vec![path(filename)],
+            hold_ledger: Some(hold_ledger.clone()),
+            ..Default::default()
+        });
+        assert_eq!(accounts, run(filename));
+        std::fs::read_to_string(&hold_ledger).unwrap()
+    };
+    let expected = r#"tx,client,action,amount,held_after
+2,1,hold,1.2000,1.2000
+2,1,release,1.2000,0.0000
+5,2,hold,1.0000,1.0000
+5,2,chargeback,1.0000,0.0000
+"#;
+    assert_eq!(holds("resolve-and-chargeback.csv"), expected);
+    let expected = r#"tx,client,action,amount,held_after
+2,1,hold,2.0000,2.0000
+2,1,chargeback,2.0000,0.0000
+4,2,hold,2.0000,2.0000
+4,2,release,2.0000,0.0000
+"#;
+    assert_eq!(holds("dispute-withdrawal.csv"), expected);
+    let expected = r#"tx,client,action,amount,held_after
+1,1,hold,10.0000,10.0000
+2,2,hold,5.0000,5.0000
+2,2,release,5.0000,0.0000
+"#;
+    assert_eq!(holds("double-dispute.csv"), expected);
+}
+
+/// With `--max-amount`, a deposit, withdrawal or transfer of exactly the maximum is processed, while
+/// one of just more than it is skipped, or is an error in strict mode.
+#[test]
+fn max_amount() {
+    let capped = output(Moneybags {
+        csv_file: vec![path("max-amount.csv")],
+        max_amount: Some(Decimal::new(1000, 0)),
+        ..Default::default()
+    });
+    assert_eq!(
+        capped,
+        "client,available,held,total,locked\n1,0.0000,0.0000,0.0000,false\n"
+    );
+
+    let input = std::fs::read(path("max-amount.csv")).unwrap();
+    let options = ProcessingOptions::default().with_max_amount(Decimal::new(1000, 0));
+    let result = process_records(Cursor::new(&input), &options).unwrap();
+    assert_eq!(
+        result.report.skipped,
+        btreemap! { ErrorKind::AmountTooLarge => 3 }
+    );
+
+    let err = process_records(Cursor::new(&input), &options.with_strict(true)).unwrap_err();
+    let row_error = err.downcast_ref::<RowError>().unwrap();
+    assert_eq!(row_error.kind, ErrorKind::AmountTooLarge);
+    assert_eq!(row_error.line, Some(3));
+    assert_eq!(
+        err.root_cause().to_string(),
+        "Deposit tx 2 amount 1000.0001 is more than the maximum of 1000"
+    );
+
+    // Without a maximum, no amount is too large, though the transfer is then more than client 1
+    // has.
+    let result = process_records(Cursor::new(&input), &ProcessingOptions::default()).unwrap();
+    assert_eq!(
+        result.report.skipped,
+        btreemap! { ErrorKind::InsufficientFunds => 1 }
+    );
+}
+
+/// Without a <CSV_FILE>, the binary reads the file at the path in `MONEYBAGS_INPUT`, which a
+/// <CSV_FILE> overrides. A path that does not exist is an error that names it.
+#[test]
+fn input_from_env() {
+    let moneybags = |input: &str| {
+        let mut command = Command::new(env!("CARGO_BIN_EXE_moneybags"));
+        command
+            .env("MONEYBAGS_INPUT", path(input))
+            .stdin(Stdio::null());
+        command
+    };
+    let output = moneybags("given-example.csv").output().unwrap();
+    assert_eq!(output.status.code(), Some(2));
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        run("given-example.csv")
+    );
+
+    let output = moneybags("given-example.csv")
+        .arg(path("zero-amount.csv"))
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        run("zero-amount.csv")
+    );
+
+    let output = moneybags("missing.csv").output().unwrap();
+    assert_eq!(output.status.code(), Some(1));
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.starts_with("Unable to open file '"));
+    assert!(stderr.contains("missing.csv"));
+}
+
+/// Amounts that are equal in value are written identically, whatever the scale of the input amounts
+/// and the operations they went through, in CSV and JSON alike.
+#[test]
+fn equivalent_balances() {
+    let expected = "client,available,held,total,locked\n\
+        1,1.0000,0.0000,1.0000,false\n\
+        2,1.0000,0.0000,1.0000,false\n\
+        3,1.0000,0.0000,1.0000,false\n";
+    assert_eq!(run("equivalent-balances.csv"), expected);
+
+    let json = output(Moneybags {
+        csv_file: vec![path("equivalent-balances.csv")],
+        format: OutputFormat::Json,
+        ..Default::default()
+    });
+    assert_eq!(json.matches(r#""available": "1.0000""#).count(), 3);
+    assert_eq!(json.matches(r#""total": "1.0000""#).count(), 3);
+}
+
+/// With `--history`, the balances of each account are written after every transaction applied to
+/// it, numbered in order, while a skipped transaction has no row and takes no number.
+#[test]
+fn history() {
+    let history = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("history.csv");
+    let accounts = output(Moneybags {
+        csv_file: vec![path("history.csv")],
+        history: Some(history.clone()),
+        ..Default::default()
+    });
+    assert_eq!(accounts, run("history.csv"));
+    let expected = r#"sequence_index,client,available,held,total
+1,1,5.0000,0.0000,5.0000
+2,1,3.0000,0.0000,3.0000
+3,1,4.5000,0.0000,4.5000
+"#;
+    assert_eq!(std::fs::read_to_string(&history).unwrap(), expected);
+}
+
+/// Once the interrupt flag is set, no more rows are read, and the result is of the rows read until
+/// then. Here it is set from another thread when the reader is asked for the third row.
+#[test]
+fn interrupt() {
+    static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+    /// Returns the input a chunk at a time, setting the flag before returning any of the second.
+    struct Interrupting {
+        chunks: Vec<&'static [u8]>,
+        /// How much of the first chunk has been returned.
+        offset: usize,
+        /// How many chunks have been started.
+        started: usize,
+    }
+
+    impl Read for Interrupting {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let chunk = match self.chunks.first() {
+                Some(chunk) => &chunk[self.offset..],
+                None => return Ok(0),
+            };
+            if self.offset == 0 {
+                self.started += 1;
+                if self.started == 2 {
+                    std::thread::spawn(|| INTERRUPTED.store(true, Ordering::SeqCst))
+                        .join()
+                        .unwrap();
+                }
+            }
+            let n = buf.len().min(chunk.len());
+            buf[..n].copy_from_slice(&chunk[..n]);
+            self.offset += n;
+            if n == chunk.len() {
+                self.chunks.remove(0);
+                self.offset = 0;
+            }
+            Ok(n)
+        }
+    }
+
+    let reader = Interrupting {
+        chunks: vec![
+            b"type,client,tx,amount\ndeposit,1,1,1.0\ndeposit,2,2,2.0\n",
+            b"deposit,1,3,4.0\n",
+            b"deposit,1,4,8.0\n",
+        ],
+        offset: 0,
+        started: 0,
+    };
+    let options = ProcessingOptions::default().with_interrupt(&INTERRUPTED);
+    let result = process_records(reader, &options).unwrap();
+    assert_eq!(result.report.rows, 3);
+    assert_eq!(result.client(1).unwrap().available(), Decimal::new(5, 0));
+    assert_eq!(result.client(2).unwrap().available(), Decimal::new(2, 0));
+}
+
+/// A single record can be checked on its own, before it is applied, without any account state.
+#[test]
+fn validate_single_record() {
+    let amount = Decimal::new(15, 1);
+    for record in [
+        Record::new(RecordType::Deposit, 1, 1, amount),
+        Record::new(RecordType::Withdrawal, 1, 2, Decimal::new(10001, 4)),
+        Record::transfer(1, 2, 3, amount),
+        Record::new(RecordType::Dispute, 1, 1, Decimal::ZERO),
+        Record::new(RecordType::Dispute, 1, 1, amount),
+        Record::new(RecordType::Resolve, 1, 1, Decimal::ZERO),
+        Record::new(RecordType::Chargeback, 1, 1, Decimal::ZERO),
+        Record::new(RecordType::Unlock, 1, 4, Decimal::ZERO),
+    ] {
+        assert!(validate_record(&record).is_ok(), "{:?}", record);
+    }
+
+    let invalid = |record: Record| validate_record(&record).unwrap_err();
+    let err = invalid(Record::new(
+        RecordType::Deposit,
+        1,
+        1,
+        Decimal::new(100001, 5),
+    ));
+    assert_eq!(err.kind(), Some(ErrorKind::ExcessPrecision));
+    let err = invalid(Record::new(RecordType::Withdrawal, 1, 2, -amount));
+    assert_eq!(err.kind(), Some(ErrorKind::NegativeAmount));
+    assert_eq!(err.to_string(), "Withdrawal amount -1.5 is negative");
+    let err = invalid(Record::new(RecordType::Deposit, 1, 1, Decimal::ZERO));
+    assert_eq!(err.kind(), Some(ErrorKind::ZeroAmount));
+    let err = invalid(Record::new(RecordType::Transfer, 1, 3, amount));
+    assert_eq!(err.kind(), Some(ErrorKind::MissingTargetClient));
+    let err = invalid(Record::new(RecordType::Dispute, 1, 1, -amount));
+    assert_eq!(err.kind(), Some(ErrorKind::NegativeAmount));
+    for record_type in [RecordType::Resolve, RecordType::Chargeback] {
+        let err = invalid(Record::new(record_type, 1, 1, amount));
+        assert_eq!(err.kind(), Some(ErrorKind::UnexpectedAmount));
+    }
+}
+
+/// With `--dedupe-window`, a row identical to a recent one is skipped as a replay, while one that
+/// reappears after the window is processed as usual, here as a duplicate tx for the deposit that
+/// was applied, and as a withdrawal that now succeeds for the one that was skipped.
+#[test]
+fn dedupe_window() {
+    let input = std::fs::read(path("dedupe-window.csv")).unwrap();
+    let result = process_records(Cursor::new(&input), &ProcessingOptions::default()).unwrap();
+    assert_eq!(
+        result.report.skipped,
+        btreemap! { ErrorKind::InsufficientFunds => 2, ErrorKind::DuplicateTx => 2 }
+    );
+
+    let options = ProcessingOptions::default().with_dedupe_window(NonZeroUsize::new(2).unwrap());
+    let result = process_records(Cursor::new(&input), &options).unwrap();
+    assert_eq!(
+        result.report.skipped,
+        btreemap! {
+            ErrorKind::InsufficientFunds => 1,
+            ErrorKind::DuplicateTx => 1,
+            ErrorKind::Replayed => 2,
+        }
+    );
+    assert_eq!(result.client(1).unwrap().available(), Decimal::new(61, 0));
+
+    let err = process_records(Cursor::new(&input), &options.with_strict(true)).unwrap_err();
+    let row_error = err.downcast_ref::<RowError>().unwrap();
+    assert_eq!(row_error.kind, ErrorKind::Replayed);
+    assert_eq!(row_error.line, Some(3));
+    assert_eq!(
+        err.root_cause().to_string(),
+        "Deposit tx 1 is a replay of one of the last 2 records"
+    );
+}
+
+/// With `--scale-to-input`, each account's amounts have as many places as the most precise amount
+/// applied to it, so whole units stay whole, and client 5's partial dispute is not rounded away,
+/// while an account with nothing applied to it, like client 4's, has the currency scale.
+#[test]
+fn scale_to_input() {
+    let actual = output(Moneybags {
+        csv_file: vec![path("scale-to-input.csv")],
+        scale_to_input: true,
+        ..Default::default()
+    });
+    let expected = r#"client,available,held,total,locked
+1,125,0,125,false
+2,3.2345,0.0000,3.2345,false
+3,0.0,1.5,1.5,false
+4,0.0000,0.0000,0.0000,false
+5,9.5,0.5,10.0,false
+"#;
+    assert_eq!(actual, expected);
+
+    // The amounts still read back as the same balances.
+    let round_tripped = output(Moneybags {
+        csv_file: vec![path("scale-to-input.csv")],
+        scale_to_input: true,
+        round_trip_test: true,
+        ..Default::default()
+    });
+    assert_eq!(round_tripped, expected);
+}
+
+/// With `--summary-only`, a single JSON object with the aggregates of the accounts and the rows is
+/// written instead of the accounts, unless the sums overflow, which is an error.
+#[test]
+fn summary_only() {
+    let output = output(Moneybags {
+        csv_file: vec![path("given-example.csv")],
+        summary_only: true,
+        ..Default::default()
+    });
+    let expected = r#"{
+  "clients": 2,
+  "locked": 0,
+  "available": "3.5000",
+  "held": "0.0000",
+  "total": "3.5000",
+  "rows": 5,
+  "skipped": 1
+}
+"#;
+    assert_eq!(output, expected);
+
+    let output = Command::new(env!("CARGO_BIN_EXE_moneybags"))
+        .arg(path("totals-overflow.csv"))
+        .arg("--summary-only")
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(1));
+    assert!(output.stdout.is_empty());
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("beyond the range of amounts"), "{}", stderr);
+}
+
+/// With `--no-freeze-on-chargeback`, a chargeback changes the funds as usual but leaves the account
+/// unlocked, so that later records for it are processed, here client 2's last withdrawal.
+#[test]
+fn no_freeze_on_chargeback() {
+    let output = output(Moneybags {
+        csv_file: vec![path("resolve-and-chargeback.csv")],
+        no_freeze_on_chargeback: true,
+        ..Default::default()
+    });
+    let expected = r#"client,available,held,total,locked
+1,3.4000,0.0000,3.4000,false
+2,1.2999,0.0000,1.2999,false
+"#;
+    assert_eq!(output, expected);
+
+    let input = r#"type,client,tx,amount
+deposit,1,1,5.0
+deposit,1,2,2.0
+dispute,1,2,
+chargeback,1,2,
+deposit,1,3,1.0
+"#;
+    let options = strict().with_no_freeze_on_chargeback(true);
+    let client = *process_records(Cursor::new(input), &options)
+        .unwrap()
+        .client(1)
+        .unwrap();
+    assert!(!client.locked());
+    assert_eq!(client.available(), Decimal::new(6, 0));
+    assert_eq!(client.held(), Decimal::ZERO);
+    assert_eq!(client.total(), Decimal::new(6, 0));
+
+    let explanation = options.explain(RecordType::Chargeback);
+    assert!(explanation.contains("the account is not locked, i.e. frozen, by the chargeback"));
+}
+
+/// The `generate` subcommand writes the same records for the same seed, and they can be processed,
+/// disputes and all, with only withdrawals skipped for insufficient funds.
+#[test]
+fn generate() {
+    let generate = |seed| {
+        output(Moneybags {
+            command: Some(MoneybagsCommand::Generate {
+                clients: NonZeroU32::new(5).unwrap(),
+                transactions: 1000,
+                seed,
+                dispute_rate: Decimal::new(1, 1),
+            }),
+            ..Default::default()
+        })
+    };
+    let generated = generate(42);
+    assert_eq!(generated, generate(42));
+    assert_ne!(generated, generate(43));
+    assert_eq!(generated.lines().count(), 1001);
+    assert!(generated.starts_with("type,client,tx,amount\n"));
+    for record_type in ["deposit", "withdrawal", "dispute", "resolve", "chargeback"] {
+        let prefix = format!("\n{},", record_type);
+        assert!(generated.contains(&prefix), "{}", record_type);
+    }
+
+    let result = process_records(Cursor::new(&generated), &ProcessingOptions::default()).unwrap();
+    assert_eq!(result.report.rows, 1000);
+    assert_eq!(
+        result.report.skipped.keys().collect::<Vec<_>>(),
+        vec![&ErrorKind::InsufficientFunds]
+    );
+
+    // There are not enough txs for more records than this.
+    let output = Command::new(env!("CARGO_BIN_EXE_moneybags"))
+        .args(["generate", "--transactions", "4294967296"])
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(2));
+    assert!(output.stdout.is_empty());
+}
+
+/// With `--alert-on-locked-activity`, each record for a locked account is counted, here client 2's
+/// deposit after the chargeback that locked it, whether it is then skipped or processed.
+#[test]
+fn alert_on_locked_activity() {
+    let input = std::fs::read(path("locked-activity.csv")).unwrap();
+    let result = process_records(Cursor::new(&input), &ProcessingOptions::default()).unwrap();
+    assert_eq!(result.report.locked_activity, 0);
+
+    let options = ProcessingOptions::default().with_alert_on_locked_activity(true);
+    let result = process_records(Cursor::new(&input), &options).unwrap();
+    assert_eq!(result.report.locked_activity, 1);
+    assert_eq!(result.report.skipped, btreemap! { ErrorKind::Locked => 1 });
+
+    for locked_policy in [LockedPolicy::Ignore, LockedPolicy::Process] {
+        let options = options.with_locked_policy(locked_policy);
+        let result = process_records(Cursor::new(&input), &options).unwrap();
+        assert_eq!(result.report.locked_activity, 1, "{:?}", locked_policy);
+        assert!(result.report.skipped.is_empty(), "{:?}", locked_policy);
+    }
+
+    let threads = NonZeroUsize::new(2).unwrap();
+    let result = process_records_threaded(Cursor::new(&input), &options, threads).unwrap();
+    assert_eq!(result.report.locked_activity, 1);
+}
+
+/// A column that is not part of the schema, such as a memo, is ignored by default, but with
+/// `--deny-unknown-columns` it is an error before any row is read. Known optional columns are
+/// allowed either way.
+#[test]
+fn deny_unknown_columns() {
+    let expected = r#"client,available,held,total,locked
+1,0.5000,0.0000,0.5000,false
+"#;
+    assert_eq!(run("memo.csv"), expected);
+
+    let err = Moneybags {
+        csv_file: vec![path("memo.csv")],
+        deny_unknown_columns: true,
+        ..Default::default()
+    }
+    .run(&mut Cursor::new(Vec::<u8>::new()))
+    .unwrap_err();
+    assert!(
+        err.to_string().starts_with("Unexpected column 'memo'"),
+        "{}",
+        err
+    );
+
+    let options = strict().with_deny_unknown_columns(true);
+    let input = std::fs::read(path("memo.csv")).unwrap();
+    let err = validate_records(input.as_slice(), &options).unwrap_err();
+    assert!(
+        err.to_string().starts_with("Unexpected column 'memo'"),
+        "{}",
+        err
+    );
+    let input = "type,client,tx,amount,timestamp\ndeposit,1,1,1.0,\n";
+    let result = process_records(input.as_bytes(), &options).unwrap();
+    assert_eq!(result.report.rows, 1);
+}